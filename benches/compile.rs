@@ -0,0 +1,94 @@
+/*
+MIT License
+
+Copyright (c) 2023 Vincent Hiribarren
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Throughput of each compilation phase, code being generated but never run.
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use inkwell::context::Context;
+use llvm_tuto_kaleidoscope_rust::{
+    codegen::{CodeGen, PassConfig},
+    parser::GlobalParser,
+};
+
+/// Inputs of the benches, by name
+fn sources() -> Vec<(&'static str, String)> {
+    let terms: Vec<_> = (1..500).map(|i| format!("{i} * x")).collect();
+    let big_expression = format!("def big(x) {};", terms.join(" + "));
+    let fib = String::from("def fib(x) if x < 3 then 1 else fib(x - 1) + fib(x - 2); fib(20);");
+    let small_defs = (0..200)
+        .map(|i| format!("def f{i}(x) x + {i};\n"))
+        .collect();
+    vec![
+        ("big_expression", big_expression),
+        ("fib", fib),
+        ("small_defs", small_defs),
+    ]
+}
+
+fn lexing(c: &mut Criterion) {
+    let parser = GlobalParser::default();
+    for (name, source) in sources() {
+        c.bench_function(&format!("lex_{name}"), |b| {
+            b.iter(|| parser.lexer(black_box(&source)).count())
+        });
+    }
+}
+
+fn parsing(c: &mut Criterion) {
+    for (name, source) in sources() {
+        c.bench_function(&format!("parse_{name}"), |b| {
+            b.iter(|| GlobalParser::default().parse(black_box(&source)).unwrap())
+        });
+    }
+}
+
+fn codegen(c: &mut Criterion) {
+    for (name, source) in sources() {
+        let ast = GlobalParser::default().parse(&source).unwrap();
+        c.bench_function(&format!("codegen_{name}"), |b| {
+            b.iter(|| {
+                let context = Context::create();
+                let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+                for ast_part in &ast {
+                    codegen.visit_top(ast_part).unwrap();
+                }
+            })
+        });
+    }
+}
+
+fn compile_many(c: &mut Criterion) {
+    let sources = sources();
+    let sources: Vec<_> = sources.iter().map(|(_, source)| source.as_str()).collect();
+    c.bench_function("compile_many", |b| {
+        b.iter(|| {
+            let context = Context::create();
+            let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+            assert!(codegen.compile_many(black_box(&sources)).is_ok());
+        })
+    });
+}
+
+criterion_group!(benches, lexing, parsing, codegen, compile_many);
+criterion_main!(benches);
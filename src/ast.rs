@@ -22,73 +22,190 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::lexer::Position;
+use crate::transform::subexprs;
+
 pub const ANONYM_FUNCTION: &str = "__anon_expr";
 
-#[derive(Debug, PartialEq)]
+/// Declarations of the usual libm functions, resolved by the JIT in the
+/// running process
+pub const LIBM_PRELUDE: &str = r#"
+extern sin(x); extern cos(x); extern tan(x);
+extern asin(x); extern acos(x); extern atan(x); extern atan2(y x);
+extern sinh(x); extern cosh(x); extern tanh(x);
+extern exp(x); extern log(x); extern log10(x); extern pow(x y);
+extern sqrt(x); extern fabs(x); extern floor(x); extern ceil(x);
+extern round(x); extern fmod(x y);
+"#;
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct KaleoGrammar(pub Vec<TopAST>);
 
-#[derive(Debug, PartialEq)]
+impl KaleoGrammar {
+    pub fn iter(&self) -> impl Iterator<Item = &TopAST> {
+        self.0.iter()
+    }
+
+    /// Functions called but neither defined with `def` nor declared with
+    /// `extern` in the grammar, in order of first call. There is no built-in
+    /// function: they must all be provided, like `printd` by the REPL.
+    pub fn undefined_calls(&self) -> Vec<String> {
+        let defined: Vec<&str> = self
+            .iter()
+            .filter_map(|ast_part| match ast_part {
+                TopAST::Function(func) => Some(func.proto.name.as_str()),
+                TopAST::Prototype(proto) => Some(proto.name.as_str()),
+                TopAST::GlobalVar(_) => None,
+            })
+            .collect();
+        let mut pending: Vec<&ExprAST> = self
+            .0
+            .iter()
+            .filter_map(|ast_part| match ast_part {
+                TopAST::Function(func) => Some(&func.body),
+                TopAST::GlobalVar(global) => global.init.as_ref(),
+                TopAST::Prototype(_) => None,
+            })
+            .rev()
+            .collect();
+        let mut undefined: Vec<String> = vec![];
+        while let Some(expr) = pending.pop() {
+            if let ExprAST::CallExpr(call) = expr {
+                if !defined.contains(&call.callee.as_str()) && !undefined.contains(&call.callee) {
+                    undefined.push(call.callee.clone());
+                }
+            }
+            pending.extend(subexprs(expr).into_iter().rev());
+        }
+        undefined
+    }
+}
+
+impl IntoIterator for KaleoGrammar {
+    type Item = TopAST;
+    type IntoIter = std::vec::IntoIter<TopAST>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a KaleoGrammar {
+    type Item = &'a TopAST;
+    type IntoIter = std::slice::Iter<'a, TopAST>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.iter()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub enum TopAST {
     Function(FunctionAST),
     Prototype(PrototypeAST),
+    GlobalVar(GlobalVarAST),
 }
 
-#[derive(Debug, PartialEq)]
+/// Module-level variable, its initializer must be a constant expression
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct GlobalVarAST {
+    pub name: String,
+    pub init: Option<ExprAST>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub enum ExprAST {
     VarExpr(VarExprAST),
     NumberExpr(NumberExprAST),
+    IntExpr(IntExprAST),
+    StringExpr(StringExprAST),
     VariableExpr(VariableExprAST),
     UnaryExpr(UnaryExprAST),
     BinaryExpr(BinaryExprAST),
     CallExpr(CallExprAST),
     IfExpr(IfExprAST),
     ForExpr(ForExprAST),
+    WhileExpr(WhileExprAST),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct VarExprAST {
     pub var_names: Vec<(String, Option<ExprAST>)>,
     pub body: Box<ExprAST>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct NumberExprAST {
     pub val: f64,
 }
 
-#[derive(Debug, PartialEq)]
+/// Integer literal. Integers only exist as literals: `+`, `-`, `*`, `%`
+/// and `<` between two of them are computed at compile time, any other use
+/// turns them into floating point numbers, the type of all the variables.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct IntExprAST {
+    pub val: i64,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct StringExprAST {
+    pub val: String,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct VariableExprAST {
     pub name: String,
+    pub position: Position,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct BinaryExprAST {
-    pub op: char,
+    pub op: String,
     pub lhs: Box<ExprAST>,
     pub rhs: Box<ExprAST>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct UnaryExprAST {
     pub opcode: char,
     pub operand: Box<ExprAST>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct CallExprAST {
     pub callee: String,
     pub args: Vec<ExprAST>,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct PrototypeAST {
     pub name: String,
     pub args: Vec<String>,
     pub operator: Option<Operator>,
+    pub ret_type: ReturnType,
+    /// Arguments ended by `...`, for externs like `printf` taking extra
+    /// arguments
+    pub var_args: bool,
+}
+
+/// Type of the value returned by a function, `double` unless annotated with
+/// `: void` after the arguments, for externs like `putchard` that are only
+/// called for their side effect
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub enum ReturnType {
+    Double,
+    Void,
 }
 
 impl PrototypeAST {
-    pub fn gen_binary_func_name(op: char) -> String {
+    /// Name of the function implementing a user-defined operator. The
+    /// operator may be any Unicode character, like `±`, since LLVM accepts
+    /// any name.
+    pub fn gen_binary_func_name(op: &str) -> String {
         format!("binary{op}")
     }
     pub fn gen_unary_func_name(op: char) -> String {
@@ -99,32 +216,58 @@ impl PrototypeAST {
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// Prototype as written after `def` or `extern`, like `binary| 5 (a b)`
+impl fmt::Display for PrototypeAST {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut args = self.args.join(" ");
+        if self.var_args {
+            args = if args.is_empty() {
+                String::from("...")
+            } else {
+                format!("{args} ...")
+            };
+        }
+        match &self.operator {
+            Some(Operator::Binary { precedence, .. }) => {
+                write!(f, "{} {precedence} ({args})", self.name)?
+            }
+            Some(Operator::Unary) | None => write!(f, "{}({args})", self.name)?,
+        }
+        match self.ret_type {
+            ReturnType::Double => Ok(()),
+            ReturnType::Void => write!(f, ": void"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub enum Operator {
     Unary,
-    Binary { op_name: char, precedence: isize },
+    Binary { op_name: String, precedence: isize },
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct FunctionAST {
     pub proto: PrototypeAST,
     pub body: ExprAST,
 }
 
 impl FunctionAST {
+    /// True if the function wraps a top-level expression under the default
+    /// ANONYM_FUNCTION name, see CodeGen::is_top_function for other names
     pub fn is_top_function(&self) -> bool {
         self.proto.name == ANONYM_FUNCTION
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct IfExprAST {
     pub condition: Box<ExprAST>,
     pub then_block: Box<ExprAST>,
     pub else_block: Box<ExprAST>,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct ForExprAST {
     pub var_name: String,
     pub var_start: Box<ExprAST>,
@@ -132,3 +275,28 @@ pub struct ForExprAST {
     pub step: Option<Box<ExprAST>>,
     pub body: Box<ExprAST>,
 }
+
+/// Loop evaluating `body` as long as `condition` is not 0, evaluates to 0.
+/// It has no syntax of its own and is only produced by desugaring.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct WhileExprAST {
+    pub condition: Box<ExprAST>,
+    pub body: Box<ExprAST>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::GlobalParser;
+
+    #[test]
+    fn undefined_calls() {
+        let ast = GlobalParser::default()
+            .parse("def f(x) sin(x) + g(x); extern g(x); def h() f(cos(1)) * sin(2); h();")
+            .unwrap();
+        assert_eq!(ast.undefined_calls(), ["sin", "cos"]);
+        let ast = GlobalParser::default()
+            .parse("def fib(x) if x < 3 then 1 else fib(x - 1) + fib(x - 2); fib(10);")
+            .unwrap();
+        assert!(ast.undefined_calls().is_empty());
+    }
+}
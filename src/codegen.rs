@@ -22,116 +22,736 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use std::{collections::HashMap, path::Path};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashMap, HashSet},
+    mem::transmute,
+    path::{Path, PathBuf},
+};
 
 use anyhow::{anyhow, bail, ensure, Result};
 use inkwell::{
+    basic_block::BasicBlock,
     builder::Builder,
     context::Context,
-    execution_engine::JitFunction,
-    module::Module,
+    execution_engine::ExecutionEngine,
+    module::{Linkage, Module},
     passes::PassManager,
-    targets::{FileType, TargetMachine},
-    types::BasicMetadataTypeEnum,
-    values::{AnyValue, AnyValueEnum, FunctionValue, PointerValue},
-    FloatPredicate,
+    support::search_for_address_of_symbol,
+    targets::{
+        CodeModel, FileType, InitializationConfig, RelocMode, Target, TargetMachine, TargetTriple,
+    },
+    types::{BasicMetadataTypeEnum, BasicTypeEnum, FloatType},
+    values::{
+        AnyValue, AnyValueEnum, BasicValue, FloatValue, FunctionValue, GlobalValue, IntValue,
+        PointerValue,
+    },
+    FloatPredicate, IntPredicate, OptimizationLevel,
 };
 
 use crate::ast::*;
+use crate::parser::GlobalParser;
+use crate::transform::{fold_constants, int_binary_op, propagate_constants, subexprs};
+
+/// Maximum number of arguments of a function called by run_function
+pub const MAX_RUN_ARGS: usize = 4;
+
+/// Version of LLVM the code generator was built against, selected at build
+/// time by the `llvm15-0` feature, which enables the same inkwell feature.
+/// LLVM can only report its version at runtime from LLVM 16.
+#[cfg(feature = "llvm15-0")]
+pub const LLVM_VERSION: &str = "15.0";
+
+#[cfg(not(feature = "llvm15-0"))]
+compile_error!("an LLVM version feature, like llvm15-0, must be enabled");
+
+/// Operators generated without calling a function, unless redefined
+const BUILTIN_OPERATORS: [&str; 16] = [
+    "binary:", "binary=", "binary||", "binary&&", "binary<", "binary<=", "binary>=", "binary==",
+    "binary!=", "binary+", "binary-", "binary*", "binary/", "binary%", "unary-", "unary!",
+];
+
+/// Global counting the loop iterations, when they are limited
+const LOOP_COUNTER: &str = "__loop_iterations";
+/// Function called by the generated code when too many iterations are done
+const LOOP_LIMIT_TRAP: &str = "__loop_limit_reached";
+
+thread_local! {
+    static LOOP_LIMIT_REACHED: Cell<bool> = const { Cell::new(false) };
+    /// Storage of the LOOP_COUNTER global of the JIT-compiled code
+    static LOOP_ITERATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+extern "C" fn loop_limit_reached() {
+    LOOP_LIMIT_REACHED.with(|reached| reached.set(true));
+}
+
+/// Target machine for the given triple, or for the host if None
+pub fn create_target_machine(triple: Option<&str>) -> Result<TargetMachine> {
+    Target::initialize_all(&InitializationConfig {
+        asm_parser: true,
+        asm_printer: true,
+        base: true,
+        disassembler: true,
+        info: true,
+        machine_code: true,
+    });
+    let cpu = "generic";
+    let features = "";
+    let level = OptimizationLevel::Default;
+    let reloc_mode = RelocMode::Default;
+    let code_model = CodeModel::Default;
+    let target_triple = match triple {
+        Some(triple) => TargetTriple::create(triple),
+        None => TargetMachine::get_default_triple(),
+    };
+    let target = Target::from_triple(&target_triple).map_err(|err| anyhow!(err.to_string()))?;
+    target
+        .create_target_machine(&target_triple, cpu, features, level, reloc_mode, code_model)
+        .ok_or_else(|| {
+            anyhow!(
+                "Cannot create a target machine for {}",
+                target_triple.as_str().to_string_lossy()
+            )
+        })
+}
+
+/// Function passes run on each generated function when optimizing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pass {
+    PromoteMemoryToRegister,
+    InstructionCombining,
+    Reassociate,
+    Gvn,
+    CfgSimplification,
+}
+
+/// Ordered list of the function passes to run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PassConfig {
+    pub passes: Vec<Pass>,
+}
+
+impl Default for PassConfig {
+    fn default() -> Self {
+        Self {
+            passes: vec![
+                // Promote allocas to registers.
+                Pass::PromoteMemoryToRegister,
+                // Do simple "peephole" optimizations and bit-twiddling optzns.
+                Pass::InstructionCombining,
+                // Reassociate expressions.
+                Pass::Reassociate,
+                Pass::Gvn,
+                Pass::CfgSimplification,
+                // Promote allocas to registers.
+                Pass::PromoteMemoryToRegister,
+            ],
+        }
+    }
+}
+
+impl PassConfig {
+    /// Same configuration, with all the occurrences of pass removed
+    pub fn without(mut self, pass: Pass) -> Self {
+        self.passes.retain(|&p| p != pass);
+        self
+    }
+}
+
+/// Hook called by CodeGen, to relate the AST nodes to the generated IR
+pub trait CodeGenObserver {
+    /// Called once the code of an expression is generated, with its value
+    fn on_expr(&mut self, _expr: &ExprAST, _value: &AnyValueEnum) {}
+}
 
 pub struct CodeGen<'ctx> {
     context: &'ctx Context,
     named_values_ctx: HashMap<String, PointerValue<'ctx>>,
     prototypes: HashMap<String, PrototypeAST>,
     builder: Builder<'ctx>,
-    modules: Vec<Module<'ctx>>,
-    last_pass_manager: PassManager<FunctionValue<'ctx>>,
+    pass_manager: PassManager<FunctionValue<'ctx>>,
+    module: Module<'ctx>,
     with_optim: bool,
+    keep_unoptimized_ir: bool,
+    unoptimized_ir: Option<String>,
+    annotate_ir: bool,
+    /// Comment naming the source of the last generated item
+    annotation: Option<String>,
+    /// Run transform::propagate_constants on each function body
+    propagate_constants: bool,
+    observer: Option<Box<dyn CodeGenObserver + 'ctx>>,
+    max_loop_iterations: Option<u64>,
+    /// Name of the functions wrapping top-level expressions
+    anonymous_name: String,
+    float_width: FloatWidth,
+    /// Execution engine kept for the life of the CodeGen, the definitions
+    /// of the module are added to it as they are needed
+    jit: RefCell<Option<JitCache<'ctx>>>,
+    /// Number of function addresses looked up in an execution engine
+    jit_lookups: Cell<usize>,
+    /// Where the IR is written when the CodeGen is dropped
+    ir_on_drop: Option<PathBuf>,
 }
 
-/// Due to issue or having partial borrow before borrowing the whole structure,
-/// a macro is used. The output of generate_and_get_func is not used, otherwise
-/// it become difficult to borrow self later.
-macro_rules! generate_and_get_func {
-    ($codegen:ident, $func_name:expr) => {{
-        $codegen.generate_and_get_func($func_name)?;
-        $codegen
-            .modules
-            .last()
-            .ok_or(anyhow!("No module founds"))?
-            .get_function($func_name)
-            .ok_or(anyhow!("{} not found in prototype lists", $func_name))
-    }};
+/// Execution engine owning copies of the module, with the addresses of the
+/// compiled functions already called
+struct JitCache<'ctx> {
+    engine: ExecutionEngine<'ctx>,
+    addresses: HashMap<String, usize>,
+    /// Functions and globals defined by the copies given to the engine
+    defined: HashSet<String>,
 }
 
 type CodeGenResult<'ctx> = Result<AnyValueEnum<'ctx>>;
 
-impl<'ctx> CodeGen<'ctx> {
-    pub fn new(context: &'ctx Context, with_optim: bool) -> Self {
-        let (module, pass_manager) = Self::init_new_module(context);
-        let modules = vec![module];
-        let prototypes = HashMap::new();
+/// Options of a CodeGen, by default generating optimized code with the
+/// default passes, without any loop limit
+pub struct CodeGenBuilder<'ctx> {
+    with_optim: bool,
+    keep_unoptimized_ir: bool,
+    annotate_ir: bool,
+    pass_config: PassConfig,
+    propagate_constants: bool,
+    observer: Option<Box<dyn CodeGenObserver + 'ctx>>,
+    max_loop_iterations: Option<u64>,
+    anonymous_name: String,
+    float_width: FloatWidth,
+    ir_on_drop: Option<PathBuf>,
+}
+
+/// Type of the numbers in the generated code. With F32, the extern functions
+/// must take and return `float` as well, which is not the case of the
+/// functions provided by the REPL nor of libm.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum FloatWidth {
+    F32,
+    #[default]
+    F64,
+}
+
+impl Default for CodeGenBuilder<'_> {
+    fn default() -> Self {
+        Self {
+            with_optim: true,
+            keep_unoptimized_ir: false,
+            annotate_ir: false,
+            pass_config: PassConfig::default(),
+            propagate_constants: false,
+            observer: None,
+            max_loop_iterations: None,
+            anonymous_name: String::from(ANONYM_FUNCTION),
+            float_width: FloatWidth::default(),
+            ir_on_drop: None,
+        }
+    }
+}
+
+impl<'ctx> CodeGenBuilder<'ctx> {
+    /// Run the passes of the pass config on each generated function
+    pub fn optimize(mut self, with_optim: bool) -> Self {
+        self.with_optim = with_optim;
+        self
+    }
+
+    /// See CodeGen::take_unoptimized_ir
+    pub fn keep_unoptimized_ir(mut self, keep: bool) -> Self {
+        self.keep_unoptimized_ir = keep;
+        self
+    }
+
+    /// See CodeGen::take_annotation
+    pub fn annotate_ir(mut self, annotate: bool) -> Self {
+        self.annotate_ir = annotate;
+        self
+    }
+
+    pub fn pass_config(mut self, pass_config: PassConfig) -> Self {
+        self.pass_config = pass_config;
+        self
+    }
+
+    /// Replace the variables bound by `var` to a literal, and never assigned,
+    /// by the literal before generating the code of a function, so that
+    /// they need no stack slot even without optimization
+    pub fn propagate_constants(mut self, propagate: bool) -> Self {
+        self.propagate_constants = propagate;
+        self
+    }
+
+    /// Notify the observer of each generated expression
+    pub fn observer(mut self, observer: impl CodeGenObserver + 'ctx) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Stop the evaluation once loops have iterated max times in total,
+    /// run_function then returns an error instead of hanging. Only meant for
+    /// the JIT: the generated loops call a trap function that only the JIT
+    /// provides, so an object file would not link.
+    pub fn max_loop_iterations(mut self, max: u64) -> Self {
+        self.max_loop_iterations = Some(max);
+        self
+    }
+
+    /// Treat the functions named name as top-level expressions, it must match
+    /// the name given to the parser with GlobalParser::set_anonymous_name.
+    pub fn anonymous_name(mut self, name: &str) -> Self {
+        self.anonymous_name = name.to_string();
+        self
+    }
+
+    /// Generate `float` instead of `double` numbers, run_function still
+    /// takes and returns f64
+    pub fn float_width(mut self, float_width: FloatWidth) -> Self {
+        self.float_width = float_width;
+        self
+    }
+
+    /// Write the IR to path when the CodeGen is dropped, so that a session
+    /// interrupted by a panic or an early return still produces it
+    pub fn ir_on_drop(mut self, path: &Path) -> Self {
+        self.ir_on_drop = Some(path.to_path_buf());
+        self
+    }
+
+    pub fn build(self, context: &'ctx Context) -> CodeGen<'ctx> {
+        let (module, pass_manager) = CodeGen::init_new_module(context, &self.pass_config);
         CodeGen {
             context,
             named_values_ctx: HashMap::new(),
-            prototypes,
+            prototypes: HashMap::new(),
             builder: context.create_builder(),
-            last_pass_manager: pass_manager,
-            modules,
-            with_optim,
+            pass_manager,
+            module,
+            with_optim: self.with_optim,
+            keep_unoptimized_ir: self.keep_unoptimized_ir,
+            unoptimized_ir: None,
+            annotate_ir: self.annotate_ir,
+            annotation: None,
+            propagate_constants: self.propagate_constants,
+            observer: self.observer,
+            max_loop_iterations: self.max_loop_iterations,
+            anonymous_name: self.anonymous_name,
+            float_width: self.float_width,
+            jit: RefCell::new(None),
+            jit_lookups: Cell::new(0),
+            ir_on_drop: self.ir_on_drop,
+        }
+    }
+}
+
+impl<'ctx> CodeGen<'ctx> {
+    /// Same as `CodeGen::builder()` with the given options, the other ones
+    /// keeping their default value
+    pub fn new(
+        context: &'ctx Context,
+        with_optim: bool,
+        keep_unoptimized_ir: bool,
+        pass_config: PassConfig,
+    ) -> Self {
+        CodeGen::builder()
+            .optimize(with_optim)
+            .keep_unoptimized_ir(keep_unoptimized_ir)
+            .pass_config(pass_config)
+            .build(context)
+    }
+
+    pub fn builder() -> CodeGenBuilder<'ctx> {
+        CodeGenBuilder::default()
+    }
+
+    pub fn anonymous_name(&self) -> &str {
+        &self.anonymous_name
+    }
+
+    pub fn float_width(&self) -> FloatWidth {
+        self.float_width
+    }
+
+    /// Type of the numbers in the generated code
+    fn float_type(&self) -> FloatType<'ctx> {
+        match self.float_width {
+            FloatWidth::F32 => self.context.f32_type(),
+            FloatWidth::F64 => self.context.f64_type(),
         }
     }
 
-    fn init_new_module(context: &Context) -> (Module, PassManager<FunctionValue>) {
+    /// True if func wraps a top-level expression, and is replaced by the
+    /// next one instead of being kept as a regular definition
+    pub fn is_top_function(&self, func: &FunctionAST) -> bool {
+        func.proto.name == self.anonymous_name
+    }
+
+    fn init_new_module<'c>(
+        context: &'c Context,
+        pass_config: &PassConfig,
+    ) -> (Module<'c>, PassManager<FunctionValue<'c>>) {
         let module = context.create_module("my cool JIT");
         let pass_manager = PassManager::create(&module);
-        // Promote allocas to registers.
-        pass_manager.add_promote_memory_to_register_pass();
-        // Do simple "peephole" optimizations and bit-twiddling optzns.
-        pass_manager.add_instruction_combining_pass();
-        // Reassociate expressions.
-        pass_manager.add_reassociate_pass();
-        pass_manager.add_gvn_pass();
-        pass_manager.add_cfg_simplification_pass();
-        // Promote allocas to registers.
-        pass_manager.add_promote_memory_to_register_pass();
-
+        for pass in &pass_config.passes {
+            match pass {
+                Pass::PromoteMemoryToRegister => pass_manager.add_promote_memory_to_register_pass(),
+                Pass::InstructionCombining => pass_manager.add_instruction_combining_pass(),
+                Pass::Reassociate => pass_manager.add_reassociate_pass(),
+                Pass::Gvn => pass_manager.add_gvn_pass(),
+                Pass::CfgSimplification => pass_manager.add_cfg_simplification_pass(),
+            }
+        }
         pass_manager.initialize();
         (module, pass_manager)
     }
 
-    fn change_module(&mut self) {
-        let (new_module, new_pass) = Self::init_new_module(self.context);
-        self.modules.push(new_module);
-        self.last_pass_manager = new_pass;
+    /// IR of the last generated function before it was optimized, if it was
+    /// requested when creating the CodeGen.
+    pub fn take_unoptimized_ir(&mut self) -> Option<String> {
+        self.unoptimized_ir.take()
     }
 
-    pub fn print_to_stderr(&self) {
-        for module in &self.modules {
-            module.print_to_stderr();
+    /// Comment naming the source construct of the last item given to
+    /// visit_top, like `; --- def fib(x) ---`, to print before its IR. Only
+    /// produced if it was requested when creating the CodeGen.
+    pub fn take_annotation(&mut self) -> Option<String> {
+        self.annotation.take()
+    }
+
+    fn annotate(&mut self, source: impl FnOnce() -> String) {
+        if self.annotate_ir {
+            self.annotation = Some(format!("; --- {} ---", source()));
         }
     }
 
-    pub fn generate_object_code(&self, target_machine: &TargetMachine, output: &Path) {
+    pub fn print_to_stderr(&self) {
+        self.module.print_to_stderr();
+    }
+
+    pub fn generate_object_code(
+        &self,
+        target_machine: &TargetMachine,
+        output: &Path,
+    ) -> Result<()> {
         target_machine
-            .write_to_file(self.modules.last().unwrap(), FileType::Object, output)
-            .unwrap();
+            .write_to_file(&self.module, FileType::Object, output)
+            .map_err(|err| anyhow!("Cannot write object file {}: {err}", output.display()))
+    }
+
+    /// Parse and generate the code of each source in turn, without running
+    /// anything. The sources share a parser, so that the operators defined
+    /// by one of them are known by the next ones.
+    pub fn compile_many(&mut self, sources: &[&str]) -> Result<()> {
+        let mut parser = GlobalParser::default();
+        parser.set_anonymous_name(&self.anonymous_name);
+        for source in sources {
+            self.compile_grammar(&parser.parse(source)?)?;
+        }
+        Ok(())
+    }
+
+    /// Generate the code of each item of grammar, stopping at the first
+    /// error. As declare_prototypes is called first, a function can call
+    /// another one defined after it. The function of a top-level expression
+    /// is deleted by the next one, so only the last one can be used.
+    pub fn compile_grammar(&mut self, grammar: &KaleoGrammar) -> Result<Vec<AnyValueEnum<'ctx>>> {
+        let mut values = vec![];
+        self.compile_grammar_with(grammar, |_, _, value| {
+            values.push(value?);
+            Ok(())
+        })?;
+        Ok(values)
+    }
+
+    /// Same as compile_grammar, but each item is given to on_item with the
+    /// result of its generation as soon as it is visited, so that the
+    /// function of a top-level expression can be run before the next one
+    /// replaces it. The generation only stops at the first error returned
+    /// by on_item, which can report the errors of the items and go on.
+    pub fn compile_grammar_with(
+        &mut self,
+        grammar: &KaleoGrammar,
+        mut on_item: impl FnMut(&mut Self, &TopAST, Result<AnyValueEnum<'ctx>>) -> Result<()>,
+    ) -> Result<()> {
+        self.declare_prototypes(grammar);
+        for top_elem in grammar {
+            let value = self.visit_top(top_elem);
+            on_item(self, top_elem, value)?;
+        }
+        Ok(())
+    }
+
+    /// Make the functions defined in grammar callable before their
+    /// definition is visited, so that a function can call another one
+    /// defined later in the same file. Operators are left out, as they
+    /// cannot be used before being defined.
+    pub fn declare_prototypes(&mut self, grammar: &KaleoGrammar) {
+        for top_elem in grammar {
+            let TopAST::Function(func_elem) = top_elem else {
+                continue;
+            };
+            if self.is_top_function(func_elem) || func_elem.proto.operator.is_some() {
+                continue;
+            }
+            self.prototypes
+                .entry(func_elem.proto.name.clone())
+                .or_insert_with(|| func_elem.proto.clone());
+        }
+    }
+
+    /// Name and number of arguments of the functions defined or declared so
+    /// far, sorted by name
+    pub fn functions(&self) -> Vec<(String, usize)> {
+        let mut functions: Vec<_> = self
+            .prototypes
+            .values()
+            .filter(|proto| proto.name != self.anonymous_name)
+            .map(|proto| (proto.name.clone(), proto.args.len()))
+            .collect();
+        functions.sort();
+        functions
     }
 
-    fn generate_and_get_func(&mut self, func_name: &str) -> Result<FunctionValue> {
-        if let Some(func_val) = self
-            .modules
-            .last()
-            .ok_or(anyhow!("No module found"))?
+    /// Names of the global variables declared so far
+    pub fn global_names(&self) -> Vec<String> {
+        self.module
+            .get_globals()
+            .filter_map(|global| global.get_name().to_str().ok().map(String::from))
+            .filter(|name| name != LOOP_COUNTER)
+            .collect()
+    }
+
+    /// JIT-compile the generated code, then call the function named
+    /// func_name with the given arguments and return its result.
+    ///
+    /// Functions are only compiled once: the ones defined since the previous
+    /// compilation are compiled together when one of them is run, so that
+    /// calling the same functions again is cheap. Globals keep the values set
+    /// by the previous calls.
+    pub fn run_function(&self, func_name: &str, args: &[f64]) -> Result<f64> {
+        let func = self
+            .module
             .get_function(func_name)
-        {
+            .ok_or(anyhow!("Unknown function '{func_name}'"))?;
+        ensure!(
+            func.get_type().get_return_type().is_some(),
+            "function '{func_name}' does not return a value"
+        );
+        ensure!(
+            func.count_params() as usize == args.len(),
+            "function '{func_name}' expects {} args, got {}",
+            func.count_params(),
+            args.len()
+        );
+        ensure!(
+            args.len() <= MAX_RUN_ARGS,
+            "cannot run a function with more than {MAX_RUN_ARGS} args"
+        );
+        let address = self.jit_function_address(func_name)?;
+        LOOP_LIMIT_REACHED.with(|reached| reached.set(false));
+        LOOP_ITERATIONS.with(|count| count.set(0));
+        // The signature was checked against the prototype of the function
+        let result = unsafe {
+            match self.float_width {
+                FloatWidth::F64 => call_jit::<f64>(address, args),
+                FloatWidth::F32 => {
+                    let args: Vec<f32> = args.iter().map(|&arg| arg as f32).collect();
+                    f64::from(call_jit::<f32>(address, &args))
+                }
+            }
+        };
+        if LOOP_LIMIT_REACHED.with(Cell::get) {
+            bail!(
+                "evaluation of '{func_name}' aborted after {} loop iterations",
+                self.max_loop_iterations.unwrap_or_default()
+            );
+        }
+        Ok(result)
+    }
+
+    /// Address of the compiled function func_name. The definitions added to
+    /// the module since the previous compilation are compiled if func_name
+    /// is one of them, and the address of a function is only looked up once.
+    fn jit_function_address(&self, func_name: &str) -> Result<usize> {
+        let mut cache = self.jit.borrow_mut();
+        let jit = self.jit_cache(&mut cache)?;
+        if let Some(&address) = jit.addresses.get(func_name) {
+            return Ok(address);
+        }
+        if !jit.defined.contains(func_name) {
+            self.add_jit_definitions(jit)?;
+        }
+        let address = jit
+            .engine
+            .get_function_address(func_name)
+            .map_err(|err| anyhow!(err.to_string()))?;
+        self.jit_lookups.set(self.jit_lookups.get() + 1);
+        jit.addresses.insert(func_name.to_string(), address);
+        Ok(address)
+    }
+
+    /// Execution engine, created if needed. It starts with a module only
+    /// declaring what the JIT provides to the generated code.
+    fn jit_cache<'c>(
+        &self,
+        cache: &'c mut Option<JitCache<'ctx>>,
+    ) -> Result<&'c mut JitCache<'ctx>> {
+        match cache {
+            Some(jit) => Ok(jit),
+            None => {
+                let runtime = self.context.create_module("jit runtime");
+                let (counter, trap) = Self::declare_loop_limit(self.context, &runtime);
+                let engine = runtime
+                    .create_jit_execution_engine(OptimizationLevel::None)
+                    .map_err(|err| anyhow!(err.to_string()))?;
+                // Mappings are by name, so they apply to all the modules
+                engine.add_global_mapping(&trap, loop_limit_reached as usize);
+                let counter_address = LOOP_ITERATIONS.with(|count| count.as_ptr() as usize);
+                engine.add_global_mapping(&counter, counter_address);
+                Ok(cache.insert(JitCache {
+                    engine,
+                    addresses: HashMap::new(),
+                    defined: HashSet::new(),
+                }))
+            }
+        }
+    }
+
+    /// Give the engine a copy of the module, if it defines functions or
+    /// globals the engine does not have yet. The definitions the engine
+    /// already has are only available externally in the copy: they are not
+    /// compiled again, the new code is linked with the compiled ones, so
+    /// that globals keep their values.
+    fn add_jit_definitions(&self, jit: &mut JitCache<'ctx>) -> Result<()> {
+        let module = self.module.clone();
+        let functions = module
+            .get_functions()
+            .filter(|func| func.count_basic_blocks() > 0)
+            .map(|func| func.as_global_value());
+        let globals = module
+            .get_globals()
+            .filter(|global| global.get_initializer().is_some());
+        let mut new_definitions = vec![];
+        for definition in functions.chain(globals) {
+            let name = definition.get_name().to_string_lossy().into_owned();
+            if jit.defined.contains(&name) {
+                definition.set_linkage(Linkage::AvailableExternally);
+            } else {
+                new_definitions.push(name);
+            }
+        }
+        if new_definitions.is_empty() {
+            return Ok(());
+        }
+        jit.engine
+            .add_module(&module)
+            .map_err(|()| anyhow!("Could not add the module to the execution engine"))?;
+        jit.defined.extend(new_definitions);
+        Ok(())
+    }
+
+    /// Address each function declared with `extern` is linked to by the
+    /// JIT, sorted by name. None if the symbol cannot be found in the
+    /// process, calling the function would then abort the evaluation.
+    pub fn resolve_externs(&self) -> Result<Vec<(String, Option<usize>)>> {
+        // The JIT looks the symbols up in the process, which the creation
+        // of the execution engine makes searchable
+        self.jit_cache(&mut self.jit.borrow_mut())?;
+        let mut externs: Vec<_> = self
+            .prototypes
+            .keys()
+            .filter(|name| {
+                self.module
+                    .get_function(name)
+                    .is_some_and(|func| func.get_first_basic_block().is_none())
+            })
+            .map(|name| (name.clone(), search_for_address_of_symbol(name)))
+            .collect();
+        externs.sort();
+        Ok(externs)
+    }
+
+    /// Functions of the module declared without a body, sorted by name.
+    /// An object file produced from the module must be linked with them,
+    /// even those which are declared but never called.
+    pub fn external_dependencies(&self) -> Vec<String> {
+        let mut deps: Vec<_> = self
+            .module
+            .get_functions()
+            .filter(|func| func.count_basic_blocks() == 0)
+            .map(|func| func.get_name().to_string_lossy().into_owned())
+            // Provided by the JIT, not something to link with
+            .filter(|name| name != LOOP_LIMIT_TRAP)
+            .collect();
+        deps.sort();
+        deps
+    }
+
+    /// Delete the function of the last top-level expression, if any, so that
+    /// the emitted files only contain the definitions
+    pub fn remove_anonymous_function(&mut self) -> Result<()> {
+        // The compiled one stays in the engine, until the next one replaces
+        // it under the same name
+        if let Some(jit) = self.jit.get_mut() {
+            jit.defined.remove(&self.anonymous_name);
+            jit.addresses.remove(&self.anonymous_name);
+        }
+        if let Some(func) = self.module.get_function(&self.anonymous_name) {
+            unsafe {
+                func.delete();
+            }
+        }
+        Ok(())
+    }
+
+    /// Number of function addresses looked up in a compiled module since
+    /// the creation of the CodeGen, calls served by the cache excluded
+    pub fn jit_lookups(&self) -> usize {
+        self.jit_lookups.get()
+    }
+
+    /// Run the LLVM verifier on all the functions generated so far.
+    pub fn verify_all(&self) -> Result<()> {
+        self.module
+            .verify()
+            .map_err(|message| anyhow!("Verification failed: {message}"))
+    }
+
+    /// Write the LLVM bitcode of all the functions generated so far.
+    pub fn write_bitcode(&self, path: &Path) -> Result<()> {
+        ensure!(
+            self.module.write_bitcode_to_path(path),
+            "Could not write bitcode to {}",
+            path.display()
+        );
+        Ok(())
+    }
+
+    /// Write the IR now to the path given to CodeGenBuilder::ir_on_drop,
+    /// instead of when the CodeGen is dropped. Does nothing without a path.
+    pub fn flush_ir(&mut self) -> Result<()> {
+        match self.ir_on_drop.take() {
+            Some(path) => self.write_ir(&path),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the textual LLVM IR of all the functions generated so far.
+    pub fn write_ir(&self, path: &Path) -> Result<()> {
+        self.module
+            .print_to_file(path)
+            .map_err(|err| anyhow!("Could not write IR to {}: {err}", path.display()))
+    }
+
+    fn generate_and_get_func(&mut self, func_name: &str) -> Result<FunctionValue<'ctx>> {
+        if let Some(func_val) = self.module.get_function(func_name) {
             Ok(func_val)
         } else {
-            let proto_ast = self
-                .prototypes
-                .get(func_name)
-                .ok_or(anyhow!("{func_name} not found in prototype lists"))?
-                .clone();
+            let Some(proto_ast) = self.prototypes.get(func_name).cloned() else {
+                match self.closest_function(func_name) {
+                    Some(close) => {
+                        bail!("{func_name} not found in prototype lists, did you mean '{close}'?")
+                    }
+                    None => bail!("{func_name} not found in prototype lists"),
+                }
+            };
             match self.visit_prototype(&proto_ast)? {
                 AnyValueEnum::FunctionValue(func_val) => Ok(func_val),
                 _ => bail!("Shoul have been a function value"),
@@ -139,10 +759,23 @@ impl<'ctx> CodeGen<'ctx> {
         }
     }
 
+    /// Known function whose name is the closest to name, if close enough to
+    /// be a typo
+    fn closest_function(&self, name: &str) -> Option<&str> {
+        self.prototypes
+            .keys()
+            .filter(|candidate| **candidate != self.anonymous_name)
+            .map(|candidate| (edit_distance(name, candidate), candidate))
+            .filter(|&(distance, _)| distance <= 2 && distance < name.chars().count())
+            .min()
+            .map(|(_, candidate)| candidate.as_str())
+    }
+
     fn create_entry_block_alloca<'c>(
         ctx: &'c Context,
         func: &FunctionValue,
         var_name: &str,
+        var_type: BasicTypeEnum<'c>,
     ) -> Result<PointerValue<'c>> {
         let local_builder = ctx.create_builder();
         let first_block = func
@@ -152,73 +785,190 @@ impl<'ctx> CodeGen<'ctx> {
             Some(first_inst) => local_builder.position_before(&first_inst),
             None => local_builder.position_at_end(first_block),
         }
-        Ok(local_builder.build_alloca(ctx.f64_type(), var_name))
+        Ok(local_builder.build_alloca(var_type, var_name))
     }
 
-    fn visit_unary_expr(&mut self, unary_elem: &UnaryExprAST) -> CodeGenResult<'ctx> {
-        let operand_value = self.visit_expr(&unary_elem.operand)?.into_float_value();
-        let func_name = PrototypeAST::gen_unary_func_name(unary_elem.opcode);
-        let func = generate_and_get_func!(self, &func_name)?;
-        let result = self
-            .builder
-            .build_call(func, &[operand_value.into()], "unop")
+    /// True if the operator function was defined or declared by the user.
+    /// User definitions take precedence over the built-in operators, so that
+    /// scripts written for earlier chapters, defining `binary=` as an
+    /// equality or `unary-`, keep the meaning they give to them. Redefining
+    /// one with itself, like `def binary+ (a b) a + b`, would then recurse
+    /// forever, so visit_function rejects it.
+    fn is_user_operator(&self, func_name: &str) -> bool {
+        self.prototypes.contains_key(func_name) || self.module.get_function(func_name).is_some()
+    }
+
+    fn call_operator(
+        &mut self,
+        func_name: &str,
+        args: &[FloatValue<'ctx>],
+    ) -> Result<FloatValue<'ctx>> {
+        let func = self.generate_and_get_func(func_name)?;
+        let args: Vec<_> = args.iter().map(|&arg| arg.into()).collect();
+        let call = self.builder.build_call(func, &args, "opcall");
+        self.build_limit_check()?;
+        Ok(call
             .try_as_basic_value()
             .left()
             .ok_or(anyhow!("Error when calling function"))?
-            .into_float_value();
+            .into_float_value())
+    }
+
+    fn visit_unary_expr(&mut self, unary_elem: &UnaryExprAST) -> CodeGenResult<'ctx> {
+        let func_name = PrototypeAST::gen_unary_func_name(unary_elem.opcode);
+        let operand_value = self.visit_expr(&unary_elem.operand)?;
+        if self.is_user_operator(&func_name) {
+            let operand_value = self.as_float(operand_value)?;
+            let result = self.call_operator(&func_name, &[operand_value])?;
+            return Ok(AnyValueEnum::FloatValue(result));
+        }
+        match (unary_elem.opcode, operand_value) {
+            // -i64::MIN overflows, it is then negated as a float
+            ('-', AnyValueEnum::IntValue(int_val))
+                if int_val.get_sign_extended_constant() != Some(i64::MIN) =>
+            {
+                return Ok(AnyValueEnum::IntValue(
+                    self.builder.build_int_neg(int_val, "negtmp"),
+                ))
+            }
+            ('-', value) => {
+                let float_val = self.as_float(value)?;
+                return Ok(AnyValueEnum::FloatValue(
+                    self.builder.build_float_neg(float_val, "negtmp"),
+                ));
+            }
+            ('!', value) => {
+                let float_val = self.as_float(value)?;
+                let comp = self.builder.build_float_compare(
+                    FloatPredicate::OEQ,
+                    float_val,
+                    self.float_type().const_float(0.0),
+                    "nottmp",
+                );
+                return Ok(AnyValueEnum::FloatValue(
+                    self.builder
+                        .build_unsigned_int_to_float(comp, self.float_type(), "booltmp"),
+                ));
+            }
+            _ => (),
+        }
+        let operand_value = self.as_float(operand_value)?;
+        let result = self.call_operator(&func_name, &[operand_value])?;
         Ok(AnyValueEnum::FloatValue(result))
     }
 
     fn visit_binary_expr(&mut self, bin_elem: &BinaryExprAST) -> CodeGenResult<'ctx> {
-        if bin_elem.op == '=' {
+        let func_name = PrototypeAST::gen_binary_func_name(&bin_elem.op);
+        if self.is_user_operator(&func_name) {
+            let l = self.visit_float_expr(&bin_elem.lhs)?;
+            let r = self.visit_float_expr(&bin_elem.rhs)?;
+            let result = self.call_operator(&func_name, &[l, r])?;
+            return Ok(AnyValueEnum::FloatValue(result));
+        }
+        if bin_elem.op == "&&" || bin_elem.op == "||" {
+            return self.visit_short_circuit_expr(bin_elem);
+        }
+        if bin_elem.op == ":" {
+            // Only evaluated for its side effects
+            self.visit_expr(&bin_elem.lhs)?;
+            return self.visit_expr(&bin_elem.rhs);
+        }
+        if bin_elem.op == "=" {
             let ExprAST::VariableExpr(lhse) = bin_elem.lhs.as_ref() else {
                 bail!("Destination of '=' must be a variable");
             };
-            let rhs_val: inkwell::values::FloatValue =
-                self.visit_expr(&bin_elem.rhs)?.into_float_value();
-            let variable = self
-                .named_values_ctx
-                .get(&lhse.name)
-                .ok_or(anyhow!("Unknown variable name"))?;
-            self.builder.build_store(*variable, rhs_val);
+            let rhs_val = self.visit_float_expr(&bin_elem.rhs)?;
+            let variable = self.get_variable_pointer(&lhse.name)?;
+            self.builder.build_store(variable, rhs_val);
             return Ok(rhs_val.as_any_value_enum());
         }
-        let l = self.visit_expr(&bin_elem.lhs)?.into_float_value();
-        let r = self.visit_expr(&bin_elem.rhs)?.into_float_value();
-        let result = match bin_elem.op {
-            '+' => self.builder.build_float_add(l, r, "addtmp"),
-            '-' => self.builder.build_float_sub(l, r, "subtmp"),
-            '*' => self.builder.build_float_mul(l, r, "multmp"),
-            '<' => {
-                let comp =
-                    self.builder
-                        .build_float_compare(inkwell::FloatPredicate::ULT, l, r, "cmttmp");
+        let l = self.visit_expr(&bin_elem.lhs)?;
+        let r = self.visit_expr(&bin_elem.rhs)?;
+        if let Some(predicates) = comparison_predicates(&bin_elem.op) {
+            let comp = self.build_comparison(predicates, l, r)?;
+            return Ok(AnyValueEnum::FloatValue(
                 self.builder
-                    .build_unsigned_int_to_float(comp, self.context.f64_type(), "booltmp")
-            }
-            other => {
-                let func_name = PrototypeAST::gen_binary_func_name(other);
-                let func = generate_and_get_func!(self, &func_name)?;
-                self.builder
-                    .build_call(func, &[l.into(), r.into()], "binop")
-                    .try_as_basic_value()
-                    .left()
-                    .ok_or(anyhow!("Error when calling function"))?
-                    .into_float_value()
+                    .build_unsigned_int_to_float(comp, self.float_type(), "booltmp"),
+            ));
+        }
+        if let (AnyValueEnum::IntValue(l), AnyValueEnum::IntValue(r)) = (l, r) {
+            if let Some(result) = self.fold_int_binary_op(&bin_elem.op, l, r) {
+                return Ok(result);
             }
+        }
+        let l = self.as_float(l)?;
+        let r = self.as_float(r)?;
+        let result = match bin_elem.op.as_str() {
+            "+" => self.builder.build_float_add(l, r, "addtmp"),
+            "-" => self.builder.build_float_sub(l, r, "subtmp"),
+            "*" => self.builder.build_float_mul(l, r, "multmp"),
+            "/" => self.builder.build_float_div(l, r, "divtmp"),
+            "%" => self.builder.build_float_rem(l, r, "remtmp"),
+            _ => self.call_operator(&func_name, &[l, r])?,
         };
         Ok(AnyValueEnum::FloatValue(result))
     }
 
+    /// Integer arithmetic on two integers, computed at compile time. Integers
+    /// only exist as literals: there is no integer variable, parameter or
+    /// return value, so both sides are always constants. Returns None if the
+    /// operator has no integer implementation or overflows, the float
+    /// operation is then used, as for any other number: `7 % 0` is NaN.
+    fn fold_int_binary_op(
+        &self,
+        op: &str,
+        l: IntValue<'ctx>,
+        r: IntValue<'ctx>,
+    ) -> Option<AnyValueEnum<'ctx>> {
+        let val = int_binary_op(
+            op,
+            l.get_sign_extended_constant()?,
+            r.get_sign_extended_constant()?,
+        )?;
+        Some(AnyValueEnum::IntValue(
+            l.get_type().const_int(val as u64, true),
+        ))
+    }
+
+    /// Convert an integer value to a float, the type used outside of
+    /// integer arithmetic: variables, function parameters and return values.
+    fn as_float(&self, value: AnyValueEnum<'ctx>) -> Result<FloatValue<'ctx>> {
+        match value {
+            AnyValueEnum::FloatValue(float_val) => Ok(float_val),
+            AnyValueEnum::IntValue(int_val) => {
+                Ok(self
+                    .builder
+                    .build_signed_int_to_float(int_val, self.float_type(), "inttofp"))
+            }
+            other => bail!("Was expecting a number, got {other:?}"),
+        }
+    }
+
+    fn visit_float_expr(&mut self, expr_elem: &ExprAST) -> Result<FloatValue<'ctx>> {
+        let value = self.visit_expr(expr_elem)?;
+        self.as_float(value)
+    }
+
     fn visit_expr(&mut self, expr_elem: &ExprAST) -> CodeGenResult<'ctx> {
+        let value = self.generate_expr(expr_elem)?;
+        if let Some(observer) = &mut self.observer {
+            observer.on_expr(expr_elem, &value);
+        }
+        Ok(value)
+    }
+
+    fn generate_expr(&mut self, expr_elem: &ExprAST) -> CodeGenResult<'ctx> {
         match expr_elem {
             ExprAST::NumberExpr(num_elem) => self.visit_number_expr(num_elem),
+            ExprAST::IntExpr(int_elem) => self.visit_int_expr(int_elem),
+            ExprAST::StringExpr(_) => bail!("String literals are not supported by code generation"),
             ExprAST::VariableExpr(var_elem) => self.visit_variable_expr(var_elem),
             ExprAST::UnaryExpr(unary_elem) => self.visit_unary_expr(unary_elem),
             ExprAST::BinaryExpr(bin_elem) => self.visit_binary_expr(bin_elem),
             ExprAST::CallExpr(call_elem) => self.visit_call_expr(call_elem),
             ExprAST::IfExpr(if_elem) => self.visit_if_expr(if_elem),
             ExprAST::ForExpr(for_elem) => self.visit_for_expr(for_elem),
+            ExprAST::WhileExpr(while_elem) => self.visit_while_expr(while_elem),
             ExprAST::VarExpr(var_elem) => self.visit_var_expr(var_elem),
         }
     }
@@ -233,12 +983,16 @@ impl<'ctx> CodeGen<'ctx> {
             .ok_or(anyhow!("No parent"))?;
         for (var_name, init_expr) in &var_elem.var_names {
             let init_val = match init_expr {
-                Some(expr) => self.visit_expr(expr)?,
-                None => self.context.f64_type().const_float(0.).as_any_value_enum(),
+                Some(expr) => self.visit_float_expr(expr)?,
+                None => self.float_type().const_float(0.),
             };
-            let alloca = Self::create_entry_block_alloca(self.context, &func, var_name)?;
-            self.builder
-                .build_store(alloca, init_val.into_float_value());
+            let alloca = Self::create_entry_block_alloca(
+                self.context,
+                &func,
+                var_name,
+                self.float_type().into(),
+            )?;
+            self.builder.build_store(alloca, init_val);
             if let Some(old_alloca) = self.named_values_ctx.get(var_name) {
                 old_allocas.push((var_name.clone(), *old_alloca));
             }
@@ -251,124 +1005,405 @@ impl<'ctx> CodeGen<'ctx> {
         Ok(body_val)
     }
 
-    fn visit_if_expr(&mut self, if_elem: &IfExprAST) -> CodeGenResult<'ctx> {
-        let cond_value = self.visit_expr(&if_elem.condition)?;
-        let current_func = self
+    /// Comparison as an i1, with integer comparison if both sides are integers
+    fn build_comparison(
+        &self,
+        (int_predicate, float_predicate): (IntPredicate, FloatPredicate),
+        l: AnyValueEnum<'ctx>,
+        r: AnyValueEnum<'ctx>,
+    ) -> Result<IntValue<'ctx>> {
+        if let (AnyValueEnum::IntValue(l), AnyValueEnum::IntValue(r)) = (l, r) {
+            return Ok(self
+                .builder
+                .build_int_compare(int_predicate, l, r, "cmptmp"));
+        }
+        let l = self.as_float(l)?;
+        let r = self.as_float(r)?;
+        Ok(self
             .builder
-            .get_insert_block()
-            .ok_or(anyhow!("No block"))?
-            .get_parent()
-            .ok_or(anyhow!("No parent"))?;
-        let comparison = self.builder.build_float_compare(
-            inkwell::FloatPredicate::ONE,
-            cond_value.into_float_value(),
-            self.context.f64_type().const_float(0.0),
-            "ifcond",
-        );
-        let then_block = self.context.append_basic_block(current_func, "then");
-        let else_block = self.context.append_basic_block(current_func, "else");
-        let merge_block = self.context.append_basic_block(current_func, "ifcont");
-        self.builder
-            .build_conditional_branch(comparison, then_block, else_block);
-        // Then block
-        self.builder.position_at_end(then_block);
-        let then_value = self.visit_expr(&if_elem.then_block)?.into_float_value();
-        self.builder.build_unconditional_branch(merge_block);
-        let phi_then_block = self
+            .build_float_compare(float_predicate, l, r, "cmptmp"))
+    }
+
+    /// Evaluate a condition to an i1. A built-in comparison is used as is,
+    /// rather than converted to a float then compared again to 0.
+    fn visit_condition(&mut self, expr_elem: &ExprAST, name: &str) -> Result<IntValue<'ctx>> {
+        if let ExprAST::BinaryExpr(bin_elem) = expr_elem {
+            let predicates = comparison_predicates(&bin_elem.op).filter(|_| {
+                !self.is_user_operator(&PrototypeAST::gen_binary_func_name(&bin_elem.op))
+            });
+            if let Some(predicates) = predicates {
+                let l = self.visit_expr(&bin_elem.lhs)?;
+                let r = self.visit_expr(&bin_elem.rhs)?;
+                return self.build_comparison(predicates, l, r);
+            }
+        }
+        let value = self.visit_float_expr(expr_elem)?;
+        Ok(self.builder.build_float_compare(
+            FloatPredicate::ONE,
+            value,
+            self.float_type().const_float(0.0),
+            name,
+        ))
+    }
+
+    /// `&&` and `||` only evaluate their right operand if the left one does
+    /// not already determine the result, and yield 0.0 or 1.0.
+    fn visit_short_circuit_expr(&mut self, bin_elem: &BinaryExprAST) -> CodeGenResult<'ctx> {
+        let is_and = bin_elem.op == "&&";
+        let zero = self.float_type().const_float(0.0);
+        let lhs_value = self.visit_float_expr(&bin_elem.lhs)?;
+        let lhs_bool =
+            self.builder
+                .build_float_compare(FloatPredicate::ONE, lhs_value, zero, "lhsbool");
+        let lhs_block = self
             .builder
             .get_insert_block()
             .ok_or(anyhow!("Could not find block"))?;
-        // Else block
-        self.builder.position_at_end(else_block);
-        let else_value = self.visit_expr(&if_elem.else_block)?.into_float_value();
+        let current_func = lhs_block.get_parent().ok_or(anyhow!("No parent"))?;
+        let rhs_block = self.context.append_basic_block(current_func, "rhs");
+        let merge_block = self.context.append_basic_block(current_func, "logiccont");
+        if is_and {
+            self.builder
+                .build_conditional_branch(lhs_bool, rhs_block, merge_block);
+        } else {
+            self.builder
+                .build_conditional_branch(lhs_bool, merge_block, rhs_block);
+        }
+        // Right operand block
+        self.builder.position_at_end(rhs_block);
+        let rhs_value = self.visit_float_expr(&bin_elem.rhs)?;
+        let rhs_bool =
+            self.builder
+                .build_float_compare(FloatPredicate::ONE, rhs_value, zero, "rhsbool");
         self.builder.build_unconditional_branch(merge_block);
-        let phi_else_block = self
+        let phi_rhs_block = self
             .builder
             .get_insert_block()
             .ok_or(anyhow!("Could not find block"))?;
+        // Merge block, the left operand alone gives false for && and true for ||
+        self.builder.position_at_end(merge_block);
+        let short_value = self
+            .context
+            .bool_type()
+            .const_int(u64::from(!is_and), false);
+        let phi_node = self.builder.build_phi(self.context.bool_type(), "logictmp");
+        phi_node.add_incoming(&[(&short_value, lhs_block), (&rhs_bool, phi_rhs_block)]);
+        Ok(AnyValueEnum::FloatValue(
+            self.builder.build_unsigned_int_to_float(
+                phi_node.as_basic_value().into_int_value(),
+                self.float_type(),
+                "booltmp",
+            ),
+        ))
+    }
+
+    fn visit_if_expr(&mut self, if_elem: &IfExprAST) -> CodeGenResult<'ctx> {
+        let comparison = self.visit_condition(&if_elem.condition, "ifcond")?;
+        if is_pure_operand(&if_elem.then_block) && is_pure_operand(&if_elem.else_block) {
+            // Nothing to skip in either branch, both values can be computed
+            let then_value = self.visit_float_expr(&if_elem.then_block)?;
+            let else_value = self.visit_float_expr(&if_elem.else_block)?;
+            let select = self
+                .builder
+                .build_select(comparison, then_value, else_value, "iftmp");
+            return Ok(AnyValueEnum::FloatValue(select.into_float_value()));
+        }
+        let current_func = self
+            .builder
+            .get_insert_block()
+            .ok_or(anyhow!("No block"))?
+            .get_parent()
+            .ok_or(anyhow!("No parent"))?;
+        // An `else if` chain is flattened: each condition is tested in the
+        // else block of the previous one, and all the branches jump to a
+        // single merge block, instead of one merge block per `if`.
+        let mut incoming = vec![];
+        let mut arm = if_elem;
+        let mut comparison = comparison;
+        let else_expr = loop {
+            let then_block = self.context.append_basic_block(current_func, "then");
+            let else_block = self.context.append_basic_block(current_func, "else");
+            self.builder
+                .build_conditional_branch(comparison, then_block, else_block);
+            self.builder.position_at_end(then_block);
+            let then_value = self.visit_float_expr(&arm.then_block)?;
+            incoming.push((then_value, self.current_block()?));
+            self.builder.position_at_end(else_block);
+            match arm.else_block.as_ref() {
+                ExprAST::IfExpr(next_arm) => {
+                    comparison = self.visit_condition(&next_arm.condition, "ifcond")?;
+                    arm = next_arm;
+                }
+                else_expr => break else_expr,
+            }
+        };
+        let else_value = self.visit_float_expr(else_expr)?;
+        incoming.push((else_value, self.current_block()?));
         // Merge block
+        let merge_block = self.context.append_basic_block(current_func, "ifcont");
+        for (_, block) in &incoming {
+            self.builder.position_at_end(*block);
+            self.builder.build_unconditional_branch(merge_block);
+        }
         self.builder.position_at_end(merge_block);
-        let phi_node = self.builder.build_phi(self.context.f64_type(), "iftmp");
-        phi_node.add_incoming(&[(&then_value, phi_then_block), (&else_value, phi_else_block)]);
+        let phi_node = self.builder.build_phi(self.float_type(), "iftmp");
+        for (value, block) in &incoming {
+            phi_node.add_incoming(&[(value, *block)]);
+        }
         Ok(AnyValueEnum::FloatValue(
             phi_node.as_basic_value().into_float_value(),
         ))
     }
 
+    /// Block where the next instruction will be inserted
+    fn current_block(&self) -> Result<BasicBlock<'ctx>> {
+        self.builder
+            .get_insert_block()
+            .ok_or(anyhow!("Could not find block"))
+    }
+
     fn visit_number_expr(&mut self, num_elem: &NumberExprAST) -> CodeGenResult<'ctx> {
-        let f64_type = self.context.f64_type();
-        Ok(AnyValueEnum::FloatValue(f64_type.const_float(num_elem.val)))
+        Ok(AnyValueEnum::FloatValue(
+            self.float_type().const_float(num_elem.val),
+        ))
+    }
+
+    fn visit_int_expr(&mut self, int_elem: &IntExprAST) -> CodeGenResult<'ctx> {
+        let i64_type = self.context.i64_type();
+        Ok(AnyValueEnum::IntValue(
+            i64_type.const_int(int_elem.val as u64, true),
+        ))
     }
 
     fn visit_variable_expr(&mut self, var_elem: &VariableExprAST) -> CodeGenResult<'ctx> {
-        let alloca_pointer = self
-            .named_values_ctx
-            .get(&var_elem.name)
-            .ok_or(anyhow!("Unknown variable name"))?;
-        let loaded_val =
-            self.builder
-                .build_load(self.context.f64_type(), *alloca_pointer, &var_elem.name);
+        let pointer = self.get_variable_pointer(&var_elem.name)?;
+        let loaded_val = self
+            .builder
+            .build_load(self.float_type(), pointer, &var_elem.name);
         Ok(loaded_val.as_any_value_enum())
     }
 
+    /// Local variables shadow the globals
+    fn get_variable_pointer(&self, name: &str) -> Result<PointerValue<'ctx>> {
+        self.named_values_ctx
+            .get(name)
+            .copied()
+            .or_else(|| {
+                self.module
+                    .get_global(name)
+                    .map(|global| global.as_pointer_value())
+            })
+            .ok_or(anyhow!("Unknown variable name"))
+    }
+
+    fn visit_global_var(&mut self, global_elem: &GlobalVarAST) -> CodeGenResult<'ctx> {
+        let name = &global_elem.name;
+        ensure!(
+            self.module.get_global(name).is_none() && self.module.get_function(name).is_none(),
+            "redefinition of global '{name}'"
+        );
+        let init_val = match global_elem.init.clone().map(fold_constants) {
+            None => 0.0,
+            Some(ExprAST::NumberExpr(number)) => number.val,
+            Some(ExprAST::IntExpr(int)) => int.val as f64,
+            Some(_) => bail!("Initializer of global '{name}' must be a constant"),
+        };
+        let float_type = self.float_type();
+        let global = self.module.add_global(float_type, None, name);
+        global.set_initializer(&float_type.const_float(init_val));
+        Ok(global.as_pointer_value().as_any_value_enum())
+    }
+
     fn visit_call_expr(&mut self, call_elem: &CallExprAST) -> CodeGenResult<'ctx> {
         let func_name = &call_elem.callee;
-        let func = generate_and_get_func!(self, func_name)?;
+        let func = self.generate_and_get_func(func_name)?;
+        let param_count = func.count_params() as usize;
         ensure!(
-            func.count_params() == call_elem.args.len() as u32,
+            param_count == call_elem.args.len()
+                || func.get_type().is_var_arg() && param_count < call_elem.args.len(),
             "Bad parameter number"
         );
         let mut arg_values = vec![];
         for expr_elem in &call_elem.args {
-            arg_values.push(self.visit_expr(expr_elem)?.into_float_value().into());
+            arg_values.push(self.visit_float_expr(expr_elem)?.into());
         }
-        Ok(AnyValueEnum::FloatValue(
-            self.builder
-                .build_call(func, arg_values.as_slice(), "calltmp")
-                .try_as_basic_value()
-                .left()
-                .ok_or(anyhow!("Error when calling function"))?
-                .into_float_value(),
-        ))
+        let returns_void = func.get_type().get_return_type().is_none();
+        // Void values cannot be named
+        let name = if returns_void { "" } else { "calltmp" };
+        let call = self.builder.build_call(func, arg_values.as_slice(), name);
+        self.build_limit_check()?;
+        let value = match call.try_as_basic_value().left() {
+            Some(value) => value.into_float_value(),
+            // Calling a void function is still an expression, worth 0
+            None => self.float_type().const_float(0.0),
+        };
+        Ok(AnyValueEnum::FloatValue(value))
     }
 
     fn visit_prototype(&mut self, proto_elem: &PrototypeAST) -> CodeGenResult<'ctx> {
-        let f64_type: BasicMetadataTypeEnum = self.context.f64_type().into();
-        let param_types = vec![f64_type; proto_elem.args.len()];
+        let float_type: BasicMetadataTypeEnum = self.float_type().into();
+        let param_types = vec![float_type; proto_elem.args.len()];
         let func_name = &proto_elem.name;
-        let func_type = self
-            .context
-            .f64_type()
-            .fn_type(param_types.as_slice(), false);
+        let var_args = proto_elem.var_args;
+        let func_type = match proto_elem.ret_type {
+            ReturnType::Double => self.float_type().fn_type(&param_types, var_args),
+            ReturnType::Void => self.context.void_type().fn_type(&param_types, var_args),
+        };
+        if let Some(func) = self.module.get_function(func_name) {
+            Self::ensure_same_signature(func, proto_elem, "redeclaration")?;
+            return Ok(AnyValueEnum::FunctionValue(func));
+        }
         let func = self
-            .modules
-            .last()
-            .ok_or(anyhow!("No module found"))?
-            .add_function(
-                func_name,
-                func_type,
-                Some(inkwell::module::Linkage::External),
-            );
+            .module
+            .add_function(func_name, func_type, Some(Linkage::External));
         func.get_params().iter().enumerate().for_each(|(idx, arg)| {
             arg.set_name(&proto_elem.args[idx]);
         });
         Ok(AnyValueEnum::FunctionValue(func))
     }
 
+    /// Fail if func, already in the module, does not have the signature of
+    /// proto_elem. What is "redefinition" or "redeclaration", for the error.
+    fn ensure_same_signature(
+        func: FunctionValue<'ctx>,
+        proto_elem: &PrototypeAST,
+        what: &str,
+    ) -> Result<()> {
+        let func_name = &proto_elem.name;
+        ensure!(
+            func.count_params() as usize == proto_elem.args.len(),
+            "{what} of function '{func_name}' with a different number of arguments"
+        );
+        ensure!(
+            func.get_type().get_return_type().is_none()
+                == (proto_elem.ret_type == ReturnType::Void),
+            "{what} of function '{func_name}' with a different return type"
+        );
+        ensure!(
+            func.get_type().is_var_arg() == proto_elem.var_args,
+            "{what} of function '{func_name}' with a different '...'"
+        );
+        Ok(())
+    }
+
     fn visit_function(&mut self, func_elem: &FunctionAST) -> CodeGenResult<'ctx> {
         let proto_elem = &func_elem.proto;
         let func_name = &proto_elem.name;
-        let insert_result = self
-            .prototypes
+        ensure!(
+            !(BUILTIN_OPERATORS.contains(&func_name.as_str())
+                && uses_operator(&func_elem.body, func_name)),
+            "operator '{func_name}' cannot be defined with itself"
+        );
+        // A previous anonymous function has already been removed by visit_top,
+        // and an extern declaration can receive a body.
+        let existing = self.module.get_function(func_name);
+        if let Some(existing_func) = existing {
+            ensure!(
+                existing_func.count_basic_blocks() == 0,
+                "redefinition of function '{func_name}'"
+            );
+            ensure!(
+                !existing_func.get_type().is_var_arg(),
+                "cannot define the variadic extern '{func_name}'"
+            );
+            Self::ensure_same_signature(existing_func, proto_elem, "redefinition")?;
+        }
+        self.prototypes
             .insert(proto_elem.name.to_string(), proto_elem.clone());
-        if insert_result.is_some() && !func_elem.is_top_function() {
-            bail!("Prototype {func_name} already exists");
+        if self.is_top_function(func_elem) {
+            self.annotate(|| String::from("top-level expression"));
+        } else {
+            self.annotate(|| format!("def {proto_elem}"));
         }
-        let func = generate_and_get_func!(self, func_name)?;
-        ensure!(!func.is_null(), "Function cannot be redefined");
+        let func = self.generate_and_get_func(func_name)?;
         let basic_block = self.context.append_basic_block(func, "entry");
         self.builder.position_at_end(basic_block);
-        self.named_values_ctx.clear();
+        // The function only sees its own arguments, the variables of the
+        // enclosing context are given back once it is generated.
+        let enclosing_values = std::mem::take(&mut self.named_values_ctx);
+        let propagated;
+        let func_elem = if self.propagate_constants {
+            propagated = FunctionAST {
+                proto: proto_elem.clone(),
+                body: propagate_constants(func_elem.body.clone()),
+            };
+            &propagated
+        } else {
+            func_elem
+        };
+        let body_val = self.visit_function_body(func_elem, func);
+        self.named_values_ctx = enclosing_values;
+        match body_val {
+            Ok(ret_val) => {
+                match proto_elem.ret_type {
+                    ReturnType::Double => self.builder.build_return(Some(&ret_val)),
+                    // The body is only evaluated for its side effects
+                    ReturnType::Void => self.builder.build_return(None),
+                };
+                // The rest of the module was already verified, so any issue
+                // comes from this function.
+                if let Err(message) = self.module.verify() {
+                    self.discard_definition(func, existing.is_some());
+                    bail!("Verify function detected an issue: {message}");
+                }
+                if self.with_optim {
+                    if self.keep_unoptimized_ir {
+                        self.unoptimized_ir = Some(func.print_to_string().to_string());
+                    }
+                    self.pass_manager.run_on(&func);
+                }
+                Ok(AnyValueEnum::FunctionValue(func))
+            }
+            Err(err) => {
+                self.discard_definition(func, existing.is_some());
+                Err(err)
+            }
+        }
+    }
+
+    /// Undo the failed definition of func. A function which was declared
+    /// before, or is already called by other functions, is replaced by a
+    /// bare declaration, so that the calls do not refer to a deleted
+    /// function.
+    fn discard_definition(&self, func: FunctionValue<'ctx>, was_declared: bool) {
+        let func_ptr = func.as_global_value().as_pointer_value();
+        if !was_declared && func_ptr.get_first_use().is_none() {
+            unsafe {
+                func.delete();
+            }
+            return;
+        }
+        let name = func.get_name().to_string_lossy().into_owned();
+        let arg_names: Vec<_> = func
+            .get_param_iter()
+            .map(|arg| {
+                arg.into_float_value()
+                    .get_name()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        // Unnamed until func is deleted, which frees the name
+        let declaration = self
+            .module
+            .add_function("", func.get_type(), Some(Linkage::External));
+        let declaration_ptr = declaration.as_global_value().as_pointer_value();
+        func_ptr.replace_all_uses_with(declaration_ptr);
+        unsafe {
+            func.delete();
+        }
+        declaration_ptr.set_name(&name);
+        for (arg, arg_name) in declaration.get_param_iter().zip(&arg_names) {
+            arg.set_name(arg_name);
+        }
+    }
+
+    /// Bind the arguments of func, then generate the value of its body
+    fn visit_function_body(
+        &mut self,
+        func_elem: &FunctionAST,
+        func: FunctionValue<'ctx>,
+    ) -> Result<FloatValue<'ctx>> {
         for (idx, arg) in func.get_param_iter().enumerate() {
             let alloca_inst = Self::create_entry_block_alloca(
                 self.context,
@@ -377,71 +1412,51 @@ impl<'ctx> CodeGen<'ctx> {
                     .into_float_value()
                     .get_name()
                     .to_str()?,
+                self.float_type().into(),
             )?;
             self.builder.build_store(alloca_inst, arg);
             self.named_values_ctx
                 .insert(func_elem.proto.args[idx].clone(), alloca_inst);
         }
-        match self.visit_expr(&func_elem.body) {
-            Ok(ret_val) => {
-                self.builder.build_return(Some(&ret_val.into_float_value()));
-                if !func.verify(false) {
-                    bail!("Verify function detected an issue");
-                }
-                if self.with_optim {
-                    self.last_pass_manager.run_on(&func);
-                }
-                Ok(AnyValueEnum::FunctionValue(func))
-            }
-            error => {
-                unsafe {
-                    func.delete();
-                }
-                error
-            }
-        }
+        self.visit_float_expr(&func_elem.body)
     }
 
     pub fn visit_top(&mut self, top_elem: &TopAST) -> CodeGenResult<'ctx> {
+        self.annotation = None;
         match top_elem {
             TopAST::Function(func_elem) => {
-                self.change_module();
-                let func = self.visit_function(func_elem)?;
-                if func_elem.is_top_function() {
-                    let execution_engine = self
-                        .modules
-                        .last()
-                        .ok_or(anyhow!("No module found"))?
-                        .create_jit_execution_engine(inkwell::OptimizationLevel::None)
-                        .unwrap();
-                    self.modules
-                        .iter()
-                        .take(self.modules.len() - 1)
-                        .for_each(|m| {
-                            execution_engine.add_module(m).unwrap();
-                        });
-                    unsafe {
-                        if let Ok(top_func) = execution_engine.get_function(ANONYM_FUNCTION) {
-                            let result =
-                                (top_func as JitFunction<unsafe extern "C" fn() -> f64>).call();
-                            println!("\nEvaluated to: {result}\n");
-                            for module in &self.modules {
-                                execution_engine.remove_module(module).unwrap();
-                            }
-                        }
-                    }
+                if self.is_top_function(func_elem) {
+                    // Only the last anonymous function is kept in the module
+                    self.remove_anonymous_function()?;
                 }
-                Ok(func)
+                self.visit_function(func_elem)
             }
             TopAST::Prototype(proto_elem) => {
+                let func = self.visit_prototype(proto_elem)?;
                 self.prototypes
                     .insert(proto_elem.name.to_string(), proto_elem.clone());
-                self.visit_prototype(proto_elem)
+                self.annotate(|| format!("extern {proto_elem}"));
+                Ok(func)
+            }
+            TopAST::GlobalVar(global_elem) => {
+                let global = self.visit_global_var(global_elem)?;
+                self.annotate(|| format!("global {}", global_elem.name));
+                Ok(global)
             }
         }
     }
 
     fn visit_for_expr(&mut self, for_elem: &ForExprAST) -> CodeGenResult<'ctx> {
+        // Only a literal step can be checked here, a computed one that is
+        // zero still loops forever unless the body changes the variable
+        if let Some(step) = &for_elem.step {
+            let zero = match step.as_ref() {
+                ExprAST::NumberExpr(NumberExprAST { val }) => *val == 0.0,
+                ExprAST::IntExpr(IntExprAST { val }) => *val == 0,
+                _ => false,
+            };
+            ensure!(!zero, "for-loop step cannot be zero");
+        }
         // Get the current block of the enclosing function
         let pre_header_block = self
             .builder
@@ -452,52 +1467,48 @@ impl<'ctx> CodeGen<'ctx> {
             .ok_or(anyhow!("Block is not owned by a function"))?;
 
         let var_name = &for_elem.var_name;
-        let alloca = Self::create_entry_block_alloca(self.context, &enclosing_func, var_name)?;
-        let start_val = self.visit_expr(&for_elem.var_start)?;
-        self.builder
-            .build_store(alloca, start_val.into_float_value());
+        let alloca = Self::create_entry_block_alloca(
+            self.context,
+            &enclosing_func,
+            var_name,
+            self.float_type().into(),
+        )?;
+        let start_val = self.visit_float_expr(&for_elem.var_start)?;
+        self.builder.build_store(alloca, start_val);
 
+        let cond_block = self.context.append_basic_block(enclosing_func, "loopcond");
         let loop_block = self.context.append_basic_block(enclosing_func, "loop");
-        // We jump from the entry block to the loop block
+        let after_block = self.context.append_basic_block(enclosing_func, "afterloop");
+        // We jump from the entry block to the condition block
         // since we cannot directly have a phi instruction
-        self.builder.build_unconditional_branch(loop_block);
-        // Preparing the content of the loop block
-        self.builder.position_at_end(loop_block);
+        self.builder.build_unconditional_branch(cond_block);
+        self.builder.position_at_end(cond_block);
 
         // We update the variable table so that the variable can be used inside the loop block by other instruction
         // Previous variable with a same name is shadowed, but we save its state to restore it at the end.
         let old_var_val = self.named_values_ctx.insert(var_name.into(), alloca);
+        // The condition is evaluated before each iteration, the body may
+        // never be executed
+        let end_comp_cmp = self.visit_condition(&for_elem.var_end, "loopcond")?;
+        self.build_iteration_guard()?;
+        self.builder
+            .build_conditional_branch(end_comp_cmp, loop_block, after_block);
+
         // Generating the body of the loop
+        self.builder.position_at_end(loop_block);
         self.visit_expr(&for_elem.body)?;
         // Time to increment the for variable ; if not step value, default to 1
         let step_val = match &for_elem.step {
-            Some(step) => self.visit_expr(step)?,
-            None => self.context.f64_type().const_float(1.0).into(),
+            Some(step) => self.visit_float_expr(step)?,
+            None => self.float_type().const_float(1.0),
         };
-
-        // Evaluating condition
-        let end_cond = self.visit_expr(&for_elem.var_end)?;
-        let end_comp_cmp = self.builder.build_float_compare(
-            FloatPredicate::ONE,
-            end_cond.into_float_value(),
-            self.context.f64_type().const_float(0.0),
-            "loopcond",
-        );
-
-        let cur_var = self
-            .builder
-            .build_load(self.context.f64_type(), alloca, var_name);
-        let next_var = self.builder.build_float_add(
-            cur_var.into_float_value(),
-            step_val.into_float_value(),
-            "nextvar",
-        );
+        let cur_var = self.builder.build_load(self.float_type(), alloca, var_name);
+        let next_var =
+            self.builder
+                .build_float_add(cur_var.into_float_value(), step_val, "nextvar");
         self.builder.build_store(alloca, next_var);
+        self.builder.build_unconditional_branch(cond_block);
 
-        // Preparing the block after the for block
-        let after_block = self.context.append_basic_block(enclosing_func, "afterloop");
-        self.builder
-            .build_conditional_branch(end_comp_cmp, loop_block, after_block);
         self.builder.position_at_end(after_block);
         // This is the end, restoring shadowed variable if one was existing
         if let Some(val) = old_var_val {
@@ -505,6 +1516,1512 @@ impl<'ctx> CodeGen<'ctx> {
         } else {
             self.named_values_ctx.remove(var_name);
         }
-        Ok(self.context.f64_type().const_zero().into())
+        Ok(self.float_type().const_zero().into())
+    }
+
+    /// If loop iterations are limited, count one more iteration, and return
+    /// from the function after calling the trap if there are too many. The
+    /// builder is then positioned where the loop continues.
+    fn build_iteration_guard(&mut self) -> Result<()> {
+        let Some(max) = self.max_loop_iterations else {
+            return Ok(());
+        };
+        let i64_type = self.context.i64_type();
+        let (counter, trap) = Self::declare_loop_limit(self.context, &self.module);
+        let counter = counter.as_pointer_value();
+        let count = self
+            .builder
+            .build_load(i64_type, counter, "loopcount")
+            .into_int_value();
+        let count = self
+            .builder
+            .build_int_add(count, i64_type.const_int(1, false), "loopcount");
+        self.builder.build_store(counter, count);
+        let exceeded = self.builder.build_int_compare(
+            IntPredicate::UGT,
+            count,
+            i64_type.const_int(max, false),
+            "looplimit",
+        );
+        self.build_early_return(exceeded, Some(trap))
+    }
+
+    /// If loop iterations are limited, return from the function when the
+    /// limit was exceeded by the function just called, so that the rest of
+    /// the caller does not run either, up to the function given to
+    /// run_function.
+    fn build_limit_check(&mut self) -> Result<()> {
+        let Some(max) = self.max_loop_iterations else {
+            return Ok(());
+        };
+        let i64_type = self.context.i64_type();
+        let (counter, _) = Self::declare_loop_limit(self.context, &self.module);
+        let count = self
+            .builder
+            .build_load(i64_type, counter.as_pointer_value(), "loopcount")
+            .into_int_value();
+        let exceeded = self.builder.build_int_compare(
+            IntPredicate::UGT,
+            count,
+            i64_type.const_int(max, false),
+            "looplimit",
+        );
+        self.build_early_return(exceeded, None)
+    }
+
+    /// Return 0 from the current function if exceeded is true, after calling
+    /// trap if any. The builder is then positioned where the function goes
+    /// on otherwise.
+    fn build_early_return(
+        &mut self,
+        exceeded: IntValue<'ctx>,
+        trap: Option<FunctionValue<'ctx>>,
+    ) -> Result<()> {
+        let func = self
+            .current_block()?
+            .get_parent()
+            .ok_or(anyhow!("Block is not owned by a function"))?;
+        let limit_block = self.context.append_basic_block(func, "looplimit");
+        let continue_block = self.context.append_basic_block(func, "loopcheck");
+        self.builder
+            .build_conditional_branch(exceeded, limit_block, continue_block);
+        self.builder.position_at_end(limit_block);
+        if let Some(trap) = trap {
+            self.builder.build_call(trap, &[], "");
+        }
+        match func.get_type().get_return_type() {
+            Some(_) => self
+                .builder
+                .build_return(Some(&self.float_type().const_zero())),
+            None => self.builder.build_return(None),
+        };
+        self.builder.position_at_end(continue_block);
+        Ok(())
+    }
+
+    /// Declare in module, if not already done, the loop counter and the trap
+    /// called when it exceeds the limit. Both are provided by the JIT, the
+    /// counter being reset before each call of run_function.
+    fn declare_loop_limit<'c>(
+        context: &'c Context,
+        module: &Module<'c>,
+    ) -> (GlobalValue<'c>, FunctionValue<'c>) {
+        let counter = module
+            .get_global(LOOP_COUNTER)
+            .unwrap_or_else(|| module.add_global(context.i64_type(), None, LOOP_COUNTER));
+        let trap = module.get_function(LOOP_LIMIT_TRAP).unwrap_or_else(|| {
+            let trap_type = context.void_type().fn_type(&[], false);
+            module.add_function(LOOP_LIMIT_TRAP, trap_type, None)
+        });
+        (counter, trap)
+    }
+
+    fn visit_while_expr(&mut self, while_elem: &WhileExprAST) -> CodeGenResult<'ctx> {
+        let enclosing_func = self
+            .builder
+            .get_insert_block()
+            .ok_or(anyhow!("Block not found"))?
+            .get_parent()
+            .ok_or(anyhow!("Block is not owned by a function"))?;
+        let cond_block = self.context.append_basic_block(enclosing_func, "whilecond");
+        let loop_block = self.context.append_basic_block(enclosing_func, "whileloop");
+        let after_block = self
+            .context
+            .append_basic_block(enclosing_func, "afterwhile");
+
+        self.builder.build_unconditional_branch(cond_block);
+        self.builder.position_at_end(cond_block);
+        let cond_val = self.visit_condition(&while_elem.condition, "whilecond")?;
+        self.build_iteration_guard()?;
+        self.builder
+            .build_conditional_branch(cond_val, loop_block, after_block);
+
+        self.builder.position_at_end(loop_block);
+        self.visit_expr(&while_elem.body)?;
+        self.builder.build_unconditional_branch(cond_block);
+
+        self.builder.position_at_end(after_block);
+        Ok(self.float_type().const_zero().into())
+    }
+}
+
+impl Drop for CodeGen<'_> {
+    fn drop(&mut self) {
+        // Panicking here would abort the process if already panicking
+        if let Some(path) = &self.ir_on_drop {
+            if let Err(err) = self.write_ir(path) {
+                eprintln!("{err}");
+            }
+        }
+    }
+}
+
+/// Call the compiled function at address, whose parameters and result are
+/// all of type T
+///
+/// # Safety
+/// The function must take exactly as many parameters as args, at most
+/// MAX_RUN_ARGS.
+unsafe fn call_jit<T: Copy>(address: usize, args: &[T]) -> T {
+    type F0<T> = unsafe extern "C" fn() -> T;
+    type F1<T> = unsafe extern "C" fn(T) -> T;
+    type F2<T> = unsafe extern "C" fn(T, T) -> T;
+    type F3<T> = unsafe extern "C" fn(T, T, T) -> T;
+    type F4<T> = unsafe extern "C" fn(T, T, T, T) -> T;
+    match *args {
+        [] => transmute::<usize, F0<T>>(address)(),
+        [a] => transmute::<usize, F1<T>>(address)(a),
+        [a, b] => transmute::<usize, F2<T>>(address)(a, b),
+        [a, b, c] => transmute::<usize, F3<T>>(address)(a, b, c),
+        [a, b, c, d] => transmute::<usize, F4<T>>(address)(a, b, c, d),
+        _ => unreachable!(),
+    }
+}
+
+/// Integer and floating point predicates of a built-in comparison. The float
+/// ones are unordered, NaN operands compare as true, except for `==`.
+fn comparison_predicates(op: &str) -> Option<(IntPredicate, FloatPredicate)> {
+    match op {
+        "<" => Some((IntPredicate::SLT, FloatPredicate::ULT)),
+        "<=" => Some((IntPredicate::SLE, FloatPredicate::ULE)),
+        ">=" => Some((IntPredicate::SGE, FloatPredicate::UGE)),
+        "==" => Some((IntPredicate::EQ, FloatPredicate::OEQ)),
+        "!=" => Some((IntPredicate::NE, FloatPredicate::UNE)),
+        _ => None,
+    }
+}
+
+/// True if expr applies the operator implemented by the function func_name
+fn uses_operator(expr: &ExprAST, func_name: &str) -> bool {
+    let applies = match expr {
+        ExprAST::BinaryExpr(bin) => PrototypeAST::gen_binary_func_name(&bin.op) == func_name,
+        ExprAST::UnaryExpr(unary) => PrototypeAST::gen_unary_func_name(unary.opcode) == func_name,
+        _ => false,
+    };
+    applies
+        || subexprs(expr)
+            .into_iter()
+            .any(|subexpr| uses_operator(subexpr, func_name))
+}
+
+/// Branch of an if expression that can be evaluated unconditionally, so
+/// that a select can replace the branches and the phi
+fn is_pure_operand(expr: &ExprAST) -> bool {
+    matches!(
+        expr,
+        ExprAST::NumberExpr(_) | ExprAST::IntExpr(_) | ExprAST::VariableExpr(_)
+    )
+}
+
+/// Levenshtein distance: number of characters to insert, delete or replace
+/// to turn a into b
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, &cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transform::desugar;
+    use inkwell::{execution_engine::JitFunction, memory_buffer::MemoryBuffer};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        Mutex,
+    };
+    use std::{cell::Cell, rc::Rc};
+
+    /// Compile the input, then JIT-execute the function without parameters
+    /// named func_name.
+    fn jit_call(input: &str, func_name: &str) -> f64 {
+        jit_call_ast(&GlobalParser::default().parse(input).unwrap(), func_name)
+    }
+
+    fn jit_call_ast(ast: &KaleoGrammar, func_name: &str) -> f64 {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        for ast_part in ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        codegen.run_function(func_name, &[]).unwrap()
+    }
+
+    #[test]
+    fn failed_forward_definition() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("def main() helper(); def helper() nothere();")
+            .unwrap();
+        codegen.declare_prototypes(&ast);
+        codegen.visit_top(&ast.0[0]).unwrap();
+        let err = codegen.visit_top(&ast.0[1]).unwrap_err();
+        assert_eq!(err.to_string(), "nothere not found in prototype lists");
+        codegen.verify_all().unwrap();
+        let helper = codegen.module.get_function("helper").unwrap();
+        assert_eq!(helper.count_basic_blocks(), 0);
+        assert!(codegen.module.get_function("main").is_some());
+    }
+
+    #[test]
+    fn compile_grammar() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("def twice(x) add(x, x); def add(a b) a + b;")
+            .unwrap();
+        let values = codegen.compile_grammar(&ast).unwrap();
+        let names: Vec<_> = values
+            .iter()
+            .map(|value| value.into_function_value().get_name().to_str().unwrap())
+            .collect();
+        assert_eq!(names, ["twice", "add"]);
+        assert_eq!(codegen.run_function("twice", &[4.0]).unwrap(), 8.0);
+
+        let ast = GlobalParser::default().parse("def h() missing();").unwrap();
+        let err = codegen.compile_grammar(&ast).unwrap_err();
+        assert_eq!(err.to_string(), "missing not found in prototype lists");
+    }
+
+    #[test]
+    fn compile_grammar_with() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse(
+                "def sq(x) x * x; sq(2); def h() missing();
+                 def r() two(); def two() 2; sq(3) + r();",
+            )
+            .unwrap();
+        let mut results = vec![];
+        let mut errors = vec![];
+        codegen
+            .compile_grammar_with(&ast, |codegen, top_elem, value| {
+                let is_top = matches!(top_elem, TopAST::Function(func) if func.is_top_function());
+                match value {
+                    Ok(_) if is_top => results.push(codegen.run_function(ANONYM_FUNCTION, &[])?),
+                    Ok(_) => {}
+                    Err(err) => errors.push(err.to_string()),
+                }
+                Ok(())
+            })
+            .unwrap();
+        // The anonymous functions are run before being replaced, and two is
+        // declared before its definition
+        assert_eq!(results, [4.0, 11.0]);
+        assert_eq!(errors, ["missing not found in prototype lists"]);
+        // An error of on_item stops the generation
+        let ast = GlobalParser::default()
+            .parse("def a() 1; def b() 2;")
+            .unwrap();
+        let mut visited = 0;
+        let err = codegen
+            .compile_grammar_with(&ast, |_, _, _| {
+                visited += 1;
+                bail!("stop")
+            })
+            .unwrap_err();
+        assert_eq!(err.to_string(), "stop");
+        assert_eq!(visited, 1);
+    }
+
+    #[test]
+    fn forward_reference() {
+        let ast = GlobalParser::default()
+            .parse(
+                "def even(n) if n < 1 then 1 else odd(n - 1);
+                 def odd(n) if n < 1 then 0 else even(n - 1);
+                 def main() even(10) + odd(7) * 10;",
+            )
+            .unwrap();
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let err = codegen.visit_top(&ast.0[0]).unwrap_err();
+        assert_eq!(err.to_string(), "odd not found in prototype lists");
+
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        codegen.declare_prototypes(&ast);
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        assert_eq!(codegen.run_function("main", &[]).unwrap(), 11.0);
+    }
+
+    static SIDE_EFFECT_CALLS: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn side_effect() -> f64 {
+        SIDE_EFFECT_CALLS.fetch_add(1, AtomicOrdering::SeqCst);
+        1.0
+    }
+
+    /// Same as jit_call, but also return how many times the extern function
+    /// side_effect() was called. Optimizations are disabled.
+    fn jit_call_counting(input: &str, func_name: &str) -> (f64, usize) {
+        static LOCK: Mutex<()> = Mutex::new(());
+        let _guard = LOCK.lock().unwrap();
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, false, false, PassConfig::default());
+        let ast = GlobalParser::default().parse(input).unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        let execution_engine = codegen
+            .module
+            .create_jit_execution_engine(OptimizationLevel::None)
+            .unwrap();
+        execution_engine.add_global_mapping(
+            &codegen.module.get_function("side_effect").unwrap(),
+            side_effect as usize,
+        );
+        SIDE_EFFECT_CALLS.store(0, AtomicOrdering::SeqCst);
+        let result = unsafe {
+            let func: JitFunction<unsafe extern "C" fn() -> f64> =
+                execution_engine.get_function(func_name).unwrap();
+            func.call()
+        };
+        execution_engine.remove_module(&codegen.module).unwrap();
+        (result, SIDE_EFFECT_CALLS.load(AtomicOrdering::SeqCst))
+    }
+
+    #[test]
+    fn propagate_constants_before_codegen() {
+        let input = "def f(x) var k = 2, n = 3 in x * k + n;";
+        for (propagate, allocas) in [(false, 3), (true, 1)] {
+            let context = Context::create();
+            let mut codegen = CodeGen::builder()
+                .optimize(false)
+                .propagate_constants(propagate)
+                .build(&context);
+            let ast = GlobalParser::default().parse(input).unwrap();
+            let ir = codegen
+                .visit_top(&ast.0[0])
+                .unwrap()
+                .print_to_string()
+                .to_string();
+            assert_eq!(ir.matches("alloca").count(), allocas, "{ir}");
+            assert_eq!(ir.contains("%k"), !propagate, "{ir}");
+            assert_eq!(codegen.run_function("f", &[5.0]).unwrap(), 13.0);
+        }
+    }
+
+    #[test]
+    fn keep_unoptimized_ir() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, true, PassConfig::default());
+        let ast = GlobalParser::default().parse("def f(x) x*1;").unwrap();
+        let func = codegen.visit_top(&ast.0[0]).unwrap();
+        let unoptimized = codegen.take_unoptimized_ir().unwrap();
+        let optimized = func.print_to_string().to_string();
+        assert!(unoptimized.contains("fmul"));
+        assert!(!optimized.contains("fmul"));
+    }
+
+    #[test]
+    fn annotate_ir() {
+        let context = Context::create();
+        let mut codegen = CodeGen::builder().annotate_ir(true).build(&context);
+        let ast = GlobalParser::default()
+            .parse("extern sin(x); global g; def binary| 5 (a b) a + b; def fib(x) x; fib(1);")
+            .unwrap();
+        let mut annotations = vec![];
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+            annotations.push(codegen.take_annotation().unwrap());
+        }
+        assert_eq!(
+            annotations,
+            [
+                "; --- extern sin(x) ---",
+                "; --- global g ---",
+                "; --- def binary| 5 (a b) ---",
+                "; --- def fib(x) ---",
+                "; --- top-level expression ---",
+            ]
+        );
+        assert_eq!(codegen.take_annotation(), None);
+        // Nothing is produced unless requested
+        let mut codegen = CodeGen::builder().build(&context);
+        codegen.visit_top(&ast.0[3]).unwrap();
+        assert_eq!(codegen.take_annotation(), None);
+    }
+
+    #[test]
+    fn pass_config() {
+        let ir_of = |pass_config: PassConfig| {
+            let context = Context::create();
+            let mut codegen = CodeGen::new(&context, true, false, pass_config);
+            let ast = GlobalParser::default().parse("def f(x) x*1;").unwrap();
+            let func = codegen.visit_top(&ast.0[0]).unwrap();
+            func.print_to_string().to_string()
+        };
+        assert!(!ir_of(PassConfig::default()).contains("fmul"));
+        let without_simplification = PassConfig::default()
+            .without(Pass::InstructionCombining)
+            .without(Pass::Gvn);
+        assert_eq!(
+            without_simplification.passes,
+            vec![
+                Pass::PromoteMemoryToRegister,
+                Pass::Reassociate,
+                Pass::CfgSimplification,
+                Pass::PromoteMemoryToRegister,
+            ]
+        );
+        assert!(ir_of(without_simplification).contains("fmul"));
+    }
+
+    #[test]
+    fn builder() {
+        let context = Context::create();
+        let mut codegen = CodeGen::builder()
+            .optimize(false)
+            .pass_config(PassConfig::default().without(Pass::InstructionCombining))
+            .anonymous_name("__top")
+            .build(&context);
+        assert!(!codegen.with_optim);
+        assert_eq!(codegen.anonymous_name(), "__top");
+        let ast = GlobalParser::default().parse("def f(x) x*1;").unwrap();
+        let ir = codegen
+            .visit_top(&ast.0[0])
+            .unwrap()
+            .print_to_string()
+            .to_string();
+        assert!(ir.contains("alloca"), "{ir}");
+        assert!(ir.contains("fmul"), "{ir}");
+        assert_eq!(codegen.run_function("f", &[3.0]).unwrap(), 3.0);
+    }
+
+    #[test]
+    fn integer_arithmetic() {
+        assert_eq!(jit_call("def f() 2 + 3 * 4 - 1;", "f"), 13.0);
+        assert_eq!(jit_call("def f() 7 * 0.5;", "f"), 3.5);
+    }
+
+    #[test]
+    fn integer_overflow() {
+        let big = 3_037_000_500_f64;
+        assert_eq!(jit_call("def f() 3037000500 * 3037000500;", "f"), big * big);
+        assert_eq!(
+            jit_call("def f() 9223372036854775807 + 1;", "f"),
+            i64::MAX as f64 + 1.0
+        );
+        assert_eq!(
+            jit_call("def f() 0 - 9223372036854775807 - 2;", "f"),
+            i64::MIN as f64 - 1.0
+        );
+        assert_eq!(
+            jit_call("def f() -(0 - 9223372036854775807 - 1);", "f"),
+            -(i64::MIN as f64)
+        );
+    }
+
+    #[test]
+    fn integer_modulo_by_zero() {
+        assert_eq!(jit_call("def f() 7 % 3;", "f"), 1.0);
+        assert!(jit_call("def f() 7 % 0;", "f").is_nan());
+        assert!(jit_call("def f(x y) x % y; def g() f(7, 0);", "g").is_nan());
+        assert_eq!(
+            jit_call("def f() (0 - 9223372036854775807 - 1) % -1;", "f"),
+            0.0
+        );
+    }
+
+    #[test]
+    fn integer_comparison() {
+        assert_eq!(jit_call("def f() 2 < 3;", "f"), 1.0);
+        assert_eq!(jit_call("def f() 3 < 2;", "f"), 0.0);
+        assert_eq!(jit_call("def f() 0 - 1 < 0;", "f"), 1.0);
+    }
+
+    #[test]
+    fn builtin_comparisons() {
+        assert_eq!(jit_call("def f() 3 <= 3;", "f"), 1.0);
+        assert_eq!(jit_call("def f(x) x<=3; def g() f(4);", "g"), 0.0);
+        assert_eq!(jit_call("def f(x) x >= 3; def g() f(3);", "g"), 1.0);
+        assert_eq!(jit_call("def f(x) x == 2.5; def g() f(2.5);", "g"), 1.0);
+        assert_eq!(jit_call("def f() 2 != 2;", "f"), 0.0);
+        assert_eq!(
+            jit_call("def f(x) if x != 0 then 1 else 2; def g() f(0);", "g"),
+            2.0
+        );
+        // Unordered, except for `==`
+        assert_eq!(jit_call("def f(x) x <= 0; def g() f(0/0);", "g"), 1.0);
+        assert_eq!(jit_call("def f(x) x == x; def g() f(0/0);", "g"), 0.0);
+    }
+
+    #[test]
+    fn division_is_always_on_floats() {
+        assert_eq!(jit_call("def f() 7 / 2;", "f"), 3.5);
+        assert_eq!(jit_call("def f(x) x / 4; def g() f(1);", "g"), 0.25);
+        assert!(jit_call("def f() 0 / 0;", "f").is_nan());
+    }
+
+    #[test]
+    fn modulo() {
+        assert_eq!(jit_call("def m() 10 % 3;", "m"), 1.0);
+        assert_eq!(jit_call("def m() 7 % 3;", "m"), 1.0);
+        assert_eq!(jit_call("def m() 7.5 % 2;", "m"), 1.5);
+        assert_eq!(jit_call("def m(x) x % 4; def f() m(10);", "f"), 2.0);
+    }
+
+    #[test]
+    fn functions_resolved_across_visit_top_calls() {
+        let input = r#"
+        def a(x) x + 1;
+        def b(x) a(x) * 2;
+        def c() b(a(1));
+        "#;
+        assert_eq!(jit_call(input, "c"), 6.0);
+    }
+
+    #[test]
+    fn single_module_keeps_all_functions() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let mut parser = GlobalParser::default();
+        for input in [
+            "extern printd(x);",
+            "def a(x) x;",
+            "def b() a(2);",
+            "extern printd(x);",
+        ] {
+            let ast = parser.parse(input).unwrap();
+            codegen.visit_top(&ast.0[0]).unwrap();
+        }
+        let names: Vec<_> = codegen
+            .module
+            .get_functions()
+            .map(|func| func.get_name().to_str().unwrap().to_string())
+            .collect();
+        assert_eq!(names, vec!["printd", "a", "b"]);
+    }
+
+    #[test]
+    fn variadic_extern() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("extern printf(fmt ...); def f() printf(1, 2, 3); extern sin(x);")
+            .unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        let printf = codegen.module.get_function("printf").unwrap();
+        assert!(printf.get_type().is_var_arg());
+        assert_eq!(printf.count_params(), 1);
+        assert!(!codegen
+            .module
+            .get_function("sin")
+            .unwrap()
+            .get_type()
+            .is_var_arg());
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("declare double @printf(double, ...)"), "{ir}");
+
+        let ast = GlobalParser::default()
+            .parse("def printf(fmt) fmt;")
+            .unwrap();
+        let err = codegen.visit_top(&ast.0[0]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot define the variadic extern 'printf'"
+        );
+    }
+
+    #[test]
+    fn external_dependencies() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("extern sin(x); extern cos(x); def f(x) sin(x) * atan(x); extern atan(x);")
+            .unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        assert_eq!(codegen.external_dependencies(), ["atan", "cos", "sin"]);
+
+        let mut codegen = CodeGen::builder().max_loop_iterations(10).build(&context);
+        let ast = GlobalParser::default()
+            .parse("extern sin(x); def f(x) for i = 0, i < x in sin(i);")
+            .unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        assert!(codegen.module.get_function(LOOP_LIMIT_TRAP).is_some());
+        assert_eq!(codegen.external_dependencies(), ["sin"]);
+    }
+
+    #[test]
+    fn redefinition_of_function() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("def foo(x) x; def foo(x) x;")
+            .unwrap();
+        assert!(codegen.visit_top(&ast.0[0]).is_ok());
+        let err = codegen.visit_top(&ast.0[1]).unwrap_err();
+        assert_eq!(err.to_string(), "redefinition of function 'foo'");
+    }
+
+    #[test]
+    fn redeclaration_of_extern() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse(
+                "extern foo(x); extern foo(x); extern foo(x y); extern foo(x): void;
+                 extern foo(x ...);",
+            )
+            .unwrap();
+        codegen.visit_top(&ast.0[0]).unwrap();
+        codegen.visit_top(&ast.0[1]).unwrap();
+        let errors: Vec<_> = ast.0[2..]
+            .iter()
+            .map(|item| codegen.visit_top(item).unwrap_err().to_string())
+            .collect();
+        assert_eq!(
+            errors,
+            [
+                "redeclaration of function 'foo' with a different number of arguments",
+                "redeclaration of function 'foo' with a different return type",
+                "redeclaration of function 'foo' with a different '...'",
+            ]
+        );
+        assert_eq!(codegen.functions(), [(String::from("foo"), 1)]);
+    }
+
+    #[test]
+    fn definition_after_extern() {
+        let input = "extern foo(x); def bar(x) foo(x); def foo(x) x * 2; def baz() bar(3);";
+        assert_eq!(jit_call(input, "baz"), 6.0);
+    }
+
+    #[test]
+    fn definition_after_failed_definition() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("def foo(x) unknown; def foo(x) x;")
+            .unwrap();
+        assert!(codegen.visit_top(&ast.0[0]).is_err());
+        assert!(codegen.visit_top(&ast.0[1]).is_ok());
+    }
+
+    #[test]
+    fn failed_definition_of_called_function() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("extern foo(x); def bar() foo(1); def foo(x) nothere(x);")
+            .unwrap();
+        codegen.visit_top(&ast.0[0]).unwrap();
+        codegen.visit_top(&ast.0[1]).unwrap();
+        assert!(codegen.visit_top(&ast.0[2]).is_err());
+        codegen.verify_all().unwrap();
+        let foo = codegen.module.get_function("foo").unwrap();
+        assert_eq!(foo.count_basic_blocks(), 0);
+        assert_eq!(foo.count_params(), 1);
+        let ir = codegen.module.print_to_string().to_string();
+        assert!(ir.contains("call double @foo(double 1.000000e+00)"), "{ir}");
+        // The declaration can still receive a body
+        let ast = GlobalParser::default().parse("def foo(x) x * 2;").unwrap();
+        codegen.visit_top(&ast.0[0]).unwrap();
+        assert_eq!(codegen.run_function("bar", &[]).unwrap(), 2.0);
+    }
+
+    #[test]
+    fn write_bitcode() {
+        for with_optim in [true, false] {
+            let context = Context::create();
+            let mut codegen = CodeGen::new(&context, with_optim, false, PassConfig::default());
+            let ast = GlobalParser::default().parse("def f(x) x + 1;").unwrap();
+            codegen.visit_top(&ast.0[0]).unwrap();
+            let path = std::env::temp_dir().join(format!(
+                "kaleido-bitcode-{}-{with_optim}.bc",
+                std::process::id()
+            ));
+            codegen.write_bitcode(&path).unwrap();
+            let content = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert!(content.starts_with(b"BC\xC0\xDE"));
+        }
+    }
+
+    #[test]
+    fn write_ir() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("def f(x) x + 1; def g(x) f(x) * 2;")
+            .unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        let path = std::env::temp_dir().join(format!("kaleido-ir-{}.ll", std::process::id()));
+        codegen.write_ir(&path).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        let buffer = MemoryBuffer::create_from_file(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(content.contains("define double @f(double"));
+        assert!(content.contains("define double @g(double"));
+        let module = context.create_module_from_ir(buffer).unwrap();
+        assert!(module.get_function("g").is_some());
+    }
+
+    #[test]
+    fn ir_on_drop() {
+        let path = std::env::temp_dir().join(format!("kaleido-drop-{}.ll", std::process::id()));
+        let context = Context::create();
+        let mut codegen = CodeGen::builder().ir_on_drop(&path).build(&context);
+        let ast = GlobalParser::default()
+            .parse("def f(x) x + 1; f(2);")
+            .unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        assert_eq!(codegen.run_function(ANONYM_FUNCTION, &[]).unwrap(), 3.0);
+        assert!(!path.exists());
+        drop(codegen);
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(content.contains("define double @f(double"), "{content}");
+        assert!(
+            content.contains(&format!("@{ANONYM_FUNCTION}()")),
+            "{content}"
+        );
+
+        // Once flushed, the IR is not written again
+        let mut codegen = CodeGen::builder().ir_on_drop(&path).build(&context);
+        codegen.flush_ir().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        drop(codegen);
+        assert!(!path.exists());
+
+        // An unwritable path is only reported
+        let codegen = CodeGen::builder()
+            .ir_on_drop(Path::new("/nonexistent-dir/ir.ll"))
+            .build(&context);
+        drop(codegen);
+    }
+
+    #[test]
+    fn builtin_unary_operators() {
+        assert_eq!(jit_call("def f() -5;", "f"), -5.0);
+        assert_eq!(jit_call("def f() -2.5;", "f"), -2.5);
+        assert_eq!(jit_call("def f(x) 3 - -x; def g() f(2);", "g"), 5.0);
+        assert_eq!(jit_call("def f() !0;", "f"), 1.0);
+        assert_eq!(jit_call("def f() !4.2;", "f"), 0.0);
+    }
+
+    #[test]
+    fn user_defined_unary_operator() {
+        assert_eq!(jit_call("def unary~(v) v * 10; def f() ~2;", "f"), 20.0);
+    }
+
+    #[test]
+    fn user_defined_or_operator() {
+        let definition = "def binary| 5 (LHS RHS) if LHS then 1 else if RHS then 1 else 0;";
+        let cases = [
+            ("1 | 0", 1.0),
+            ("0 | 0", 0.0),
+            ("0 | 2", 1.0),
+            // '<' binds tighter than '|', '=' looser
+            ("0 | 2 < 1", 0.0),
+            ("var x in (x = 0 | 3) : x", 1.0),
+        ];
+        for (expr, expected) in cases {
+            let context = Context::create();
+            let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+            let ast = GlobalParser::default()
+                .parse(&format!("{definition} {expr};"))
+                .unwrap();
+            for ast_part in &ast {
+                codegen.visit_top(ast_part).unwrap();
+            }
+            assert!(codegen.module.get_function("binary|").is_some());
+            let result = codegen.run_function(ANONYM_FUNCTION, &[]).unwrap();
+            assert_eq!(result, expected, "{expr}");
+        }
+    }
+
+    #[test]
+    fn user_defined_multi_char_operator() {
+        let input = "def binary== 9 (a b) !(a < b || b < a); def f() 2 == 2; def g() 1 == 2;";
+        assert_eq!(jit_call(input, "f"), 1.0);
+        assert_eq!(jit_call(input, "g"), 0.0);
+    }
+
+    #[test]
+    fn unicode_names() {
+        let input =
+            "def binary± 10 (a b) a - b; def unary√(v) v * v; def λ(x) x ± 1; def f() λ(√3);";
+        assert_eq!(jit_call(input, "f"), 8.0);
+    }
+
+    #[test]
+    fn evaluate_top_level_expression() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("def fib(x) if x < 3 then 1 else fib(x - 1) + fib(x - 2); fib(10);")
+            .unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        assert_eq!(codegen.run_function(ANONYM_FUNCTION, &[]).unwrap(), 55.0);
+    }
+
+    #[test]
+    fn function_named_like_default_anonymous_name() {
+        let context = Context::create();
+        let mut codegen = CodeGen::builder().anonymous_name("__top").build(&context);
+        let mut parser = GlobalParser::default();
+        parser.set_anonymous_name("__top");
+        // The lexer does not read identifiers starting with '_', so the
+        // colliding definition is renamed after parsing
+        let mut ast = parser.parse("def f() 42; 1; 2;").unwrap();
+        if let TopAST::Function(func) = &mut ast.0[0] {
+            func.proto.name = ANONYM_FUNCTION.to_string();
+        }
+        for ast_part in &ast {
+            if let TopAST::Function(func) = ast_part {
+                assert_eq!(codegen.is_top_function(func), func.proto.name == "__top");
+            }
+            codegen.visit_top(ast_part).unwrap();
+        }
+        assert_eq!(codegen.run_function(ANONYM_FUNCTION, &[]).unwrap(), 42.0);
+        assert_eq!(codegen.run_function("__top", &[]).unwrap(), 2.0);
+        assert_eq!(codegen.functions(), [(ANONYM_FUNCTION.to_string(), 0)]);
+    }
+
+    #[test]
+    fn simple_if_uses_select() {
+        let ir_of = |input: &str| {
+            let context = Context::create();
+            let mut codegen = CodeGen::new(&context, false, false, PassConfig::default());
+            let ast = GlobalParser::default().parse(input).unwrap();
+            let func = codegen.visit_top(&ast.0[0]).unwrap();
+            func.print_to_string().to_string()
+        };
+        let ir = ir_of("def f(c) if c then 1 else 2;");
+        assert!(
+            ir.contains("select i1 %ifcond, double 1.000000e+00, double 2.000000e+00"),
+            "{ir}"
+        );
+        assert!(!ir.contains("phi"), "{ir}");
+        let ir = ir_of("def f(c x) if c < x then x else c;");
+        assert!(ir.contains("select i1 %cmptmp"), "{ir}");
+        // Calls must only be made in the branch taken
+        let ir = ir_of("extern g(); def f(c) if c then g() else 2;");
+        assert!(!ir.contains("select"), "{ir}");
+        assert!(ir.contains("phi double"), "{ir}");
+        assert_eq!(jit_call("def f() if 0 then 1 else 2;", "f"), 2.0);
+        assert_eq!(
+            jit_call("def f(x) if x < 3 then x else 3; def g() f(1) + f(5);", "g"),
+            4.0
+        );
+    }
+
+    #[test]
+    fn else_if_chain() {
+        let input =
+            "def f(x) if x < 1 then 10 else if x < 2 then 20 else if x < 3 then 30 else 40;";
+        let cases = [(0.0, 10.0), (1.5, 20.0), (2.0, 30.0), (7.0, 40.0)];
+        for (x, expected) in cases {
+            let call = format!("{input} def g() f({x});");
+            assert_eq!(jit_call(&call, "g"), expected, "f({x})");
+        }
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, false, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("extern g(x); def f(x) if x < 1 then g(1) else if x < 2 then g(2) else g(3);")
+            .unwrap();
+        codegen.visit_top(&ast.0[0]).unwrap();
+        let ir = codegen
+            .visit_top(&ast.0[1])
+            .unwrap()
+            .print_to_string()
+            .to_string();
+        assert_eq!(ir.matches("phi double").count(), 1, "{ir}");
+        assert_eq!(ir.matches("ifcont:").count(), 1, "{ir}");
+        assert_eq!(ir.matches("br label %ifcont").count(), 3, "{ir}");
+    }
+
+    #[test]
+    fn comparison_used_directly_as_condition() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, false, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("def f(x y) if x < y then x + 1 else 2;")
+            .unwrap();
+        let ir = codegen
+            .visit_top(&ast.0[0])
+            .unwrap()
+            .print_to_string()
+            .to_string();
+        assert_eq!(ir.matches("fcmp ult double").count(), 1, "{ir}");
+        assert!(!ir.contains("uitofp"), "{ir}");
+        assert!(!ir.contains("fcmp one"), "{ir}");
+        assert!(
+            ir.contains("br i1 %cmptmp, label %then, label %else"),
+            "{ir}"
+        );
+        // Comparisons used as values are still converted to 0.0 or 1.0
+        assert_eq!(jit_call("def f() if 1 < 2 then 3 < 4 else 0;", "f"), 1.0);
+        assert_eq!(
+            jit_call("def f() if 2 < 1 then 1 else 2.5 < 1.5;", "f"),
+            0.0
+        );
+    }
+
+    #[test]
+    fn chained_assignment() {
+        let input = "def f() var a = 0 in var b = 0 in (a = b = 7) : a * 100 + b;";
+        assert_eq!(jit_call(input, "f"), 707.0);
+        // The value of the assignment is the assigned one
+        let input = "def g() var a = 0, b = 0 in (a = b = 7) + 1;";
+        assert_eq!(jit_call(input, "g"), 8.0);
+    }
+
+    #[test]
+    fn global_variables() {
+        let input = r#"
+        global counter = 40 + 2;
+        def incr() counter = counter + 1;
+        def f() var a = incr(), b = incr() in counter;
+        def g(counter) counter;
+        def h() g(1) + counter;
+        "#;
+        assert_eq!(jit_call(input, "f"), 44.0);
+        assert_eq!(jit_call(input, "h"), 43.0);
+    }
+
+    #[test]
+    fn resolve_externs() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("extern sin(x); extern kaleido_nowhere(x); def f(x) sin(x); f(1);")
+            .unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        let externs = codegen.resolve_externs().unwrap();
+        let names: Vec<_> = externs.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names, ["kaleido_nowhere", "sin"]);
+        assert_eq!(externs[0].1, None);
+        assert!(externs[1].1.is_some_and(|address| address != 0));
+        assert_eq!(codegen.run_function("f", &[0.0]).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn llvm_version_matches_feature() {
+        let major = LLVM_VERSION.split('.').next().unwrap();
+        assert!(
+            cfg!(feature = "llvm15-0") && major == "15",
+            "{LLVM_VERSION}"
+        );
+    }
+
+    #[test]
+    fn float_width() {
+        let context = Context::create();
+        let mut codegen = CodeGen::builder()
+            .optimize(false)
+            .float_width(FloatWidth::F32)
+            .build(&context);
+        assert_eq!(codegen.float_width(), FloatWidth::F32);
+        let ast = GlobalParser::default()
+            .parse(
+                "global g = 0.5;
+                def f(x) x * 2.5 + g;
+                def sum(n) var s = 0 in (for i = 0, i < n in s = s + i) : s;
+                def pick(a b) if a < b && !(a < 0) then a else b;
+                sum(4) + 3;",
+            )
+            .unwrap();
+        let mut ir = String::new();
+        for ast_part in &ast {
+            let value = codegen.visit_top(ast_part).unwrap();
+            ir.push_str(&value.print_to_string().to_string());
+        }
+        assert!(ir.contains("define float @f(float %x)"), "{ir}");
+        assert!(ir.contains("alloca float"), "{ir}");
+        assert!(!ir.contains("double"), "{ir}");
+        assert_eq!(codegen.run_function("f", &[2.0]).unwrap(), 5.5);
+        assert_eq!(codegen.run_function("sum", &[4.0]).unwrap(), 6.0);
+        assert_eq!(codegen.run_function("pick", &[1.0, 2.0]).unwrap(), 1.0);
+        assert_eq!(codegen.run_function("pick", &[-1.0, 2.0]).unwrap(), 2.0);
+        assert_eq!(codegen.run_function(ANONYM_FUNCTION, &[]).unwrap(), 9.0);
+        // Precision is the one of f32
+        let ast = GlobalParser::default()
+            .parse("def third() 1.0 / 3;")
+            .unwrap();
+        codegen.visit_top(&ast.0[0]).unwrap();
+        assert_eq!(
+            codegen.run_function("third", &[]).unwrap(),
+            f64::from(1.0_f32 / 3.0)
+        );
+    }
+
+    #[test]
+    fn jit_cache() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let parser = &mut GlobalParser::default();
+        let ast = parser
+            .parse("global n; def incr(x) n = n + x; def sq(x) x * x;")
+            .unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        assert_eq!(codegen.run_function("sq", &[3.0]).unwrap(), 9.0);
+        assert_eq!(codegen.run_function("sq", &[4.0]).unwrap(), 16.0);
+        assert_eq!(codegen.jit_lookups(), 1);
+        assert_eq!(codegen.run_function("incr", &[2.0]).unwrap(), 2.0);
+        assert_eq!(codegen.run_function("incr", &[2.0]).unwrap(), 4.0);
+        assert_eq!(codegen.jit_lookups(), 2);
+        // A new definition is compiled on its own, the compiled functions
+        // and the globals are kept
+        let ast = parser.parse("def cube(x) x * sq(x) + incr(0);").unwrap();
+        codegen.visit_top(&ast.0[0]).unwrap();
+        assert_eq!(codegen.run_function("sq", &[5.0]).unwrap(), 25.0);
+        assert_eq!(codegen.run_function("cube", &[2.0]).unwrap(), 12.0);
+        assert_eq!(codegen.run_function("incr", &[1.0]).unwrap(), 5.0);
+        assert_eq!(codegen.jit_lookups(), 3);
+    }
+
+    #[test]
+    fn jit_cache_kept_across_top_level_expressions() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let parser = &mut GlobalParser::default();
+        let ast = parser.parse("def f(x) x + 1;").unwrap();
+        codegen.visit_top(&ast.0[0]).unwrap();
+        assert_eq!(codegen.run_function("f", &[1.0]).unwrap(), 2.0);
+        assert_eq!(codegen.jit_lookups(), 1);
+        // Like the REPL: each line replaces the anonymous function, which is
+        // the only thing compiled and looked up again
+        for (line, expected) in [("f(2);", 3.0), ("f(3);", 4.0)] {
+            let ast = parser.parse(line).unwrap();
+            codegen.visit_top(&ast.0[0]).unwrap();
+            assert_eq!(
+                codegen.run_function(ANONYM_FUNCTION, &[]).unwrap(),
+                expected
+            );
+        }
+        assert_eq!(codegen.jit_lookups(), 3);
+        assert_eq!(codegen.run_function("f", &[4.0]).unwrap(), 5.0);
+        assert_eq!(codegen.jit_lookups(), 3);
+    }
+
+    #[test]
+    fn globals_kept_across_top_level_expressions() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("global c; def inc() c = c + 1; inc(); inc(); global d = 5; d + inc();")
+            .unwrap();
+        let mut results = vec![];
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+            if matches!(ast_part, TopAST::Function(func) if codegen.is_top_function(func)) {
+                results.push(codegen.run_function(ANONYM_FUNCTION, &[]).unwrap());
+            }
+        }
+        assert_eq!(results, [1.0, 2.0, 8.0]);
+    }
+
+    #[test]
+    fn global_variable_errors() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("global x; global x; def f(y) y; global y = f(1);")
+            .unwrap();
+        assert!(codegen.visit_top(&ast.0[0]).is_ok());
+        let err = codegen.visit_top(&ast.0[1]).unwrap_err();
+        assert_eq!(err.to_string(), "redefinition of global 'x'");
+        codegen.visit_top(&ast.0[2]).unwrap();
+        let err = codegen.visit_top(&ast.0[3]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Initializer of global 'y' must be a constant"
+        );
+        assert_eq!(codegen.global_names(), vec!["x"]);
+    }
+
+    #[test]
+    fn run_function_with_args() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("def f(a b c) a * 100 + b * 10 + c; def g(a b c d e) a;")
+            .unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        assert_eq!(codegen.run_function("f", &[1.0, 2.0, 3.0]).unwrap(), 123.0);
+        let err = codegen.run_function("f", &[1.0]).unwrap_err();
+        assert_eq!(err.to_string(), "function 'f' expects 3 args, got 1");
+        let err = codegen.run_function("h", &[]).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown function 'h'");
+        let err = codegen.run_function("g", &[0.0; 5]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "cannot run a function with more than 4 args"
+        );
+    }
+
+    #[test]
+    fn typed_entry_block_alloca() {
+        let context = Context::create();
+        let codegen = CodeGen::new(&context, false, false, PassConfig::default());
+        let fn_type = context.f64_type().fn_type(&[], false);
+        let func = codegen.module.add_function("f", fn_type, None);
+        context.append_basic_block(func, "entry");
+        CodeGen::create_entry_block_alloca(&context, &func, "i", context.i64_type().into())
+            .unwrap();
+        CodeGen::create_entry_block_alloca(&context, &func, "x", context.f64_type().into())
+            .unwrap();
+        let ir = func.print_to_string().to_string();
+        assert!(ir.contains("%i = alloca i64"), "{ir}");
+        assert!(ir.contains("%x = alloca double"), "{ir}");
+    }
+
+    #[test]
+    fn desugared_for_loop() {
+        let input =
+            "def f() var s = 0 in (for i = 1, i < 5, 2 in for j = 0, j < 2 in s = s + i*j) : s;";
+        let mut ast = GlobalParser::default().parse(input).unwrap();
+        assert_eq!(jit_call_ast(&ast, "f"), 4.0);
+        let TopAST::Function(function) = &mut ast.0[0] else {
+            panic!("expected a function");
+        };
+        function.body = desugar(function.body.clone());
+        assert_eq!(jit_call_ast(&ast, "f"), 4.0);
+    }
+
+    #[test]
+    fn user_operators_override_builtins() {
+        assert_eq!(
+            jit_call("def binary+ 20 (a b) a - b; def f() 5 + 3;", "f"),
+            2.0
+        );
+        assert_eq!(jit_call("def unary-(v) v * 2; def f() -(3);", "f"), 6.0);
+        assert_eq!(
+            jit_call(
+                "def binary = 9 (a b) !(a < b) * !(b < a); def f() 4 = 4;",
+                "f"
+            ),
+            1.0
+        );
+        assert_eq!(jit_call("def f() 5 + 3 : -(3);", "f"), -3.0);
+        // Also when the comparison is used as a condition
+        assert_eq!(
+            jit_call(
+                "def binary< 10 (a b) a - b; def f() if 3 < 1 then 1 else 2;",
+                "f"
+            ),
+            1.0
+        );
+    }
+
+    #[test]
+    fn builtin_operator_defined_with_itself() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let mut parser = GlobalParser::default();
+        for input in [
+            "def binary+ 20 (a b) a + b;",
+            "def binary< 10 (a b) if a < b then 1 else 0;",
+            "def unary-(v) 0 - -v;",
+        ] {
+            let ast = parser.parse(input).unwrap();
+            let err = codegen.visit_top(&ast.0[0]).unwrap_err();
+            let TopAST::Function(func) = &ast.0[0] else {
+                panic!("expected a function");
+            };
+            assert_eq!(
+                err.to_string(),
+                format!(
+                    "operator '{}' cannot be defined with itself",
+                    func.proto.name
+                )
+            );
+        }
+        // Operators which are not built in can be recursive
+        let ast = parser
+            .parse("def binary| 5 (a b) if a < 1 then b else (a - 1) | b;")
+            .unwrap();
+        assert!(codegen.visit_top(&ast.0[0]).is_ok());
+    }
+
+    #[test]
+    fn observer_sees_number_exprs() {
+        struct NumberCounter(Rc<Cell<usize>>);
+
+        impl CodeGenObserver for NumberCounter {
+            fn on_expr(&mut self, expr: &ExprAST, value: &AnyValueEnum) {
+                if let ExprAST::NumberExpr(_) = expr {
+                    assert!(value.is_float_value());
+                    self.0.set(self.0.get() + 1);
+                }
+            }
+        }
+
+        let count = Rc::new(Cell::new(0));
+        let context = Context::create();
+        let mut codegen = CodeGen::builder()
+            .observer(NumberCounter(count.clone()))
+            .build(&context);
+        let ast = GlobalParser::default()
+            .parse("def f(x) x * 2.5 + 1.5; def g() f(0.5) + 1;")
+            .unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        assert_eq!(count.get(), 3);
+    }
+
+    #[test]
+    fn zero_for_step() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("def f() for i = 0, i < 10, 0 in i; def g() for i = 0, i < 10, 0.0 in i;")
+            .unwrap();
+        for ast_part in &ast {
+            let err = codegen.visit_top(ast_part).unwrap_err();
+            assert_eq!(err.to_string(), "for-loop step cannot be zero");
+        }
+        assert_eq!(
+            jit_call("def f(s) for i = 0, i < 2, s in i; def g() f(1);", "g"),
+            0.0
+        );
+    }
+
+    #[test]
+    fn loop_iterations_limit() {
+        let context = Context::create();
+        let mut codegen = CodeGen::builder().max_loop_iterations(1000).build(&context);
+        let input = "def forever() for i = 0, 1 in i;
+            def finite() var s = 0 in (for i = 0, i < 10 in s = s + 1) : s;";
+        let ast = GlobalParser::default().parse(input).unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        let err = codegen.run_function("forever", &[]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "evaluation of 'forever' aborted after 1000 loop iterations"
+        );
+        assert_eq!(codegen.run_function("finite", &[]).unwrap(), 10.0);
+    }
+
+    #[test]
+    fn loop_limit_stops_the_callers() {
+        let context = Context::create();
+        let mut codegen = CodeGen::builder().max_loop_iterations(1000).build(&context);
+        let input = "global g;
+            def forever() while 1 do 0;
+            def direct() forever() : g = 1;
+            def indirect() (for i = 0, i < 3 in direct()) : g = 2;
+            def binary~ 5 (a b) forever();
+            def by_operator() (1 ~ 2) : g = 3;
+            def get() g;";
+        let ast = GlobalParser::default().parse(input).unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        for func_name in ["forever", "direct", "indirect", "by_operator"] {
+            let err = codegen.run_function(func_name, &[]).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("evaluation of '{func_name}' aborted after 1000 loop iterations")
+            );
+            assert_eq!(codegen.run_function("get", &[]).unwrap(), 0.0);
+        }
+    }
+
+    #[test]
+    fn functions_in_sequence() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("def f(a) a * 2; def g(b) f(b) + b; def h() a;")
+            .unwrap();
+        codegen.visit_top(&ast.0[0]).unwrap();
+        assert!(codegen.named_values_ctx.is_empty());
+        codegen.visit_top(&ast.0[1]).unwrap();
+        assert!(codegen.named_values_ctx.is_empty());
+        assert_eq!(codegen.run_function("g", &[3.0]).unwrap(), 9.0);
+        // Arguments of the previous functions are not visible
+        assert!(codegen.visit_top(&ast.0[2]).is_err());
+        assert!(codegen.named_values_ctx.is_empty());
+    }
+
+    #[test]
+    fn list_functions() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("extern sin(x); extern pow(x y); def f() 1; f();")
+            .unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        assert_eq!(
+            codegen.functions(),
+            [
+                ("f".to_string(), 0),
+                ("pow".to_string(), 2),
+                ("sin".to_string(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_function_suggestion() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("extern printd(x); extern putchard(c); pintd(1); zzz(1);")
+            .unwrap();
+        codegen.visit_top(&ast.0[0]).unwrap();
+        codegen.visit_top(&ast.0[1]).unwrap();
+        let err = codegen.visit_top(&ast.0[2]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "pintd not found in prototype lists, did you mean 'printd'?"
+        );
+        let err = codegen.visit_top(&ast.0[3]).unwrap_err();
+        assert_eq!(err.to_string(), "zzz not found in prototype lists");
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("pintd", "printd"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("same", "same"), 0);
+    }
+
+    #[test]
+    fn libm_functions() {
+        let input = format!("{LIBM_PRELUDE} def f() sqrt(16); def g() pow(2, 10) + floor(2.5);");
+        assert_eq!(jit_call(&input, "f"), 4.0);
+        assert_eq!(jit_call(&input, "g"), 1026.0);
+    }
+
+    #[test]
+    fn void_functions() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("extern putchard(c): void; global g; def store(x): void g = x; def f() store(4) + g;")
+            .unwrap();
+        let ir = codegen
+            .visit_top(&ast.0[0])
+            .unwrap()
+            .print_to_string()
+            .to_string();
+        assert!(ir.contains("declare void @putchard(double"), "{ir}");
+        for ast_part in &ast.0[1..] {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        assert_eq!(codegen.run_function("f", &[]).unwrap(), 4.0);
+        let err = codegen.run_function("store", &[1.0]).unwrap_err();
+        assert_eq!(err.to_string(), "function 'store' does not return a value");
+        let ast = GlobalParser::default().parse("def putchard(c) c;").unwrap();
+        let err = codegen.visit_top(&ast.0[0]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "redefinition of function 'putchard' with a different return type"
+        );
+    }
+
+    #[test]
+    fn compile_many() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let sources = ["def binary% 40 (a b) a - b;", "def f(x) x % 1;", "f(3);"];
+        codegen.compile_many(&sources).unwrap();
+        assert_eq!(codegen.run_function("f", &[5.0]).unwrap(), 4.0);
+        assert_eq!(codegen.run_function(ANONYM_FUNCTION, &[]).unwrap(), 2.0);
+        assert!(codegen.compile_many(&["def g(x) x +;"]).is_err());
+    }
+
+    #[test]
+    fn verify_all() {
+        let context = Context::create();
+        let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+        let ast = GlobalParser::default()
+            .parse("extern sin(x); def f(x) if x < 1 then sin(x) else f(x - 1);")
+            .unwrap();
+        for ast_part in &ast {
+            codegen.visit_top(ast_part).unwrap();
+        }
+        assert!(codegen.verify_all().is_ok());
+        // A basic block without terminator instruction is invalid
+        let fn_type = context.f64_type().fn_type(&[], false);
+        let broken = codegen.module.add_function("broken", fn_type, None);
+        context.append_basic_block(broken, "entry");
+        let err = codegen.verify_all().unwrap_err();
+        assert!(err.to_string().contains("terminator"), "{err}");
+    }
+
+    #[test]
+    fn short_circuit_operators() {
+        let cases = [
+            ("0 && side_effect()", 0.0, 0),
+            ("1 && side_effect()", 1.0, 1),
+            ("2 && 0", 0.0, 0),
+            ("1 || side_effect()", 1.0, 0),
+            ("0 || side_effect()", 1.0, 1),
+            ("0 || 0.0", 0.0, 0),
+        ];
+        for (expr, expected, calls) in cases {
+            let input = format!("extern side_effect(); def f() {expr};");
+            assert_eq!(jit_call_counting(&input, "f"), (expected, calls), "{expr}");
+        }
+    }
+
+    #[test]
+    fn for_condition_checked_first() {
+        let cases = [
+            ("for i = 5, i < 3 in side_effect()", 0),
+            ("for i = 0, i < 3 in side_effect()", 3),
+            ("for i = 0, i < 1, 0.25 in side_effect()", 4),
+        ];
+        for (expr, calls) in cases {
+            let input = format!("extern side_effect(); def f() {expr};");
+            assert_eq!(jit_call_counting(&input, "f"), (0.0, calls), "{expr}");
+        }
+        assert_eq!(
+            jit_call(
+                "def f() var s = 0 in (for i = 0, i < 4 in s = s + i) : s;",
+                "f"
+            ),
+            6.0
+        );
+    }
+
+    #[test]
+    fn sequence_operator() {
+        let input =
+            "extern side_effect(); def f(x) side_effect() : side_effect() : x + 1; def g() f(2);";
+        assert_eq!(jit_call_counting(input, "g"), (3.0, 2));
+        assert_eq!(jit_call("def f() 1 : 2.5;", "f"), 2.5);
     }
 }
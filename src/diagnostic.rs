@@ -0,0 +1,83 @@
+/*
+MIT License
+
+Copyright (c) 2023 Vincent Hiribarren
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Rendering of the errors on the terminal, optionally with ANSI colors.
+
+use crate::lexer::Position;
+
+const RED: &str = "\x1b[1;31m";
+const RESET: &str = "\x1b[0m";
+
+/// Message of an error, in red if color is set. If the location of the error
+/// in the source is known, the faulty line follows, with a caret under the
+/// column of the error.
+pub fn render_error(message: &str, location: Option<(&str, Position)>, color: bool) -> String {
+    let (red, reset) = if color { (RED, RESET) } else { ("", "") };
+    let mut rendered = format!("{red}{message}{reset}");
+    let faulty_line = location.and_then(|(source, position)| {
+        let line = source.lines().nth(position.line.checked_sub(1)?)?;
+        Some((line, position.column))
+    });
+    if let Some((line, column)) = faulty_line {
+        // Keep the tabs so that the caret is aligned with the column
+        let padding: String = line
+            .chars()
+            .take(column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        rendered.push_str(&format!("\n{line}\n{padding}{red}^{reset}"));
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_error() {
+        assert_eq!(render_error("oops", None, false), "oops");
+        assert_eq!(render_error("oops", None, true), "\x1b[1;31moops\x1b[0m");
+    }
+
+    #[test]
+    fn caret_under_column() {
+        let source = "def f(x) x;\n\tf(1 +);";
+        let position = Position { line: 2, column: 7 };
+        assert_eq!(
+            render_error("unexpected ')'", Some((source, position)), false),
+            "unexpected ')'\n\tf(1 +);\n\t     ^"
+        );
+        assert_eq!(
+            render_error("unexpected ')'", Some((source, position)), true),
+            "\x1b[1;31munexpected ')'\x1b[0m\n\tf(1 +);\n\t     \x1b[1;31m^\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn position_out_of_source() {
+        let position = Position { line: 3, column: 1 };
+        assert_eq!(render_error("oops", Some(("1;", position)), false), "oops");
+    }
+}
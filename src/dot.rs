@@ -0,0 +1,183 @@
+/*
+MIT License
+
+Copyright (c) 2023 Vincent Hiribarren
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Graphviz rendering of the syntax tree, to visualize how a program is
+//! parsed.
+
+use std::fmt::Write;
+
+use crate::ast::*;
+
+impl KaleoGrammar {
+    /// Graphviz DOT graph of the syntax tree, with a vertex per node and
+    /// edges labeled by the role of the child, like `lhs` or `body`.
+    /// Render it with `dot -Tsvg`.
+    pub fn to_dot(&self) -> String {
+        let mut graph = DotGraph::default();
+        for ast_part in self {
+            graph.top(ast_part);
+        }
+        format!("digraph ast {{\n    node [shape=box];\n{}}}\n", graph.out)
+    }
+}
+
+#[derive(Default)]
+struct DotGraph {
+    out: String,
+    node_count: usize,
+}
+
+impl DotGraph {
+    fn node(&mut self, label: &str) -> usize {
+        let id = self.node_count;
+        self.node_count += 1;
+        writeln!(self.out, "    n{id} [label={label:?}];").unwrap();
+        id
+    }
+
+    fn edge(&mut self, from: usize, to: usize, label: &str) {
+        writeln!(self.out, "    n{from} -> n{to} [label={label:?}];").unwrap();
+    }
+
+    fn child(&mut self, parent: usize, expr: &ExprAST, label: &str) {
+        let id = self.expr(expr);
+        self.edge(parent, id, label);
+    }
+
+    fn top(&mut self, ast_part: &TopAST) {
+        match ast_part {
+            TopAST::Function(func) => {
+                let id = self.node(&format!("Function {}", func.proto));
+                self.child(id, &func.body, "body");
+            }
+            TopAST::Prototype(proto) => {
+                self.node(&format!("Prototype {proto}"));
+            }
+            TopAST::GlobalVar(global) => {
+                let id = self.node(&format!("GlobalVar {}", global.name));
+                if let Some(init) = &global.init {
+                    self.child(id, init, "init");
+                }
+            }
+        }
+    }
+
+    fn expr(&mut self, expr: &ExprAST) -> usize {
+        match expr {
+            ExprAST::NumberExpr(num) => self.node(&format!("NumberExpr {}", num.val)),
+            ExprAST::IntExpr(int) => self.node(&format!("IntExpr {}", int.val)),
+            ExprAST::StringExpr(string) => self.node(&format!("StringExpr {:?}", string.val)),
+            ExprAST::VariableExpr(var) => self.node(&format!("VariableExpr {}", var.name)),
+            ExprAST::UnaryExpr(unary) => {
+                let id = self.node(&format!("UnaryExpr {}", unary.opcode));
+                self.child(id, &unary.operand, "operand");
+                id
+            }
+            ExprAST::BinaryExpr(bin) => {
+                let id = self.node(&format!("BinaryExpr {}", bin.op));
+                self.child(id, &bin.lhs, "lhs");
+                self.child(id, &bin.rhs, "rhs");
+                id
+            }
+            ExprAST::CallExpr(call) => {
+                let id = self.node(&format!("CallExpr {}", call.callee));
+                for (index, arg) in call.args.iter().enumerate() {
+                    self.child(id, arg, &format!("arg{index}"));
+                }
+                id
+            }
+            ExprAST::IfExpr(if_expr) => {
+                let id = self.node("IfExpr");
+                self.child(id, &if_expr.condition, "cond");
+                self.child(id, &if_expr.then_block, "then");
+                self.child(id, &if_expr.else_block, "else");
+                id
+            }
+            ExprAST::ForExpr(for_expr) => {
+                let id = self.node(&format!("ForExpr {}", for_expr.var_name));
+                self.child(id, &for_expr.var_start, "start");
+                self.child(id, &for_expr.var_end, "end");
+                if let Some(step) = &for_expr.step {
+                    self.child(id, step, "step");
+                }
+                self.child(id, &for_expr.body, "body");
+                id
+            }
+            ExprAST::WhileExpr(while_expr) => {
+                let id = self.node("WhileExpr");
+                self.child(id, &while_expr.condition, "cond");
+                self.child(id, &while_expr.body, "body");
+                id
+            }
+            ExprAST::VarExpr(var_expr) => {
+                let names: Vec<&str> = var_expr
+                    .var_names
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect();
+                let id = self.node(&format!("VarExpr {}", names.join(" ")));
+                for (name, init) in &var_expr.var_names {
+                    if let Some(init) = init {
+                        self.child(id, init, name);
+                    }
+                }
+                self.child(id, &var_expr.body, "body");
+                id
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::parser::GlobalParser;
+
+    #[test]
+    fn binary_expression() {
+        let dot = GlobalParser::default().parse("x+y;").unwrap().to_dot();
+        assert!(dot.starts_with("digraph ast {\n"), "{dot}");
+        assert!(dot.contains("n1 [label=\"BinaryExpr +\"];"), "{dot}");
+        assert!(dot.contains("n1 -> n2 [label=\"lhs\"];"), "{dot}");
+        assert!(dot.contains("n2 [label=\"VariableExpr x\"];"), "{dot}");
+        assert!(dot.contains("n1 -> n3 [label=\"rhs\"];"), "{dot}");
+        assert!(dot.contains("n3 [label=\"VariableExpr y\"];"), "{dot}");
+        assert_eq!(dot.matches("n1 -> ").count(), 2, "{dot}");
+        assert!(dot.ends_with("}\n"), "{dot}");
+    }
+
+    #[test]
+    fn escaped_labels() {
+        let dot = GlobalParser::default()
+            .parse("extern puts(s); def f() var s = 1 in puts(\"say \\\"hi\\\"\");")
+            .unwrap()
+            .to_dot();
+        assert!(dot.contains("[label=\"Prototype puts(s)\"];"), "{dot}");
+        assert!(
+            dot.contains(r#"[label="StringExpr \"say \\\"hi\\\"\""];"#),
+            "{dot}"
+        );
+        assert!(dot.contains("[label=\"s\"];"), "{dot}");
+        assert!(dot.contains("[label=\"arg0\"];"), "{dot}");
+    }
+}
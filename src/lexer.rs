@@ -22,16 +22,35 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
+use serde::Serialize;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::BufRead;
 use std::iter::Peekable;
+use std::rc::Rc;
 use std::str::Chars;
 
+/// Two-character operators lexed as a single token rather than one token
+/// per character, unless configured otherwise
+pub const DEFAULT_MULTI_CHAR_OPS: [&str; 6] = ["&&", "||", "<=", ">=", "==", "!="];
+
 #[derive(Debug, PartialEq)]
 pub enum Token {
     Def,
     Extern,
     Identifier(String),
     Number(f64),
+    Int(i64),
+    Str(String),
+    /// Any other character, Unicode ones like `±` included, which can all
+    /// be defined as operators
     Op(char),
+    /// Operator made of several characters, like `&&`
+    OpStr(String),
+    /// `...`, ending the arguments of a variadic extern
+    Ellipsis,
+    /// `;`, separating top-level items, never an operator
+    Semicolon,
     Binary,
     Unary,
     If,
@@ -40,27 +59,154 @@ pub enum Token {
     For,
     In,
     Var,
+    Global,
     EoF,
+    /// Input that could not be turned into a token, with the reason
+    Error(String),
+    /// Text following a `#`, only produced when comments are preserved
+    Comment(String),
+}
+
+/// Short description of the token, for error messages
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Def => write!(f, "'def'"),
+            Token::Extern => write!(f, "'extern'"),
+            Token::Identifier(name) => write!(f, "identifier '{name}'"),
+            Token::Number(val) => write!(f, "number {val}"),
+            Token::Int(val) => write!(f, "number {val}"),
+            Token::Str(val) => write!(f, "string {val:?}"),
+            Token::Op(op) => write!(f, "'{op}'"),
+            Token::OpStr(op) => write!(f, "'{op}'"),
+            Token::Ellipsis => write!(f, "'...'"),
+            Token::Semicolon => write!(f, "';'"),
+            Token::Binary => write!(f, "'binary'"),
+            Token::Unary => write!(f, "'unary'"),
+            Token::If => write!(f, "'if'"),
+            Token::Then => write!(f, "'then'"),
+            Token::Else => write!(f, "'else'"),
+            Token::For => write!(f, "'for'"),
+            Token::In => write!(f, "'in'"),
+            Token::Var => write!(f, "'var'"),
+            Token::Global => write!(f, "'global'"),
+            Token::EoF => write!(f, "end of input"),
+            Token::Error(message) => write!(f, "{message}"),
+            Token::Comment(_) => write!(f, "comment"),
+        }
+    }
+}
+
+/// Line and column in the input, both starting at 1
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Self { line: 1, column: 1 }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
 }
 
 pub struct Lexer<'a> {
-    input_iter: Peekable<Chars<'a>>,
+    input_iter: Peekable<Box<dyn Iterator<Item = char> + 'a>>,
+    position: Position,
+    multi_char_ops: Vec<String>,
+    preserve_comments: bool,
+    case_insensitive_keywords: bool,
+    /// Error which ended the input, set by ReaderChars
+    read_error: Rc<RefCell<Option<String>>>,
 }
 
 impl<'a> Lexer<'a> {
     pub fn new(iter: Chars<'a>) -> Lexer<'a> {
+        Self::from_chars(Box::new(iter))
+    }
+
+    /// Lex the input read line by line, without loading it all in memory.
+    /// Lexing stops at the first read error or invalid UTF-8 line, which is
+    /// reported as a Token::Error.
+    pub fn from_reader<R: BufRead + 'a>(reader: R) -> Lexer<'a> {
+        let read_error = Rc::new(RefCell::new(None));
+        let mut lexer = Self::from_chars(Box::new(ReaderChars {
+            reader: Some(reader),
+            line: Vec::new().into_iter(),
+            error: Rc::clone(&read_error),
+        }));
+        lexer.read_error = read_error;
+        lexer
+    }
+
+    fn from_chars(iter: Box<dyn Iterator<Item = char> + 'a>) -> Lexer<'a> {
         Self {
             input_iter: iter.peekable(),
+            position: Position::default(),
+            multi_char_ops: DEFAULT_MULTI_CHAR_OPS.map(String::from).to_vec(),
+            preserve_comments: false,
+            case_insensitive_keywords: false,
+            read_error: Rc::new(RefCell::new(None)),
         }
     }
 
+    /// Replace the set of two-character operators, the longest match is
+    /// always preferred over a one-character operator.
+    pub fn with_multi_char_ops(mut self, ops: Vec<String>) -> Lexer<'a> {
+        self.multi_char_ops = ops;
+        self
+    }
+
+    /// Emit comments as Token::Comment instead of skipping them, for tools
+    /// working on the source. The parser does not accept them.
+    pub fn with_comments(mut self, preserve: bool) -> Lexer<'a> {
+        self.preserve_comments = preserve;
+        self
+    }
+
+    /// Recognize keywords whatever their case, like `DEF` or `If`. The
+    /// identifiers keep their case.
+    pub fn with_case_insensitive_keywords(mut self, insensitive: bool) -> Lexer<'a> {
+        self.case_insensitive_keywords = insensitive;
+        self
+    }
+
+    /// Iterate over the tokens along with the position where they start
+    pub fn spanned(self) -> SpannedLexer<'a> {
+        SpannedLexer(self)
+    }
+
+    /// Consume a character, tracking the position. `\n`, `\r\n` and a
+    /// lone `\r` all count as one new line.
+    fn next_char(&mut self) -> Option<char> {
+        let c = self.input_iter.next()?;
+        let crlf = c == '\r' && self.input_iter.peek() == Some(&'\n');
+        if Self::is_eol(c) && !crlf {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else {
+            self.position.column += 1;
+        }
+        Some(c)
+    }
+
+    fn is_eol(c: char) -> bool {
+        c == '\n' || c == '\r'
+    }
+
     fn consume_whitespaces(&mut self) {
         loop {
             match self.input_iter.peek() {
                 Some(c) if c.is_whitespace() => {
-                    self.input_iter.next();
+                    self.next_char();
                 }
-                Some(c) if c == &'#' => {
+                Some(c) if c == &'#' && !self.preserve_comments => {
                     self.consume_until_eol();
                 }
                 Some(_) | None => {
@@ -71,89 +217,250 @@ impl<'a> Lexer<'a> {
     }
 
     fn consume_until_eol(&mut self) {
-        loop {
-            match self.input_iter.peek() {
-                Some(c) if c == &'\n' => {
-                    self.input_iter.next();
-                    return;
-                }
-                Some(_) => {
-                    self.input_iter.next();
-                }
-                None => {
-                    return;
-                }
-            }
+        while self.input_iter.peek().is_some_and(|&c| !Self::is_eol(c)) {
+            self.next_char();
         }
     }
 
-    fn consume_alphabetic(&mut self) -> Option<String> {
+    fn consume_alphabetic(&mut self) -> String {
         let mut result = String::new();
         loop {
             match self.input_iter.peek() {
                 None => break,
-                Some(c) if c.is_alphanumeric() => {
-                    result.push(self.input_iter.next().unwrap());
+                Some(&c) if Self::is_identifier_char(c) => {
+                    result.push(self.next_char().unwrap());
                     continue;
                 }
                 Some(_) => break,
             }
         }
-        Some(result)
+        result
     }
 
-    fn consume_numeric(&mut self) -> Option<f64> {
+    /// Number starting with a digit or with a `.`, like `.5`. Digits can be
+    /// separated by `_`, like in `1_000`.
+    fn consume_numeric(&mut self) -> Token {
+        let text = self.consume_number_text(String::new());
+        if text == "..." {
+            return Token::Ellipsis;
+        }
+        if text == "0" {
+            match self.input_iter.peek() {
+                Some('x') => return self.consume_radix_int(16, "hexadecimal"),
+                Some('b') => return self.consume_radix_int(2, "binary"),
+                _ => (),
+            }
+        }
+        let Some(val) = Self::strip_separators(&text) else {
+            return Self::misplaced_separator(&text);
+        };
+        if !val.contains('.') {
+            if let Ok(int_val) = val.parse() {
+                return Token::Int(int_val);
+            }
+        }
+        match val.parse() {
+            Ok(number) => Token::Number(number),
+            Err(_) if val == "." => Token::Error("'.' is not a number".to_string()),
+            Err(_) => Token::Error(format!("invalid number '{text}'")),
+        }
+    }
+
+    /// Digits, dots and separators following `text`, as written
+    fn consume_number_text(&mut self, mut text: String) -> String {
+        while let Some(&c) = self
+            .input_iter
+            .peek()
+            .filter(|&&c| Self::is_numeric(c) || c == '_')
+        {
+            text.push(c);
+            self.next_char();
+        }
+        text
+    }
+
+    /// Number without its `_` separators, None if one of them is not
+    /// between two digits, like in `5_`, `5__0` or `1_.5`
+    fn strip_separators(text: &str) -> Option<String> {
+        let chars: Vec<char> = text.chars().collect();
+        let misplaced = chars.iter().enumerate().any(|(i, &c)| {
+            let is_digit_at = |j: usize| chars.get(j).is_some_and(char::is_ascii_digit);
+            c == '_' && !(i > 0 && is_digit_at(i - 1) && is_digit_at(i + 1))
+        });
+        (!misplaced).then(|| text.replace('_', ""))
+    }
+
+    fn misplaced_separator(text: &str) -> Token {
+        Token::Error(format!(
+            "invalid number '{text}': '_' must be between two digits"
+        ))
+    }
+
+    /// Integer written in the given radix, after its `0x` or `0b` prefix
+    fn consume_radix_int(&mut self, radix: u32, radix_name: &str) -> Token {
+        self.next_char();
+        let mut digits = String::new();
+        while let Some(&c) = self.input_iter.peek().filter(|c| c.is_alphanumeric()) {
+            digits.push(c);
+            self.next_char();
+        }
+        if let Some(invalid) = digits.chars().find(|c| !c.is_digit(radix)) {
+            return Token::Error(format!("invalid digit '{invalid}' in {radix_name} literal"));
+        }
+        if digits.is_empty() {
+            return Token::Error(format!("missing digits in {radix_name} literal"));
+        }
+        match i64::from_str_radix(&digits, radix) {
+            Ok(val) => Token::Int(val),
+            Err(_) => Token::Error(format!("{radix_name} literal out of range")),
+        }
+    }
+
+    fn consume_string(&mut self) -> Token {
+        self.next_char();
         let mut val = String::new();
         loop {
-            match self.input_iter.peek() {
-                Some(&v) if Self::is_numeric(v) => {
-                    val.push(v);
-                    self.input_iter.next().unwrap();
-                }
-                Some(_) | None => {
-                    break;
-                }
+            match self.next_char() {
+                None => return Token::Error("unterminated string".to_string()),
+                Some('"') => return Token::Str(val),
+                Some('\\') => match self.next_char() {
+                    Some('n') => val.push('\n'),
+                    Some('t') => val.push('\t'),
+                    Some('\\') => val.push('\\'),
+                    Some('"') => val.push('"'),
+                    Some(other) => {
+                        return Token::Error(format!("unknown escape sequence '\\{other}'"))
+                    }
+                    None => return Token::Error("unterminated string".to_string()),
+                },
+                Some(c) => val.push(c),
             }
         }
-        val.parse().ok()
+    }
+
+    /// Comment text, without the `#` nor the end of line
+    fn consume_comment(&mut self) -> Token {
+        self.next_char();
+        let mut text = String::new();
+        while let Some(&c) = self.input_iter.peek().filter(|&&c| !Self::is_eol(c)) {
+            text.push(c);
+            self.next_char();
+        }
+        Token::Comment(text)
     }
 
     fn is_numeric(c: char) -> bool {
         matches!(c, '.' | '0'..='9')
     }
-}
 
-impl Iterator for Lexer<'_> {
-    type Item = Token;
+    /// Identifiers start with a letter, Unicode ones like `λ` included, and
+    /// go on with letters and digits. Combining diacritical marks are kept
+    /// as well, so that a decomposed `é` is not split into a letter and an
+    /// operator.
+    fn is_identifier_char(c: char) -> bool {
+        c.is_alphanumeric() || matches!(c, '\u{300}'..='\u{36f}')
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
+    fn next_token(&mut self) -> Option<(Token, Position)> {
         self.consume_whitespaces();
+        let position = self.position;
         let token = match self.input_iter.peek() {
-            None => return None,
-            Some(c) if c.is_numeric() => match self.consume_numeric() {
-                None => panic!(),
-                Some(v) => Token::Number(v),
-            },
-            Some(c) if c.is_alphabetic() => match self.consume_alphabetic() {
-                None => panic!(),
-                Some(val) if val == "def" => Token::Def,
-                Some(val) if val == "extern" => Token::Extern,
-                Some(val) if val == "if" => Token::If,
-                Some(val) if val == "then" => Token::Then,
-                Some(val) if val == "else" => Token::Else,
-                Some(val) if val == "for" => Token::For,
-                Some(val) if val == "in" => Token::In,
-                Some(val) if val == "binary" => Token::Binary,
-                Some(val) if val == "unary" => Token::Unary,
-                Some(val) if val == "var" => Token::Var,
-                Some(any) => Token::Identifier(any),
-            },
+            None => {
+                let message = self.read_error.borrow_mut().take()?;
+                Token::Error(message)
+            }
+            Some(&c) if Self::is_numeric(c) => self.consume_numeric(),
+            Some(c) if c.is_alphabetic() => {
+                let val = self.consume_alphabetic();
+                let keyword = if self.case_insensitive_keywords {
+                    val.to_lowercase()
+                } else {
+                    val.clone()
+                };
+                match keyword.as_str() {
+                    "def" => Token::Def,
+                    "extern" => Token::Extern,
+                    "if" => Token::If,
+                    "then" => Token::Then,
+                    "else" => Token::Else,
+                    "for" => Token::For,
+                    "in" => Token::In,
+                    "binary" => Token::Binary,
+                    "unary" => Token::Unary,
+                    "var" => Token::Var,
+                    "global" => Token::Global,
+                    _ => Token::Identifier(val),
+                }
+            }
+            Some('"') => self.consume_string(),
+            Some('#') => self.consume_comment(),
+            Some(';') => {
+                self.next_char().unwrap();
+                Token::Semicolon
+            }
             Some(&c) => {
-                self.input_iter.next().unwrap();
-                Token::Op(c)
+                self.next_char().unwrap();
+                let multi_char_op = self
+                    .input_iter
+                    .peek()
+                    .map(|next| format!("{c}{next}"))
+                    .filter(|op| self.multi_char_ops.contains(op));
+                match multi_char_op {
+                    Some(op) => {
+                        self.next_char().unwrap();
+                        Token::OpStr(op)
+                    }
+                    None => Token::Op(c),
+                }
             }
         };
-        Some(token)
+        Some((token, position))
+    }
+}
+
+impl Iterator for Lexer<'_> {
+    type Item = Token;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_token().map(|(token, _)| token)
+    }
+}
+
+struct ReaderChars<R> {
+    /// None once the end of the input or an error is reached
+    reader: Option<R>,
+    line: std::vec::IntoIter<char>,
+    error: Rc<RefCell<Option<String>>>,
+}
+
+impl<R: BufRead> Iterator for ReaderChars<R> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(c) = self.line.next() {
+                return Some(c);
+            }
+            let mut line = String::new();
+            match self.reader.as_mut()?.read_line(&mut line) {
+                Ok(0) => self.reader = None,
+                Err(err) => {
+                    *self.error.borrow_mut() = Some(format!("cannot read the input: {err}"));
+                    self.reader = None;
+                }
+                Ok(_) => self.line = line.chars().collect::<Vec<_>>().into_iter(),
+            }
+        }
+    }
+}
+
+pub struct SpannedLexer<'a>(Lexer<'a>);
+
+impl Iterator for SpannedLexer<'_> {
+    type Item = (Token, Position);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next_token()
     }
 }
 
@@ -213,11 +520,90 @@ mod tests {
 
     #[test]
     fn scan_simple_number() {
-        let input = "42";
+        let input = "42.0";
         let mut lexer = Lexer::new(input.chars());
         assert_eq!(lexer.next().unwrap(), Number(42_f64));
     }
 
+    #[test]
+    fn scan_simple_int() {
+        let input = "42";
+        let mut lexer = Lexer::new(input.chars());
+        assert_eq!(lexer.next().unwrap(), Int(42));
+    }
+
+    #[test]
+    fn scan_big_int_as_number() {
+        let input = "100000000000000000000";
+        let mut lexer = Lexer::new(input.chars());
+        assert_eq!(lexer.next().unwrap(), Number(1e20));
+    }
+
+    #[test]
+    fn scan_dotted_numbers() {
+        let tokens: Vec<_> = Lexer::new(".5 5. 1.2.3 . x".chars()).collect();
+        assert_eq!(
+            tokens,
+            [
+                Number(0.5),
+                Number(5.0),
+                Error("invalid number '1.2.3'".to_string()),
+                Error("'.' is not a number".to_string()),
+                Identifier("x".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_number_separators() {
+        let tokens: Vec<_> = Lexer::new("1_000 1_000.5 0.000_1 1_2_3".chars()).collect();
+        assert_eq!(
+            tokens,
+            [Int(1000), Number(1000.5), Number(0.0001), Int(123)]
+        );
+        for invalid in ["5_", "5__0", "1_.5", "1._5", "1_000_"] {
+            let tokens: Vec<_> = Lexer::new(invalid.chars()).collect();
+            assert_eq!(
+                tokens,
+                [Error(format!(
+                    "invalid number '{invalid}': '_' must be between two digits"
+                ))],
+                "{invalid}"
+            );
+        }
+        // Outside of a number, '_' is still an operator
+        let tokens: Vec<_> = Lexer::new("a _ b".chars()).collect();
+        assert_eq!(tokens[1], Op('_'));
+        let tokens: Vec<_> = Lexer::new("x_1".chars()).collect();
+        assert_eq!(tokens, [Identifier("x".to_string()), Op('_'), Int(1)]);
+    }
+
+    #[test]
+    fn case_insensitive_keywords() {
+        let input = "DEF Foo(x) If x THEN FOR else Def";
+        let tokens: Vec<_> = Lexer::new(input.chars())
+            .with_case_insensitive_keywords(true)
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                Def,
+                Identifier("Foo".to_string()),
+                Op('('),
+                Identifier("x".to_string()),
+                Op(')'),
+                If,
+                Identifier("x".to_string()),
+                Then,
+                For,
+                Else,
+                Def
+            ]
+        );
+        let mut lexer = Lexer::new(input.chars());
+        assert_eq!(lexer.next().unwrap(), Identifier("DEF".to_string()));
+    }
+
     #[test]
     fn scan_simple_identifier() {
         let input = "abcd";
@@ -232,6 +618,21 @@ mod tests {
         assert_eq!(lexer.next().unwrap(), Op('('));
     }
 
+    #[test]
+    fn scan_semicolon() {
+        let tokens: Vec<_> = Lexer::new("x;;y ;".chars()).collect();
+        assert_eq!(
+            tokens,
+            [
+                Identifier(String::from("x")),
+                Semicolon,
+                Semicolon,
+                Identifier(String::from("y")),
+                Semicolon,
+            ]
+        );
+    }
+
     #[test]
     fn scan_simple_binary() {
         let input = "binary";
@@ -275,4 +676,254 @@ mod tests {
         assert_eq!(lexer.next().unwrap(), Identifier("hello".to_string()));
         assert!(lexer.next().is_none());
     }
+
+    #[test]
+    fn scan_positions() {
+        let input = "def foo(x)\n  x + 1";
+        let positions: Vec<_> = Lexer::new(input.chars())
+            .spanned()
+            .map(|(_, position)| (position.line, position.column))
+            .collect();
+        assert_eq!(
+            positions,
+            vec![
+                (1, 1),
+                (1, 5),
+                (1, 8),
+                (1, 9),
+                (1, 10),
+                (2, 3),
+                (2, 5),
+                (2, 7)
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_multi_char_operators() {
+        let input = "a&&b || c & d";
+        let tokens: Vec<_> = Lexer::new(input.chars()).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Identifier("a".to_string()),
+                OpStr("&&".to_string()),
+                Identifier("b".to_string()),
+                OpStr("||".to_string()),
+                Identifier("c".to_string()),
+                Op('&'),
+                Identifier("d".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_comparison_operators() {
+        let tokens: Vec<_> = Lexer::new("a<=b==c".chars()).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Identifier("a".to_string()),
+                OpStr("<=".to_string()),
+                Identifier("b".to_string()),
+                OpStr("==".to_string()),
+                Identifier("c".to_string()),
+            ]
+        );
+        let tokens: Vec<_> = Lexer::new("a < = b".chars()).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Identifier("a".to_string()),
+                Op('<'),
+                Op('='),
+                Identifier("b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_custom_multi_char_operators() {
+        let tokens: Vec<_> = Lexer::new("a<=b<>c".chars())
+            .with_multi_char_ops(vec!["<>".to_string()])
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Identifier("a".to_string()),
+                Op('<'),
+                Op('='),
+                Identifier("b".to_string()),
+                OpStr("<>".to_string()),
+                Identifier("c".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_string() {
+        let tokens: Vec<_> = Lexer::new(r#"printstr("hello world")"#.chars()).collect();
+        assert_eq!(
+            tokens,
+            vec![
+                Identifier("printstr".to_string()),
+                Op('('),
+                Str("hello world".to_string()),
+                Op(')'),
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_string_escapes() {
+        let mut lexer = Lexer::new(r#""a\n\tb\\c\"d""#.chars());
+        assert_eq!(lexer.next().unwrap(), Str("a\n\tb\\c\"d".to_string()));
+        let mut lexer = Lexer::new(r#""a\qb""#.chars());
+        assert_eq!(
+            lexer.next().unwrap(),
+            Error("unknown escape sequence '\\q'".to_string())
+        );
+    }
+
+    #[test]
+    fn scan_unterminated_string() {
+        let mut lexer = Lexer::new(r#""hello"#.chars());
+        assert_eq!(
+            lexer.next().unwrap(),
+            Error("unterminated string".to_string())
+        );
+        assert_eq!(lexer.next(), None);
+    }
+
+    #[test]
+    fn scan_from_reader() {
+        let input = "def λx(été)\n  été * 2.5; # comment\nλx(1);";
+        let from_str: Vec<_> = Lexer::new(input.chars()).spanned().collect();
+        let from_reader: Vec<_> = Lexer::from_reader(std::io::Cursor::new(input))
+            .spanned()
+            .collect();
+        assert_eq!(from_reader, from_str);
+        assert_eq!(from_str[1].0, Identifier("λx".to_string()));
+    }
+
+    #[test]
+    fn scan_from_reader_invalid_utf8() {
+        let input: &[u8] = b"def f(x) x;\n\xff\xfe;\ndef g() 1;";
+        let tokens: Vec<_> = Lexer::from_reader(std::io::Cursor::new(input)).collect();
+        assert_eq!(tokens.len(), 8, "{tokens:?}");
+        assert_eq!(tokens[6], Semicolon);
+        assert_eq!(
+            tokens[7],
+            Error(String::from(
+                "cannot read the input: stream did not contain valid UTF-8"
+            ))
+        );
+    }
+
+    #[test]
+    fn preserved_comments() {
+        let input = "# Square\ndef sq(x) x*x; # inline\n#";
+        let tokens: Vec<_> = Lexer::new(input.chars()).with_comments(true).collect();
+        assert_eq!(tokens[0], Token::Comment(" Square".to_string()));
+        assert_eq!(tokens[1], Token::Def);
+        assert_eq!(
+            tokens[tokens.len() - 2..],
+            [
+                Token::Comment(" inline".to_string()),
+                Token::Comment(String::new())
+            ]
+        );
+        let tokens: Vec<_> = Lexer::new(input.chars()).collect();
+        assert_eq!(tokens[0], Token::Def);
+        assert!(!tokens.iter().any(|t| matches!(t, Token::Comment(_))));
+    }
+
+    #[test]
+    fn radix_literals() {
+        let tokens: Vec<_> = Lexer::new("0xff 0b101 0xFF+0 0x".chars()).collect();
+        assert_eq!(
+            tokens,
+            [
+                Int(255),
+                Int(5),
+                Int(255),
+                Op('+'),
+                Int(0),
+                Error("missing digits in hexadecimal literal".to_string())
+            ]
+        );
+        let tokens: Vec<_> = Lexer::new("0x1g 0b102".chars()).collect();
+        assert_eq!(
+            tokens,
+            [
+                Error("invalid digit 'g' in hexadecimal literal".to_string()),
+                Error("invalid digit '2' in binary literal".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn crlf_line_endings() {
+        let input = "# first\r\n# second\r\ndef f(x)\r\n  x; # last\r\n\r\nf(1);\rf(2);";
+        let tokens: Vec<_> = Lexer::new(input.chars()).spanned().collect();
+        let lines: Vec<_> = tokens.iter().map(|(_, pos)| pos.line).collect();
+        assert_eq!(lines, [3, 3, 3, 3, 3, 4, 4, 6, 6, 6, 6, 6, 7, 7, 7, 7, 7]);
+        assert_eq!(
+            tokens[5],
+            (Identifier("x".to_string()), Position { line: 4, column: 3 })
+        );
+        let comments: Vec<_> = Lexer::new(input.chars()).with_comments(true).collect();
+        assert_eq!(comments[0], Token::Comment(" first".to_string()));
+        assert_eq!(comments[1], Token::Comment(" second".to_string()));
+    }
+
+    #[test]
+    fn scan_unicode() {
+        let tokens: Vec<_> = Lexer::new("def λ(x) x ± 1; e\u{301}t\u{e9}2·3".chars()).collect();
+        assert_eq!(
+            tokens,
+            [
+                Def,
+                Identifier("λ".to_string()),
+                Op('('),
+                Identifier("x".to_string()),
+                Op(')'),
+                Identifier("x".to_string()),
+                Op('±'),
+                Int(1),
+                Semicolon,
+                Identifier("e\u{301}t\u{e9}2".to_string()),
+                Op('·'),
+                Int(3),
+            ]
+        );
+        let tokens: Vec<_> = Lexer::new("a ±± b".chars())
+            .with_multi_char_ops(vec!["±±".to_string()])
+            .spanned()
+            .collect();
+        assert_eq!(
+            tokens[1],
+            (OpStr("±±".to_string()), Position { line: 1, column: 3 })
+        );
+        assert_eq!(
+            tokens[2],
+            (Identifier("b".to_string()), Position { line: 1, column: 6 })
+        );
+    }
+
+    #[test]
+    fn comment_at_end_of_input() {
+        let mut lexer = Lexer::new("x # trailing".chars());
+        assert_eq!(lexer.next(), Some(Identifier("x".to_string())));
+        assert_eq!(lexer.next(), None);
+        let tokens: Vec<_> = Lexer::new("f(1)#".chars()).collect();
+        assert_eq!(
+            tokens,
+            [Identifier("f".to_string()), Op('('), Int(1), Op(')')]
+        );
+        let tokens: Vec<_> = Lexer::new("2.5# trailing".chars())
+            .with_comments(true)
+            .collect();
+        assert_eq!(tokens, [Number(2.5), Comment(" trailing".to_string())]);
+    }
 }
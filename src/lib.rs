@@ -24,5 +24,233 @@ SOFTWARE.
 
 pub mod ast;
 pub mod codegen;
+pub mod diagnostic;
+mod dot;
 pub mod lexer;
 pub mod parser;
+pub mod repl;
+pub mod semantic;
+pub mod timing;
+pub mod transform;
+
+use std::{mem::ManuallyDrop, path::Path};
+
+use anyhow::{anyhow, bail, Result};
+use inkwell::{context::Context, values::AnyValue};
+
+use ast::{TopAST, ANONYM_FUNCTION};
+use codegen::{create_target_machine, CodeGen, PassConfig};
+use parser::GlobalParser;
+
+/// Parse and compile a kaleido program, then write it as an object file for
+/// the given target triple, or for the host if None.
+pub fn compile_str_to_object(src: &str, triple: Option<&str>, out: &Path) -> Result<()> {
+    let ast = GlobalParser::default().parse(src)?;
+    semantic::analyze(&ast)?;
+    let context = Context::create();
+    let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+    codegen.compile_grammar(&ast)?;
+    let target_machine = create_target_machine(triple)?;
+    codegen.generate_object_code(&target_machine, out)
+}
+
+/// Evaluate a single expression, like `2+3*4`, and return its value
+pub fn eval(src: &str) -> Result<f64> {
+    let ast = GlobalParser::default().parse(src)?;
+    let [TopAST::Function(function)] = &ast.0[..] else {
+        bail!("Expected a single expression to evaluate");
+    };
+    if !function.is_top_function() {
+        bail!("Expected an expression to evaluate, not a definition");
+    }
+    jit_eval(src)
+}
+
+/// Compile a whole program, like `def sq(x) x*x; sq(3);`, evaluating its
+/// top-level expressions in turn, and return the value of the last one
+pub fn jit_eval(src: &str) -> Result<f64> {
+    let ast = GlobalParser::default().parse(src)?;
+    semantic::analyze(&ast)?;
+    let context = Context::create();
+    let mut codegen = CodeGen::new(&context, true, false, PassConfig::default());
+    let mut result = None;
+    codegen.compile_grammar_with(&ast, |codegen, ast_part, value| {
+        value?;
+        if matches!(ast_part, TopAST::Function(function) if function.is_top_function()) {
+            result = Some(codegen.run_function(ANONYM_FUNCTION, &[])?);
+        }
+        Ok(())
+    })?;
+    result.ok_or(anyhow!("Expected a top-level expression to evaluate"))
+}
+
+/// Code generator owning its LLVM context, for the programs which cannot
+/// keep a Context alive around a CodeGen, like long-lived services. The
+/// definitions compiled by a call are known by the next ones.
+pub struct OwnedCompiler {
+    /// Dropped by hand, before the context it borrows
+    codegen: ManuallyDrop<CodeGen<'static>>,
+    parser: GlobalParser,
+    /// Allocated with Box, freed on drop
+    context: *mut Context,
+}
+
+impl OwnedCompiler {
+    pub fn new() -> Self {
+        let context = Box::into_raw(Box::new(Context::create()));
+        // SAFETY: the context is only freed on drop, once the code generator
+        // is gone, and is never exposed with the 'static lifetime
+        let codegen = CodeGen::new(unsafe { &*context }, true, false, PassConfig::default());
+        Self {
+            codegen: ManuallyDrop::new(codegen),
+            parser: GlobalParser::default(),
+            context,
+        }
+    }
+
+    /// Parse and generate the code of `src`, then return the LLVM IR of its
+    /// items. Top-level expressions are compiled but not evaluated.
+    pub fn compile(&mut self, src: &str) -> Result<String> {
+        let ast = self.parser.parse(src)?;
+        let mut ir = String::new();
+        for value in self.codegen.compile_grammar(&ast)? {
+            ir.push_str(&value.print_to_string().to_string());
+            ir.push('\n');
+        }
+        Ok(ir)
+    }
+}
+
+impl Default for OwnedCompiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for OwnedCompiler {
+    fn drop(&mut self) {
+        // SAFETY: nothing borrows the context anymore once the code
+        // generator is dropped, and it was allocated by new
+        unsafe {
+            ManuallyDrop::drop(&mut self.codegen);
+            drop(Box::from_raw(self.context));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eval_expression() {
+        assert_eq!(eval("2+3*4").unwrap(), 14.0);
+        assert_eq!(eval("var x = 1.5 in x * 2;").unwrap(), 3.0);
+    }
+
+    #[test]
+    fn eval_rejects_definitions() {
+        assert!(eval("def f() 1").is_err());
+        assert!(eval("extern sin(x)").is_err());
+        assert!(eval("1; 2").is_err());
+        assert!(eval("").is_err());
+    }
+
+    #[test]
+    fn owned_compiler() {
+        let mut compiler = OwnedCompiler::new();
+        let ir = compiler.compile("def f(x) x + 1;").unwrap();
+        assert!(ir.contains("define double @f(double %x)"), "{ir}");
+        let ir = compiler.compile("def g(x) f(x) * 2; g(3);").unwrap();
+        assert!(ir.contains("define double @g(double %x)"), "{ir}");
+        assert!(ir.contains("call double @f"), "{ir}");
+        assert!(!ir.contains("define double @f("), "{ir}");
+        assert!(compiler.compile("def h(x) unknown(x);").is_err());
+        assert!(compiler.compile("def (x) x;").is_err());
+        // Moving the compiler does not move its context
+        let mut moved = Box::new(compiler);
+        assert!(moved.compile("def h(x) g(x);").is_ok());
+    }
+
+    #[test]
+    fn jit_eval_arithmetic() {
+        assert_eq!(jit_eval("1 + 2 * 3 - 4;").unwrap(), 3.0);
+        assert_eq!(jit_eval("(1 + 2) * 3;").unwrap(), 9.0);
+        // Integers are only kept for '+', '-', '*' and '%'
+        assert_eq!(jit_eval("7 / 2;").unwrap(), 3.5);
+        assert_eq!(jit_eval("7 % 3 + 0.5;").unwrap(), 1.5);
+        assert_eq!(jit_eval("-(2 - 5);").unwrap(), 3.0);
+        assert_eq!(jit_eval("1 < 2;").unwrap(), 1.0);
+    }
+
+    #[test]
+    fn jit_eval_if() {
+        let src = "def sign(x) if x < 0 then -1 else if 0 < x then 1 else 0;";
+        assert_eq!(jit_eval(&format!("{src} sign(-3);")).unwrap(), -1.0);
+        assert_eq!(jit_eval(&format!("{src} sign(0);")).unwrap(), 0.0);
+        assert_eq!(jit_eval(&format!("{src} sign(2.5);")).unwrap(), 1.0);
+        assert_eq!(jit_eval("if 1 < 2 && 3 < 2 then 1 else 2;").unwrap(), 2.0);
+    }
+
+    #[test]
+    fn jit_eval_loops() {
+        let src = "def sum(n) var s = 0 in (for i = 1, i < n + 1 in s = s + i) : s;";
+        assert_eq!(jit_eval(&format!("{src} sum(10);")).unwrap(), 55.0);
+        assert_eq!(jit_eval(&format!("{src} sum(0);")).unwrap(), 0.0);
+        let src = "def pow2(n) var p = 1 in (for i = 0, i < n, 1 in p = p * 2) : p;";
+        assert_eq!(jit_eval(&format!("{src} pow2(8);")).unwrap(), 256.0);
+    }
+
+    #[test]
+    fn jit_eval_calls() {
+        let src = "def fib(x) if x < 3 then 1 else fib(x - 1) + fib(x - 2);
+            def twice(x) x * 2;
+            def binary ~ 30 (a b) a * 10 + b;";
+        assert_eq!(jit_eval(&format!("{src} fib(15);")).unwrap(), 610.0);
+        assert_eq!(jit_eval(&format!("{src} twice(fib(5));")).unwrap(), 10.0);
+        assert_eq!(jit_eval(&format!("{src} 1 ~ 2 ~ 3;")).unwrap(), 123.0);
+        let src = "extern sqrt(x); sqrt(16) + 1;";
+        assert_eq!(jit_eval(src).unwrap(), 5.0);
+    }
+
+    #[test]
+    fn jit_eval_globals_and_errors() {
+        assert_eq!(
+            jit_eval("global g = 1; def bump() g = g + 1; def twice() bump() : bump(); twice();")
+                .unwrap(),
+            3.0
+        );
+        assert!(jit_eval("def f(x) x;").is_err());
+        assert!(jit_eval("undefined(1);").is_err());
+        assert!(jit_eval("def (x) x;").is_err());
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn object_file_for_host() {
+        let out = std::env::temp_dir().join(format!("kaleido-obj-{}.o", std::process::id()));
+        compile_str_to_object("def square(x) x*x;", None, &out).unwrap();
+        let header = std::fs::read(&out).unwrap();
+        std::fs::remove_file(&out).unwrap();
+        if cfg!(target_os = "macos") {
+            assert_eq!(header[..4], [0xcf, 0xfa, 0xed, 0xfe]);
+        } else {
+            assert_eq!(header[..4], *b"\x7fELF");
+        }
+    }
+
+    #[test]
+    #[cfg(not(windows))]
+    fn object_file_forward_reference() {
+        let out = std::env::temp_dir().join(format!("kaleido-fwd-{}.o", std::process::id()));
+        compile_str_to_object("def a() b(); def b() 1;", None, &out).unwrap();
+        std::fs::remove_file(&out).unwrap();
+    }
+
+    #[test]
+    fn object_file_parse_error() {
+        let out = std::env::temp_dir().join("kaleido-never-written.o");
+        assert!(compile_str_to_object("def (x) x;", None, &out).is_err());
+        assert!(!out.exists());
+    }
+}
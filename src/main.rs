@@ -22,24 +22,36 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use std::{
-    io::{stdin, BufRead},
-    path::PathBuf,
-};
+use std::io::{IsTerminal, Read};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
-use anyhow::Result;
-use clap::Parser;
+use anyhow::{anyhow, bail, Context as _, Result};
+use clap::{Parser, ValueEnum};
 use inkwell::{
     context::Context,
-    targets::{CodeModel, InitializationConfig, RelocMode, Target, TargetMachine},
-    values::AnyValue,
-    OptimizationLevel,
+    values::{AnyValue, AnyValueEnum},
+};
+use llvm_tuto_kaleidoscope_rust::{
+    ast::{FunctionAST, KaleoGrammar, TopAST, LIBM_PRELUDE},
+    codegen::{create_target_machine, CodeGen, LLVM_VERSION},
+    diagnostic,
+    lexer::Position,
+    parser::GlobalParser,
+    repl::{self, ReplBuffer, ReplCommand, ReplInput},
+    semantic,
+    timing::{Phase, Timings},
+    transform,
 };
-use llvm_tuto_kaleidoscope_rust::{codegen::CodeGen, parser::GlobalParser};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Parameters {
+    /// Print the version of LLVM the program was built against, as selected
+    /// by a build feature, then exit
+    #[arg(long)]
+    llvm_version: bool,
+
     /// Disable LLVM code optimisation
     #[arg(long)]
     without_optim: bool,
@@ -52,103 +64,615 @@ struct Parameters {
     #[arg(short, long)]
     file: Option<PathBuf>,
 
+    /// Execute the script read from the standard input, without a REPL
+    #[arg(long)]
+    stdin: bool,
+
+    /// Once the file is executed, call this function and print its result
+    #[arg(long, value_name = "FUNCNAME")]
+    run: Option<String>,
+
+    /// Argument given to the function called with --run, can be repeated
+    #[arg(long = "arg", value_name = "NUMBER", allow_negative_numbers = true)]
+    args: Vec<f64>,
+
     /// Produce an object file
     #[arg(short, long)]
     output_object: Option<PathBuf>,
 
+    /// Produce an LLVM bitcode file
+    #[arg(long)]
+    emit_bitcode: Option<PathBuf>,
+
+    /// Write the textual LLVM IR to a file
+    #[arg(long)]
+    output_ir: Option<PathBuf>,
+
+    /// Write the external functions the object file must be linked with,
+    /// one per line
+    #[arg(long, value_name = "PATH")]
+    emit_deps: Option<PathBuf>,
+
+    /// Keep the function of the last top-level expression in the emitted
+    /// files, by default it is only kept in the IR, not in object files
+    #[arg(long, value_name = "BOOL")]
+    keep_anon: Option<bool>,
+
+    /// Declare the usual libm functions, like sqrt or sin
+    #[arg(long)]
+    with_libm: bool,
+
+    /// Generate the code of top-level expressions without evaluating them,
+    /// to only produce an object file or IR
+    #[arg(long)]
+    no_jit: bool,
+
     /// Mute LLVM code display
     #[arg(short, long)]
     silent: bool,
+
+    /// Consider a NaN or infinite evaluation result as an error, not a warning
+    #[arg(long)]
+    trap_on_nan: bool,
+
+    /// Consider suspicious code, like chained comparisons, as an error
+    #[arg(long)]
+    strict: bool,
+
+    /// Write the syntax tree of the script as JSON, for editor tooling
+    #[arg(long, value_name = "PATH")]
+    ast_json: Option<PathBuf>,
+
+    /// Write a representation of the script to PATH, KIND being `dot` for a
+    /// Graphviz DOT graph of the syntax tree
+    #[arg(long, num_args = 2, value_names = ["KIND", "PATH"])]
+    emit: Option<Vec<String>>,
+
+    /// Print the parsed syntax tree instead of generating code
+    #[arg(long)]
+    dump_ast: bool,
+
+    /// Only check the syntax of the script, without generating any code
+    #[arg(long)]
+    parse_only: bool,
+
+    /// Once the scripts are parsed, print the binary operators and their
+    /// precedences, including those defined with `def binary`
+    #[arg(long)]
+    dump_precedence: bool,
+
+    /// Once the scripts are executed, print the address each extern function
+    /// is linked to by the JIT, to debug missing symbols
+    #[arg(long)]
+    dump_symbols: bool,
+
+    /// Display LLVM code before and after optimisation
+    #[arg(long)]
+    show_unoptimized: bool,
+
+    /// Precede the LLVM code of each item with a comment naming it
+    #[arg(long)]
+    annotate_ir: bool,
+
+    /// Abort an evaluation once its loops have iterated this many times. The
+    /// generated code then needs the JIT, so no object file can be produced.
+    #[arg(
+        long,
+        value_name = "COUNT",
+        conflicts_with_all = ["output_object", "emit_bitcode", "emit_deps"]
+    )]
+    max_jit_iterations: Option<u64>,
+
+    /// Rewrite `for` loops into `while` loops before generating code
+    #[arg(long)]
+    desugar: bool,
+
+    /// Replace the variables bound by `var` to a literal, and never assigned,
+    /// by the literal before generating code
+    #[arg(long)]
+    propagate_constants: bool,
+
+    /// Print to stderr the time spent lexing, parsing and generating code
+    #[arg(long)]
+    report_timing: bool,
+
+    /// Color the errors, `auto` only does it if stderr is a terminal
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto)]
+    color: ColorChoice,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+/// Representations of the script written by --emit
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum EmitKind {
+    Dot,
+}
+
+impl Parameters {
+    /// Kind and path given to --emit, if any
+    fn emit(&self) -> Result<Option<(EmitKind, PathBuf)>> {
+        let Some([kind, path]) = self.emit.as_deref() else {
+            return Ok(None);
+        };
+        let kind = EmitKind::from_str(kind, false)
+            .map_err(|_| anyhow!("unknown --emit kind '{kind}', expected dot"))?;
+        Ok(Some((kind, PathBuf::from(path))))
+    }
+}
+
+impl ColorChoice {
+    fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Auto => std::io::stderr().is_terminal(),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
 }
 
 fn main() -> Result<()> {
     let params = &Parameters::parse();
+    if params.llvm_version {
+        println!("LLVM {LLVM_VERSION}");
+        return Ok(());
+    }
+    let emit = params.emit()?;
+    if params.parse_only {
+        return check_syntax(params);
+    }
     let context = &Context::create();
-    let codegen = CodeGen::new(context, !params.without_optim);
-    let global_parser = GlobalParser::default();
+    let codegen = new_codegen(context, params);
+    let mut global_parser = GlobalParser::default();
+    global_parser.set_measure_lexing(params.report_timing);
 
     let mut kaleido = Kaleido {
         params,
+        context,
         codegen,
         global_parser,
+        timings: Timings::default(),
+        reporter: Reporter {
+            error_count: 0,
+            color: params.color.enabled(),
+        },
+        emit,
     };
+    kaleido.declare_prelude()?;
 
     if let Some(script_path) = &params.file {
         let file_data = std::fs::read_to_string(script_path)?;
         kaleido.parse_and_execute(&file_data);
     }
-    if params.file.is_none() || params.interactive {
+    if params.stdin {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input)?;
+        kaleido.parse_and_execute(&input);
+    }
+    if params.dump_precedence {
+        print_precedence_table(&kaleido.global_parser);
+    }
+    if params.dump_symbols {
+        for (name, address) in kaleido.codegen.resolve_externs()? {
+            match address {
+                Some(address) => println!("{name}: {address:#x}"),
+                None => println!("{name}: not found"),
+            }
+        }
+    }
+    if kaleido.reporter.error_count > 0 && !params.interactive {
+        bail!(
+            "{} error(s) while executing the script",
+            kaleido.reporter.error_count
+        );
+    }
+    if let Some(func_name) = &params.run {
+        let result = kaleido.codegen.run_function(func_name, &params.args)?;
+        println!("{result}");
+    }
+    if (params.file.is_none() && params.run.is_none() && !params.stdin) || params.interactive {
         kaleido.launch_repl()?;
     }
+    // The IR is written first, as by default it is the only output keeping
+    // the last top-level expression
+    if params.keep_anon == Some(false) {
+        kaleido.codegen.remove_anonymous_function()?;
+    }
+    kaleido.codegen.flush_ir()?;
+    if params.keep_anon != Some(true) {
+        kaleido.codegen.remove_anonymous_function()?;
+    }
     if params.output_object.is_some() {
-        kaleido.produce_object_code();
+        kaleido.produce_object_code()?;
+    }
+    if let Some(deps_path) = &params.emit_deps {
+        let deps: String = kaleido
+            .codegen
+            .external_dependencies()
+            .iter()
+            .map(|name| format!("{name}\n"))
+            .collect();
+        std::fs::write(deps_path, deps).map_err(|err| {
+            anyhow!(
+                "Cannot write dependencies to {}: {err}",
+                deps_path.display()
+            )
+        })?;
+    }
+    if let Some(bitcode_path) = &params.emit_bitcode {
+        kaleido.codegen.write_bitcode(bitcode_path)?;
+    }
+    if params.report_timing {
+        eprintln!("{}", kaleido.timings);
+    }
+    Ok(())
+}
+
+/// Parse the scripts given with --file and --stdin, stopping at the first
+/// error. LLVM is not involved at all.
+fn check_syntax(params: &Parameters) -> Result<()> {
+    let mut inputs = vec![];
+    if let Some(script_path) = &params.file {
+        let file_data = std::fs::read_to_string(script_path)?;
+        inputs.push((script_path.display().to_string(), file_data));
+    }
+    if params.stdin {
+        let mut input = String::new();
+        std::io::stdin().read_to_string(&mut input)?;
+        inputs.push((String::from("<stdin>"), input));
+    }
+    if inputs.is_empty() {
+        bail!("--parse-only needs a script, given with --file or --stdin");
+    }
+    let mut global_parser = GlobalParser::default();
+    if params.with_libm {
+        global_parser.parse(LIBM_PRELUDE)?;
+    }
+    for (name, input) in inputs {
+        global_parser
+            .parse(&input)
+            .map_err(|err| anyhow!("{name}: {err}"))?;
+        eprintln!("{name}: syntax OK");
+    }
+    if params.dump_precedence {
+        print_precedence_table(&global_parser);
     }
     Ok(())
 }
 
+fn print_precedence_table(parser: &GlobalParser) {
+    for (op, prec) in parser.precedence_table() {
+        println!("{prec:>3} {op}");
+    }
+}
+
+fn write_ast_json(ast: &KaleoGrammar, path: &Path) -> Result<()> {
+    let json = serde_json::to_string_pretty(ast)?;
+    std::fs::write(path, json).with_context(|| format!("Cannot write AST to {}", path.display()))
+}
+
+fn new_codegen<'ctx>(context: &'ctx Context, params: &Parameters) -> CodeGen<'ctx> {
+    let builder = CodeGen::builder()
+        .optimize(!params.without_optim)
+        .keep_unoptimized_ir(params.show_unoptimized)
+        .annotate_ir(params.annotate_ir)
+        .propagate_constants(params.propagate_constants);
+    // Written at the end of main, or when the CodeGen is dropped if main
+    // returns early
+    let builder = match &params.output_ir {
+        Some(ir_path) => builder.ir_on_drop(ir_path),
+        None => builder,
+    };
+    match params.max_jit_iterations {
+        Some(max) => builder.max_loop_iterations(max),
+        None => builder,
+    }
+    .build(context)
+}
+
+fn desugar(ast: &KaleoGrammar) -> KaleoGrammar {
+    let parts = ast.iter().cloned().map(|ast_part| match ast_part {
+        TopAST::Function(FunctionAST { proto, body }) => TopAST::Function(FunctionAST {
+            proto,
+            body: transform::desugar(body),
+        }),
+        other => other,
+    });
+    KaleoGrammar(parts.collect())
+}
+
+/// Evaluate a generated top-level expression, then print the IR of the item
+fn show_item(
+    codegen: &mut CodeGen,
+    params: &Parameters,
+    reporter: &mut Reporter,
+    ast_part: &TopAST,
+    ir_value: AnyValueEnum,
+) {
+    if !params.no_jit && matches!(ast_part, TopAST::Function(func) if codegen.is_top_function(func))
+    {
+        let anonymous_name = codegen.anonymous_name().to_string();
+        match codegen.run_function(&anonymous_name, &[]) {
+            Ok(result) if !result.is_finite() && params.trap_on_nan => {
+                reporter.report_error(format!("Error: evaluation produced {result}"));
+                return;
+            }
+            Ok(result) => {
+                if !result.is_finite() {
+                    eprintln!("Warning: evaluation produced {result}");
+                }
+                println!("\nEvaluated to: {result}\n")
+            }
+            Err(err) => {
+                reporter.report_error(err);
+                return;
+            }
+        }
+    }
+    let unoptimized_ir = codegen.take_unoptimized_ir();
+    let annotation = codegen.take_annotation();
+    if params.silent {
+        return;
+    }
+    if let Some(annotation) = annotation {
+        println!("{annotation}");
+    }
+    if let Some(unoptimized_ir) = unoptimized_ir {
+        println!("; before opt\n{unoptimized_ir}\n; after opt");
+    }
+    println!("{}", ir_value.print_to_string().to_string())
+}
+
 struct Kaleido<'a> {
     params: &'a Parameters,
+    context: &'a Context,
     codegen: CodeGen<'a>,
     global_parser: GlobalParser,
+    timings: Timings,
+    reporter: Reporter,
+    emit: Option<(EmitKind, PathBuf)>,
+}
+
+/// Errors are only displayed, so that the following inputs are still
+/// executed, but they are counted to set the exit code of a script run.
+struct Reporter {
+    /// Number of errors reported while executing inputs
+    error_count: usize,
+    color: bool,
+}
+
+impl Reporter {
+    fn report_error(&mut self, err: impl std::fmt::Display) {
+        self.report(&err.to_string(), None);
+    }
+
+    fn report(&mut self, message: &str, location: Option<(&str, Position)>) {
+        eprintln!(
+            "{}",
+            diagnostic::render_error(message, location, self.color)
+        );
+        self.error_count += 1;
+    }
 }
 
 impl<'ctx> Kaleido<'ctx> {
+    /// Declarations available before any user input
+    fn declare_prelude(&mut self) -> Result<()> {
+        if self.params.with_libm {
+            let prelude = self.global_parser.parse(LIBM_PRELUDE)?;
+            self.codegen.compile_grammar(&prelude)?;
+        }
+        Ok(())
+    }
+
     fn parse_and_execute(&mut self, input: &str) {
-        let ast = match self.global_parser.parse(input) {
-            Ok(ast) => ast,
-            Err(err) => return eprintln!("{err}"),
-        };
-        for ast_part in &ast.0 {
-            match self.codegen.visit_top(ast_part) {
-                Ok(ir_value) => {
-                    if !self.params.silent {
-                        println!("{}", ir_value.print_to_string().to_string())
+        let parser = &mut self.global_parser;
+        let parsed = self.timings.record(Phase::Parsing, || parser.parse(input));
+        self.timings.split_lexing(parser.take_lexing_time());
+        match parsed {
+            Ok(ast) => {
+                if let Some(json_path) = &self.params.ast_json {
+                    if let Err(err) = write_ast_json(&ast, json_path) {
+                        self.reporter.report_error(err);
+                    }
+                }
+                if let Some((EmitKind::Dot, dot_path)) = &self.emit {
+                    if let Err(err) = std::fs::write(dot_path, ast.to_dot()) {
+                        self.reporter.report_error(format!(
+                            "Cannot write DOT graph to {}: {err}",
+                            dot_path.display()
+                        ));
                     }
                 }
-                Err(err) => eprintln!("{err}"),
-            };
+                self.execute(&ast)
+            }
+            Err(err) => {
+                let location = err.position().map(|position| (input, position));
+                self.reporter.report(&err.to_string(), location);
+            }
+        };
+    }
+
+    fn execute(&mut self, ast: &KaleoGrammar) {
+        let desugared;
+        let ast = if self.params.desugar {
+            desugared = desugar(ast);
+            &desugared
+        } else {
+            ast
+        };
+        if self.params.dump_ast {
+            println!("{ast:#?}");
+            return;
+        }
+        if let Err(err) = semantic::analyze_with_globals(ast, &self.codegen.global_names()) {
+            self.reporter.report_error(err);
+            return;
+        }
+        let warnings = semantic::lint(ast);
+        if self.params.strict && !warnings.is_empty() {
+            for warning in warnings {
+                self.reporter.report_error(format!("Error: {warning}"));
+            }
+            return;
+        }
+        for warning in warnings {
+            eprintln!("Warning: {warning}");
+        }
+        let Kaleido {
+            params,
+            codegen,
+            timings,
+            reporter,
+            ..
+        } = self;
+        // The time of the evaluations is left out of the code generation
+        let mut visit_start = Instant::now();
+        let compiled = codegen.compile_grammar_with(ast, |codegen, ast_part, ir_value| {
+            timings.add(Phase::Codegen, visit_start.elapsed());
+            match ir_value {
+                Ok(ir_value) => show_item(codegen, params, reporter, ast_part, ir_value),
+                Err(err) => reporter.report_error(err),
+            }
+            visit_start = Instant::now();
+            Ok(())
+        });
+        if let Err(err) = compiled {
+            self.reporter.report_error(err);
         }
     }
 
     fn launch_repl(&mut self) -> Result<()> {
-        eprintln!("Ctrl+D Ctrl+D to leave");
-        eprint!("ready> ");
-        for line in stdin().lock().lines() {
-            let line = line?;
-            self.parse_and_execute(&line);
-            eprint!("\nready> ");
+        eprintln!("Ctrl+D or .quit to leave, .help for other commands");
+        let mut reader = LineReader::new()?;
+        let mut buffer = ReplBuffer::default();
+        while let Some(line) = reader.read_line(buffer.prompt())? {
+            if let Some(command) = repl::parse_command(&line).filter(|_| buffer.is_empty()) {
+                match command {
+                    ReplCommand::Quit => break,
+                    ReplCommand::Help => eprintln!("{}", repl::HELP),
+                    ReplCommand::Dump => self.codegen.print_to_stderr(),
+                    ReplCommand::Funcs => {
+                        for (name, arity) in self.codegen.functions() {
+                            eprintln!("{name}/{arity}");
+                        }
+                    }
+                    ReplCommand::Reset => {
+                        self.codegen = new_codegen(self.context, self.params);
+                        self.global_parser = GlobalParser::default();
+                        self.declare_prelude()?;
+                    }
+                    ReplCommand::Unknown(cmd) => eprintln!("Unknown command {cmd}, try .help"),
+                }
+                eprintln!();
+                continue;
+            }
+            match buffer.push_line(&mut self.global_parser, &line) {
+                ReplInput::Complete(ast) => self.execute(&ast),
+                ReplInput::Incomplete => continue,
+                ReplInput::Error(err, source) => {
+                    let location = err.position().map(|position| (source.as_str(), position));
+                    eprintln!(
+                        "{}",
+                        diagnostic::render_error(&err.to_string(), location, self.reporter.color)
+                    )
+                }
+            }
+            eprintln!();
         }
+        reader.save_history();
         eprintln!("EOF, stopping parsing");
         self.codegen.print_to_stderr();
         Ok(())
     }
 
-    fn produce_object_code(&self) {
+    fn produce_object_code(&mut self) -> Result<()> {
+        let mut timings = self.timings;
+        let result = timings.record(Phase::ObjectEmission, || self.emit_object_code());
+        self.timings = timings;
+        result
+    }
+
+    fn emit_object_code(&self) -> Result<()> {
         let Some(ref output) = self.params.output_object else {
             panic!("Cannot produce code if no output file is provided");
         };
-        Target::initialize_all(&InitializationConfig {
-            asm_parser: true,
-            asm_printer: true,
-            base: true,
-            disassembler: true,
-            info: true,
-            machine_code: true,
-        });
-        let cpu = "generic";
-        let features = "";
-        let level = OptimizationLevel::Default;
-        let reloc_mode = RelocMode::Default;
-        let code_model = CodeModel::Default;
-        let target_triple = TargetMachine::get_default_triple();
-        let target = Target::from_triple(&target_triple).unwrap();
-        let target_machine = target
-            .create_target_machine(&target_triple, cpu, features, level, reloc_mode, code_model)
-            .unwrap();
-        self.codegen.generate_object_code(&target_machine, output);
+        let target_machine = create_target_machine(None)?;
+        self.codegen.generate_object_code(&target_machine, output)
     }
 }
 
+/// Line editor with history, persisted between sessions
+#[cfg(feature = "repl")]
+struct LineReader {
+    editor: rustyline::DefaultEditor,
+    history: Option<PathBuf>,
+}
+
+#[cfg(feature = "repl")]
+impl LineReader {
+    fn new() -> Result<Self> {
+        let mut editor = rustyline::DefaultEditor::new()?;
+        let history = repl::history_path();
+        if let Some(path) = &history {
+            // The history file does not exist yet on first launch
+            let _ = editor.load_history(path);
+        }
+        Ok(Self { editor, history })
+    }
+
+    fn read_line(&mut self, prompt: &str) -> Result<Option<String>> {
+        use rustyline::error::ReadlineError;
+        match self.editor.readline(prompt) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    self.editor.add_history_entry(line.as_str())?;
+                }
+                Ok(Some(line))
+            }
+            Err(ReadlineError::Interrupted) => Ok(Some(String::new())),
+            Err(ReadlineError::Eof) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn save_history(&mut self) {
+        if let Some(path) = &self.history {
+            if let Err(err) = self.editor.save_history(path) {
+                eprintln!("Could not save history to {}: {err}", path.display());
+            }
+        }
+    }
+}
+
+/// Plain line reader on the standard input, without edition nor history
+#[cfg(not(feature = "repl"))]
+struct LineReader {
+    lines: std::io::Lines<std::io::StdinLock<'static>>,
+}
+
+#[cfg(not(feature = "repl"))]
+impl LineReader {
+    fn new() -> Result<Self> {
+        use std::io::BufRead;
+        Ok(Self {
+            lines: std::io::stdin().lock().lines(),
+        })
+    }
+
+    fn read_line(&mut self, prompt: &str) -> Result<Option<String>> {
+        eprint!("{prompt}");
+        Ok(self.lines.next().transpose()?)
+    }
+
+    fn save_history(&mut self) {}
+}
+
 #[no_mangle]
 pub extern "C" fn hello() -> f64 {
     println!("Bonjour le monde !");
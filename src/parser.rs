@@ -22,153 +22,625 @@ OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
 
-use anyhow::{bail, ensure, Result};
 use once_cell::sync::Lazy;
 
 use crate::ast::*;
-use crate::lexer::{Lexer, Token};
+use crate::lexer::{Lexer, Position, SpannedLexer, Token, DEFAULT_MULTI_CHAR_OPS};
+use crate::transform::subexprs;
 use std::collections::HashMap;
+use std::fmt;
 use std::iter::Peekable;
+use std::time::{Duration, Instant};
 
-static BIN_OP_PRIORITY: Lazy<HashMap<char, isize>> = Lazy::new(|| {
+static BIN_OP_PRIORITY: Lazy<HashMap<String, isize>> = Lazy::new(|| {
     let mut m = HashMap::new();
-    m.insert('=', 2);
-    m.insert('<', 10);
-    m.insert('+', 20);
-    m.insert('-', 20);
-    m.insert('*', 40);
+    m.insert(":".to_string(), 1);
+    m.insert("=".to_string(), 2);
+    m.insert("||".to_string(), 5);
+    m.insert("&&".to_string(), 6);
+    m.insert("<".to_string(), 10);
+    m.insert("<=".to_string(), 10);
+    m.insert(">=".to_string(), 10);
+    m.insert("==".to_string(), 10);
+    m.insert("!=".to_string(), 10);
+    m.insert("+".to_string(), 20);
+    m.insert("-".to_string(), 20);
+    m.insert("*".to_string(), 40);
+    m.insert("/".to_string(), 40);
+    m.insert("%".to_string(), 40);
     m
 });
 
+/// Binary operator applied before the unary operators, see parse_unary
+const POWER_OP: &str = "^";
+
+/// Binary operators grouped from the right, so that `a = b = 3` assigns 3
+/// to both variables
+const RIGHT_ASSOCIATIVE_OPS: [&str; 1] = ["="];
+
+#[derive(Debug, PartialEq)]
+pub enum ParseError {
+    UnexpectedToken {
+        expected: String,
+        found: Token,
+    },
+    ExpectedExpression {
+        found: Token,
+        position: Position,
+    },
+    UnterminatedParen(Token),
+    /// `()`, at the position of the opening parenthesis
+    EmptyParens(Position),
+    BadArgumentList(Token),
+    InvalidPrecedence(f64),
+    OperatorArity {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    ArityMismatch {
+        callee: String,
+        expected: usize,
+        found: usize,
+        position: Position,
+    },
+    InvalidToken {
+        message: String,
+        position: Position,
+    },
+    NestingTooDeep(Position),
+    /// `...` in the arguments of a function defined with `def`
+    VariadicDefinition(String),
+    /// Input following a complete top-level item, in strict mode
+    TrailingInput {
+        found: Token,
+        position: Position,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::UnexpectedToken { expected, found } => {
+                write!(f, "Was waiting for {expected}, but received: {found:?}")
+            }
+            ParseError::ExpectedExpression {
+                found: found @ (Token::Op(')') | Token::Op(',')),
+                position,
+            } => write!(
+                f,
+                "unexpected {found} at {position}: maybe a missing operand?"
+            ),
+            ParseError::ExpectedExpression { found, position } => {
+                write!(
+                    f,
+                    "unexpected {found} at {position}, expecting an expression"
+                )
+            }
+            ParseError::UnterminatedParen(found) => {
+                write!(f, "Was expecting a ')', but received: {found:?}")
+            }
+            ParseError::EmptyParens(position) => {
+                write!(f, "empty parenthesized expression at {position}")
+            }
+            ParseError::BadArgumentList(found) => {
+                write!(
+                    f,
+                    "Expected ')' or ',' in argument list, but received: {found:?}"
+                )
+            }
+            ParseError::InvalidPrecedence(prec) => {
+                write!(f, "Invalid precedence {prec}: must be 1..100")
+            }
+            ParseError::OperatorArity {
+                name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Operator {name} must have {expected} argument(s), but has {found}"
+            ),
+            ParseError::ArityMismatch {
+                callee,
+                expected,
+                found,
+                position,
+            } => write!(
+                f,
+                "call to '{callee}' expects {expected} args, got {found} at {position}"
+            ),
+            ParseError::InvalidToken { message, position } => write!(f, "{message} at {position}"),
+            ParseError::NestingTooDeep(position) => {
+                write!(f, "expression nesting too deep at {position}")
+            }
+            ParseError::VariadicDefinition(name) => {
+                write!(f, "function '{name}' cannot take '...', only an extern can")
+            }
+            ParseError::TrailingInput { found, position } => write!(
+                f,
+                "unexpected {found} at {position} after a complete item, expecting ';'"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl ParseError {
+    /// True if the error was caused by reaching the end of the input,
+    /// meaning that more input could make it valid.
+    pub fn is_incomplete(&self) -> bool {
+        match self {
+            ParseError::UnexpectedToken { found, .. }
+            | ParseError::ExpectedExpression { found, .. }
+            | ParseError::UnterminatedParen(found)
+            | ParseError::BadArgumentList(found) => found == &Token::EoF,
+            ParseError::EmptyParens(_)
+            | ParseError::InvalidPrecedence(_)
+            | ParseError::OperatorArity { .. }
+            | ParseError::ArityMismatch { .. }
+            | ParseError::InvalidToken { .. }
+            | ParseError::NestingTooDeep(_)
+            | ParseError::VariadicDefinition(_)
+            | ParseError::TrailingInput { .. } => false,
+        }
+    }
+
+    /// Where the error occurred in the input, if known
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            ParseError::ExpectedExpression { position, .. }
+            | ParseError::EmptyParens(position)
+            | ParseError::ArityMismatch { position, .. }
+            | ParseError::InvalidToken { position, .. }
+            | ParseError::NestingTooDeep(position)
+            | ParseError::TrailingInput { position, .. } => Some(*position),
+            ParseError::UnexpectedToken { .. }
+            | ParseError::UnterminatedParen(_)
+            | ParseError::BadArgumentList(_)
+            | ParseError::InvalidPrecedence(_)
+            | ParseError::OperatorArity { .. }
+            | ParseError::VariadicDefinition(_) => None,
+        }
+    }
+}
+
+type ParseResult<T> = Result<T, ParseError>;
+
+pub const DEFAULT_MAX_NESTING_DEPTH: usize = 256;
+
 pub struct GlobalParser {
-    token_precedence: HashMap<char, isize>,
+    token_precedence: HashMap<String, isize>,
+    function_arities: HashMap<String, usize>,
+    multi_char_ops: Vec<String>,
+    max_nesting_depth: usize,
+    anonymous_name: String,
+    case_insensitive_keywords: bool,
+    /// Time spent lexing, if measured
+    lexing_time: Option<Duration>,
 }
 
 impl Default for GlobalParser {
     fn default() -> Self {
         Self {
             token_precedence: BIN_OP_PRIORITY.clone(),
+            function_arities: HashMap::new(),
+            multi_char_ops: DEFAULT_MULTI_CHAR_OPS.map(String::from).to_vec(),
+            max_nesting_depth: DEFAULT_MAX_NESTING_DEPTH,
+            anonymous_name: String::from(ANONYM_FUNCTION),
+            case_insensitive_keywords: false,
+            lexing_time: None,
         }
     }
 }
 
 impl GlobalParser {
-    pub fn parse(&mut self, input: &str) -> Result<KaleoGrammar> {
-        let lexer = Lexer::new(input.chars()).peekable();
-        let parser = &mut Parser {
+    /// Set the precedence of a binary operator, as `def binary` would do.
+    pub fn set_precedence(&mut self, op: &str, prec: isize) {
+        self.token_precedence.insert(op.to_string(), prec);
+    }
+
+    /// Precedence of a binary operator, None if it is unknown
+    pub fn precedence(&self, op: &str) -> Option<isize> {
+        self.token_precedence.get(op).copied()
+    }
+
+    /// Forget the operators defined with `def binary` and the precedences
+    /// changed with set_precedence, back to the default table.
+    pub fn reset_precedence(&mut self) {
+        self.token_precedence = BIN_OP_PRIORITY.clone();
+    }
+
+    /// Every binary operator known so far with its precedence, including
+    /// those defined with `def binary`, from the loosest to the tightest
+    pub fn precedence_table(&self) -> Vec<(String, isize)> {
+        sorted_precedences(&self.token_precedence)
+    }
+
+    /// Lex `op`, made of two characters, as a single operator token so that
+    /// it can be defined with `def binary`.
+    pub fn add_multi_char_op(&mut self, op: &str) {
+        if !self.multi_char_ops.iter().any(|known| known == op) {
+            self.multi_char_ops.push(op.to_string());
+        }
+    }
+
+    /// Lexer configured with the operators known by this parser
+    pub fn lexer<'a>(&self, input: &'a str) -> Lexer<'a> {
+        Lexer::new(input.chars())
+            .with_multi_char_ops(self.multi_char_ops.clone())
+            .with_case_insensitive_keywords(self.case_insensitive_keywords)
+    }
+
+    /// See Lexer::with_case_insensitive_keywords
+    pub fn set_case_insensitive_keywords(&mut self, insensitive: bool) {
+        self.case_insensitive_keywords = insensitive;
+    }
+
+    /// Maximum number of nested expressions, deeper input is rejected
+    /// instead of overflowing the stack
+    pub fn set_max_nesting_depth(&mut self, depth: usize) {
+        self.max_nesting_depth = depth;
+    }
+
+    /// Name of the functions wrapping top-level expressions, to be given to
+    /// the code generator as well if it is not the default ANONYM_FUNCTION
+    pub fn set_anonymous_name(&mut self, name: &str) {
+        self.anonymous_name = name.to_string();
+    }
+
+    pub fn anonymous_name(&self) -> &str {
+        &self.anonymous_name
+    }
+
+    /// Measure the time spent pulling tokens from the lexer while parsing,
+    /// see take_lexing_time
+    pub fn set_measure_lexing(&mut self, measure: bool) {
+        self.lexing_time = measure.then_some(Duration::ZERO);
+    }
+
+    /// Time spent lexing by the parses since the previous call, which is
+    /// part of their parsing time. Zero if it is not measured.
+    pub fn take_lexing_time(&mut self) -> Duration {
+        self.lexing_time
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    pub fn parse(&mut self, input: &str) -> ParseResult<KaleoGrammar> {
+        self.parser(input, false).parse_top()
+    }
+
+    /// Same as parse, but each top-level item must be followed by `;`, by
+    /// `def`, `extern` or `global`, or by the end of input. In lenient mode,
+    /// `1+2 garbage;` is silently read as two top-level expressions.
+    pub fn parse_strict(&mut self, input: &str) -> ParseResult<KaleoGrammar> {
+        self.parser(input, true).parse_top()
+    }
+
+    /// Same as parse, but instead of stopping at the first error, skip to
+    /// the next `;`, `def`, `extern` or `global` and go on. The items with
+    /// errors are missing from the returned grammar.
+    pub fn parse_collecting(&mut self, input: &str) -> (KaleoGrammar, Vec<ParseError>) {
+        let mut parser = self.parser(input, false);
+        parser.errors = Some(vec![]);
+        let grammar = parser
+            .parse_top()
+            .expect("errors are collected, not returned");
+        (grammar, parser.errors.unwrap_or_default())
+    }
+
+    fn parser<'a>(&'a mut self, input: &'a str, strict: bool) -> Parser<'a> {
+        let lexer = self.lexer(input).spanned().peekable();
+        Parser {
             lexer,
+            position: Position::default(),
             token_precedence: &mut self.token_precedence,
-        };
-        parser.parse_top()
+            function_arities: &mut self.function_arities,
+            strict,
+            depth: 0,
+            max_depth: self.max_nesting_depth,
+            anonymous_name: &self.anonymous_name,
+            after_separator: false,
+            errors: None,
+            lexing_time: self.lexing_time.as_mut(),
+        }
     }
 }
 
 pub struct Parser<'a> {
-    lexer: Peekable<Lexer<'a>>,
-    token_precedence: &'a mut HashMap<char, isize>,
+    lexer: Peekable<SpannedLexer<'a>>,
+    /// Position of the last consumed token
+    position: Position,
+    token_precedence: &'a mut HashMap<String, isize>,
+    /// Number of arguments of the functions declared so far, used to check calls
+    function_arities: &'a mut HashMap<String, usize>,
+    /// Reject anything but a separator or a new definition after a top-level item
+    strict: bool,
+    /// Number of expressions being parsed, one inside the other
+    depth: usize,
+    max_depth: usize,
+    anonymous_name: &'a str,
+    /// True if the last consumed token is a `;`
+    after_separator: bool,
+    /// Errors recovered from, None to stop at the first error
+    errors: Option<Vec<ParseError>>,
+    /// Time spent lexing, if measured
+    lexing_time: Option<&'a mut Duration>,
+}
+
+/// Number of nodes from expr down to its deepest leaf
+fn height(expr: &ExprAST) -> usize {
+    1 + subexprs(expr).into_iter().map(height).max().unwrap_or(0)
+}
+
+fn sorted_precedences(token_precedence: &HashMap<String, isize>) -> Vec<(String, isize)> {
+    let mut table: Vec<_> = token_precedence
+        .iter()
+        .map(|(op, prec)| (op.clone(), *prec))
+        .collect();
+    table.sort_by(|(op1, prec1), (op2, prec2)| prec1.cmp(prec2).then(op1.cmp(op2)));
+    table
 }
 
 impl<'a> Parser<'a> {
-    fn add_token_precedence(&mut self, op: char, prec: isize) {
+    /// See GlobalParser::precedence_table, operators defined by the input
+    /// parsed so far are included
+    pub fn precedence_table(&self) -> Vec<(String, isize)> {
+        sorted_precedences(self.token_precedence)
+    }
+
+    fn add_token_precedence(&mut self, op: String, prec: isize) {
         self.token_precedence.insert(op, prec);
     }
 
-    fn get_token_precedence(&self, op: char) -> isize {
-        match self.token_precedence.get(&op) {
+    fn get_token_precedence(&self, op: &str) -> isize {
+        match self.token_precedence.get(op) {
             Some(val) => *val,
             None => -1,
         }
     }
 
-    fn consume_and_ensure_token(&mut self, _token: Token) -> Result<()> {
-        ensure!(
-            matches!(self.consume_token(), _token),
-            format!("Was waiting for '{_token:?}' token")
-        );
+    fn consume_and_ensure_token(&mut self, token: Token) -> ParseResult<()> {
+        let found = self.consume_token();
+        if found != token {
+            return Err(ParseError::UnexpectedToken {
+                expected: format!("'{token:?}' token"),
+                found,
+            });
+        }
         Ok(())
     }
 
-    fn parse_top(&mut self) -> Result<KaleoGrammar> {
+    fn unexpected<T>(expected: &str, found: Token) -> ParseResult<T> {
+        Err(ParseError::UnexpectedToken {
+            expected: expected.to_string(),
+            found,
+        })
+    }
+
+    fn parse_top(&mut self) -> ParseResult<KaleoGrammar> {
         let mut result = vec![];
         loop {
-            match self.peek_token() {
-                Token::Def => result.push(TopAST::Function(self.parse_definition()?)),
-                Token::Extern => result.push(TopAST::Prototype(self.parse_extern()?)),
-                Token::Op(';') => {
+            let item = match self.peek_token() {
+                Token::Def => self.parse_definition().map(TopAST::Function),
+                Token::Extern => self.parse_extern().map(TopAST::Prototype),
+                Token::Global => self.parse_global().map(TopAST::GlobalVar),
+                Token::Semicolon => {
                     self.consume_token();
+                    continue;
                 }
                 Token::EoF => return Ok(KaleoGrammar(result)),
-                _ => result.push(TopAST::Function(self.parse_top_level_expression()?)),
+                _ => self.parse_top_level_expression().map(TopAST::Function),
+            };
+            let item = match item {
+                Ok(item) if self.strict => self.ensure_item_end().map(|_| item),
+                item => item,
             };
+            match (item, &mut self.errors) {
+                (Ok(item), _) => result.push(item),
+                (Err(err), Some(errors)) => {
+                    errors.push(err);
+                    self.skip_to_next_item();
+                }
+                (Err(err), None) => return Err(err),
+            }
         }
     }
 
-    fn parse_primary(&mut self) -> Result<ExprAST> {
+    /// Consume the tokens up to the start of the next top-level item
+    fn skip_to_next_item(&mut self) {
+        if self.after_separator {
+            return;
+        }
+        loop {
+            match self.peek_token() {
+                Token::Def | Token::Extern | Token::Global | Token::EoF => return,
+                Token::Semicolon => {
+                    self.consume_token();
+                    return;
+                }
+                _ => {
+                    self.consume_token();
+                }
+            }
+        }
+    }
+
+    fn ensure_item_end(&mut self) -> ParseResult<()> {
+        match self.peek_token() {
+            Token::Semicolon | Token::Def | Token::Extern | Token::Global | Token::EoF => Ok(()),
+            _ => {
+                let position = self.peek_position();
+                Err(ParseError::TrailingInput {
+                    found: self.consume_token(),
+                    position,
+                })
+            }
+        }
+    }
+
+    fn parse_primary(&mut self) -> ParseResult<ExprAST> {
         match self.peek_token() {
             Token::Identifier(_) => self.parse_identifier_expr(),
-            Token::Number(_) => self.parse_number_expr(),
+            Token::Number(_) | Token::Int(_) => self.parse_number_expr(),
+            Token::Str(_) => self.parse_string_expr(),
             Token::Op('(') => self.parse_paren_expr(),
             Token::If => self.parse_if_expr(),
             Token::For => self.parse_for_expr(),
             Token::Var => self.parse_var_expr(),
-            other => bail!("Unknown token {other:?} when expecting an expression"),
+            Token::Error(_) => {
+                let position = self.peek_position();
+                let Token::Error(message) = self.consume_token() else {
+                    unreachable!()
+                };
+                Err(ParseError::InvalidToken { message, position })
+            }
+            _ => {
+                let position = self.peek_position();
+                Err(ParseError::ExpectedExpression {
+                    found: self.consume_token(),
+                    position,
+                })
+            }
+        }
+    }
+
+    /// Lex the next token if not already done, measuring the time it takes
+    /// if requested. Tokens are only lexed here, consuming one once peeked
+    /// costs nothing.
+    fn lex_ahead(&mut self) {
+        if let Some(lexing_time) = &mut self.lexing_time {
+            let start = Instant::now();
+            self.lexer.peek();
+            **lexing_time += start.elapsed();
         }
     }
 
     fn consume_token(&mut self) -> Token {
+        self.lex_ahead();
         match self.lexer.next() {
-            Some(token) => token,
+            Some((token, position)) => {
+                self.position = position;
+                self.after_separator = token == Token::Semicolon;
+                token
+            }
             None => Token::EoF,
         }
     }
 
     fn peek_token(&mut self) -> &Token {
+        self.lex_ahead();
         match self.lexer.peek() {
-            Some(token) => token,
+            Some((token, _)) => token,
             None => &Token::EoF,
         }
     }
 
-    fn parse_expression(&mut self) -> Result<ExprAST> {
-        let lhs = self.parse_unary()?;
-        self.parse_bin_op_rhs(0, lhs)
+    fn peek_position(&mut self) -> Position {
+        self.lex_ahead();
+        match self.lexer.peek() {
+            Some((_, position)) => *position,
+            None => self.position,
+        }
     }
 
-    fn parse_unary(&mut self) -> Result<ExprAST> {
+    /// Name of the operator about to be consumed, if the next token is one
+    fn peek_operator(&mut self) -> Option<String> {
         match self.peek_token() {
-            Token::Op('(') | Token::Op(',') => return self.parse_primary(),
+            Token::Op(op) => Some(op.to_string()),
+            Token::OpStr(op) => Some(op.clone()),
+            _ => None,
+        }
+    }
+
+    fn parse_expression(&mut self) -> ParseResult<ExprAST> {
+        self.nested(|parser| {
+            let lhs = parser.parse_unary()?;
+            parser.parse_bin_op_rhs(0, lhs)
+        })
+    }
+
+    /// Run a parsing function which may recurse, failing if too many of them
+    /// are already running.
+    fn nested<T>(&mut self, parse: impl FnOnce(&mut Self) -> ParseResult<T>) -> ParseResult<T> {
+        if self.depth >= self.max_depth {
+            return Err(ParseError::NestingTooDeep(self.peek_position()));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// Unary operators bind tighter than binary ones, `-x + 1` is
+    /// `(-x) + 1`, except for the power operator `^`, once defined: as in
+    /// mathematics, `-x ^ 2` is `-(x ^ 2)`.
+    fn parse_unary(&mut self) -> ParseResult<ExprAST> {
+        match self.peek_token() {
+            // Punctuation, never a unary operator
+            Token::Op('(' | ',' | ')') => return self.parse_primary(),
             Token::Op(_) => (),
             _ => return self.parse_primary(),
         }
         let Token::Op(opcode) = self.consume_token() else {
             unreachable!()
         };
-        let operand = self.parse_unary()?;
+        let mut operand = self.nested(Self::parse_unary)?;
+        if self.peek_operator().as_deref() == Some(POWER_OP) {
+            let power_precedence = self.get_token_precedence(POWER_OP);
+            if power_precedence > 0 {
+                operand = self.parse_bin_op_rhs(power_precedence, operand)?;
+            }
+        }
         Ok(ExprAST::UnaryExpr(UnaryExprAST {
             opcode,
             operand: Box::new(operand),
         }))
     }
 
-    fn parse_bin_op_rhs(&mut self, expr_precedence: isize, mut lhs: ExprAST) -> Result<ExprAST> {
+    /// A chain of left-associative operators, like `1+1+1`, is parsed by a
+    /// loop rather than by recursion, but each operator still nests the
+    /// tree one level deeper, which is limited as well: the leaves of the
+    /// built tree cannot be deeper than max_depth.
+    fn parse_bin_op_rhs(
+        &mut self,
+        expr_precedence: isize,
+        mut lhs: ExprAST,
+    ) -> ParseResult<ExprAST> {
+        let mut lhs_height = None;
         loop {
-            let op = match self.peek_token() {
-                &Token::Op(op) => op,
-                _ => return Ok(lhs),
+            let Some(op) = self.peek_operator() else {
+                return Ok(lhs);
             };
-            let tok_prec = self.get_token_precedence(op);
+            let tok_prec = self.get_token_precedence(&op);
             if tok_prec < expr_precedence {
                 return Ok(lhs);
             }
+            let op_position = self.peek_position();
             self.consume_token();
             let mut rhs = self.parse_unary()?;
-            if let Token::Op(next_op) = self.peek_token() {
-                let test = *next_op;
-                let next_prec = self.get_token_precedence(test);
-                if tok_prec < next_prec {
-                    rhs = self.parse_bin_op_rhs(tok_prec + 1, rhs)?;
+            if let Some(next_op) = self.peek_operator() {
+                let next_prec = self.get_token_precedence(&next_op);
+                let right_associative = RIGHT_ASSOCIATIVE_OPS.contains(&op.as_str());
+                if tok_prec < next_prec || right_associative && tok_prec == next_prec {
+                    // The following operators of the same precedence are
+                    // then part of the right-hand side
+                    let min_prec = if right_associative {
+                        tok_prec
+                    } else {
+                        tok_prec + 1
+                    };
+                    rhs = self.nested(|parser| parser.parse_bin_op_rhs(min_prec, rhs))?;
                 }
             }
+            let height = 1 + height(&rhs).max(*lhs_height.get_or_insert_with(|| height(&lhs)));
+            // The root of lhs is at the current depth
+            if self.depth + height - 1 > self.max_depth {
+                return Err(ParseError::NestingTooDeep(op_position));
+            }
+            lhs_height = Some(height);
             lhs = ExprAST::BinaryExpr(BinaryExprAST {
                 op,
                 lhs: Box::new(lhs),
@@ -177,23 +649,35 @@ impl<'a> Parser<'a> {
         }
     }
 
-    fn parse_number_expr(&mut self) -> Result<ExprAST> {
+    fn parse_number_expr(&mut self) -> ParseResult<ExprAST> {
         match self.consume_token() {
             Token::Number(val) => Ok(ExprAST::NumberExpr(NumberExprAST { val })),
-            _ => bail!("Was waiting for a Token::Number"),
+            Token::Int(val) => Ok(ExprAST::IntExpr(IntExprAST { val })),
+            other => Self::unexpected("a number", other),
         }
     }
 
-    fn parse_paren_expr(&mut self) -> Result<ExprAST> {
+    fn parse_string_expr(&mut self) -> ParseResult<ExprAST> {
+        match self.consume_token() {
+            Token::Str(val) => Ok(ExprAST::StringExpr(StringExprAST { val })),
+            other => Self::unexpected("a string", other),
+        }
+    }
+
+    fn parse_paren_expr(&mut self) -> ParseResult<ExprAST> {
+        let position = self.peek_position();
         self.consume_token();
-        let expr = self.parse_expression();
+        if self.peek_token() == &Token::Op(')') {
+            return Err(ParseError::EmptyParens(position));
+        }
+        let expr = self.parse_expression()?;
         match self.consume_token() {
-            Token::Op(')') => expr,
-            _ => bail!("Was expecting a ')'"),
+            Token::Op(')') => Ok(expr),
+            other => Err(ParseError::UnterminatedParen(other)),
         }
     }
 
-    fn parse_if_expr(&mut self) -> Result<ExprAST> {
+    fn parse_if_expr(&mut self) -> ParseResult<ExprAST> {
         self.consume_and_ensure_token(Token::If)?;
         let condition = Box::new(self.parse_expression()?);
         self.consume_and_ensure_token(Token::Then)?;
@@ -207,11 +691,11 @@ impl<'a> Parser<'a> {
         }))
     }
 
-    fn parse_for_expr(&mut self) -> Result<ExprAST> {
+    fn parse_for_expr(&mut self) -> ParseResult<ExprAST> {
         self.consume_and_ensure_token(Token::For)?;
         let var_name = match self.consume_token() {
             Token::Identifier(var_name) => var_name,
-            other => bail!("Was waiting for an identifier, but received: {other:?}"),
+            other => return Self::unexpected("an identifier", other),
         };
         self.consume_and_ensure_token(Token::Op('='))?;
         let var_start = Box::new(self.parse_expression()?);
@@ -235,13 +719,14 @@ impl<'a> Parser<'a> {
         }))
     }
 
-    fn parse_identifier_expr(&mut self) -> Result<ExprAST> {
+    fn parse_identifier_expr(&mut self) -> ParseResult<ExprAST> {
+        let position = self.peek_position();
         let name = match self.consume_token() {
             Token::Identifier(id_name) => id_name,
-            _ => bail!("Was waiting for a Token::Identifier"),
+            other => return Self::unexpected("an identifier", other),
         };
         if !matches!(self.peek_token(), Token::Op('(')) {
-            return Ok(ExprAST::VariableExpr(VariableExprAST { name }));
+            return Ok(ExprAST::VariableExpr(VariableExprAST { name, position }));
         }
         self.consume_token();
         let mut args = vec![];
@@ -252,22 +737,32 @@ impl<'a> Parser<'a> {
                     break;
                 }
                 if !matches!(self.peek_token(), Token::Op(',')) {
-                    bail!("Expected ')' or ',' in argument list");
+                    return Err(ParseError::BadArgumentList(self.consume_token()));
                 }
                 self.consume_token();
             }
         }
         self.consume_token();
+        if let Some(&expected) = self.function_arities.get(&name) {
+            if expected != args.len() {
+                return Err(ParseError::ArityMismatch {
+                    callee: name,
+                    expected,
+                    found: args.len(),
+                    position,
+                });
+            }
+        }
         Ok(ExprAST::CallExpr(CallExprAST { callee: name, args }))
     }
 
-    fn parse_var_expr(&mut self) -> Result<ExprAST> {
+    fn parse_var_expr(&mut self) -> ParseResult<ExprAST> {
         self.consume_and_ensure_token(Token::Var)?;
         let mut var_names = Vec::new();
         loop {
             let identifier = match self.consume_token() {
                 Token::Identifier(id) => id,
-                other => bail!("Expected Identifier, received: {other:?}"),
+                other => return Self::unexpected("an identifier", other),
             };
             let init_val = match self.peek_token() {
                 &Token::Op('=') => {
@@ -287,7 +782,7 @@ impl<'a> Parser<'a> {
         Ok(ExprAST::VarExpr(VarExprAST { var_names, body }))
     }
 
-    fn parse_prototype(&mut self) -> Result<PrototypeAST> {
+    fn parse_prototype(&mut self) -> ParseResult<PrototypeAST> {
         let operator;
         let name;
         match self.consume_token() {
@@ -298,79 +793,141 @@ impl<'a> Parser<'a> {
             Token::Unary => {
                 let op_name = match self.consume_token() {
                     Token::Op(op) => op,
-                    other => bail!("Was expecting an Op, got {other:?}"),
+                    other => return Self::unexpected("an operator", other),
                 };
                 name = PrototypeAST::gen_unary_func_name(op_name);
                 operator = Some(Operator::Unary);
             }
             Token::Binary => {
                 let op_name = match self.consume_token() {
-                    Token::Op(op) => op,
-                    other => bail!("Was expecting an Op, got {other:?}"),
+                    Token::Op(op) => op.to_string(),
+                    Token::OpStr(op) => op,
+                    other => return Self::unexpected("an operator", other),
                 };
                 let mut precedence = 30;
-                if let Token::Number(prec_candidate) = self.peek_token() {
-                    if *prec_candidate < 1.0 || *prec_candidate > 100.0 {
-                        bail!("Invalid precedence: must be 1..100");
+                let prec_candidate = match *self.peek_token() {
+                    Token::Number(prec) => Some(prec),
+                    Token::Int(prec) => Some(prec as f64),
+                    _ => None,
+                };
+                if let Some(prec_candidate) = prec_candidate {
+                    if !(1.0..=100.0).contains(&prec_candidate) {
+                        return Err(ParseError::InvalidPrecedence(prec_candidate));
                     }
-                    precedence = *prec_candidate as isize;
+                    precedence = prec_candidate as isize;
                     self.consume_token();
+                } else if self.peek_token() != &Token::Op('(') {
+                    let found = self.consume_token();
+                    return Self::unexpected("a precedence between 1 and 100 or '('", found);
                 }
+                name = PrototypeAST::gen_binary_func_name(&op_name);
                 operator = Some(Operator::Binary {
                     op_name,
                     precedence,
                 });
-                name = PrototypeAST::gen_binary_func_name(op_name);
             }
-            _ => bail!("Was waiting a Token::Identifier"),
+            other => return Self::unexpected("an identifier", other),
         };
         self.consume_and_ensure_token(Token::Op('('))?;
         let mut args = vec![];
+        let mut var_args = false;
         loop {
             match self.consume_token() {
-                Token::Identifier(id) => args.push(id),
+                Token::Identifier(id) if !var_args => args.push(id),
+                Token::Ellipsis if !var_args && operator.is_none() => var_args = true,
                 Token::Op(')') => {
-                    match operator {
-                        Some(Operator::Binary { .. }) => ensure!(args.len() == 2),
-                        Some(Operator::Unary) => ensure!(args.len() == 1),
-                        None => (),
+                    let expected = match operator {
+                        Some(Operator::Binary { .. }) => Some(2),
+                        Some(Operator::Unary) => Some(1),
+                        None => None,
                     };
+                    if let Some(expected) = expected.filter(|&e| e != args.len()) {
+                        return Err(ParseError::OperatorArity {
+                            name,
+                            expected,
+                            found: args.len(),
+                        });
+                    }
+                    // A variadic function accepts any number of extra arguments
+                    if var_args {
+                        self.function_arities.remove(&name);
+                    } else {
+                        self.function_arities.insert(name.clone(), args.len());
+                    }
+                    let ret_type = self.parse_return_type()?;
                     return Ok(PrototypeAST {
                         name,
                         args,
                         operator,
+                        ret_type,
+                        var_args,
                     });
                 }
-                _ => bail!("Was expecting ')'"),
+                other if var_args => return Self::unexpected("')' after '...'", other),
+                other => return Self::unexpected("an argument name or ')'", other),
             }
         }
     }
 
-    fn parse_definition(&mut self) -> Result<FunctionAST> {
+    fn parse_definition(&mut self) -> ParseResult<FunctionAST> {
         self.consume_and_ensure_token(Token::Def)?;
         let proto = self.parse_prototype()?;
+        if proto.var_args {
+            return Err(ParseError::VariadicDefinition(proto.name));
+        }
         let expr = self.parse_expression()?;
         if let Some(Operator::Binary {
             op_name,
             precedence,
         }) = &proto.operator
         {
-            self.add_token_precedence(*op_name, *precedence);
+            self.add_token_precedence(op_name.clone(), *precedence);
         }
         Ok(FunctionAST { proto, body: expr })
     }
 
-    fn parse_extern(&mut self) -> Result<PrototypeAST> {
+    fn parse_global(&mut self) -> ParseResult<GlobalVarAST> {
+        self.consume_and_ensure_token(Token::Global)?;
+        let name = match self.consume_token() {
+            Token::Identifier(id) => id,
+            other => return Self::unexpected("an identifier", other),
+        };
+        let init = match self.peek_token() {
+            Token::Op('=') => {
+                self.consume_token();
+                Some(self.parse_expression()?)
+            }
+            _ => None,
+        };
+        Ok(GlobalVarAST { name, init })
+    }
+
+    /// Optional `: double` or `: void` after the arguments of a prototype
+    fn parse_return_type(&mut self) -> ParseResult<ReturnType> {
+        if self.peek_token() != &Token::Op(':') {
+            return Ok(ReturnType::Double);
+        }
+        self.consume_token();
+        match self.consume_token() {
+            Token::Identifier(name) if name == "double" => Ok(ReturnType::Double),
+            Token::Identifier(name) if name == "void" => Ok(ReturnType::Void),
+            other => Self::unexpected("a return type, 'double' or 'void'", other),
+        }
+    }
+
+    fn parse_extern(&mut self) -> ParseResult<PrototypeAST> {
         self.consume_and_ensure_token(Token::Extern)?;
         self.parse_prototype()
     }
 
-    fn parse_top_level_expression(&mut self) -> Result<FunctionAST> {
+    fn parse_top_level_expression(&mut self) -> ParseResult<FunctionAST> {
         let expr = self.parse_expression()?;
         let anonymous_prototype = PrototypeAST {
-            name: String::from(ANONYM_FUNCTION),
+            name: self.anonymous_name.to_string(),
             args: vec![],
             operator: None,
+            ret_type: ReturnType::Double,
+            var_args: false,
         };
         Ok(FunctionAST {
             body: expr,
@@ -394,6 +951,8 @@ mod tests {
             name: String::from("sin"),
             args: vec![String::from("a")],
             operator: None,
+            ret_type: ReturnType::Double,
+            var_args: false,
         })]);
         assert_eq!(ast, result);
     }
@@ -409,17 +968,27 @@ mod tests {
                 name: "foo".to_string(),
                 args: vec!["x".to_string(), "y".to_string()],
                 operator: None,
+                ret_type: ReturnType::Double,
+                var_args: false,
             },
             body: ExprAST::BinaryExpr(BinaryExprAST {
-                op: '+',
+                op: "+".to_string(),
                 lhs: Box::new(ExprAST::VariableExpr(VariableExprAST {
                     name: "x".to_string(),
+                    position: Position {
+                        line: 2,
+                        column: 22,
+                    },
                 })),
                 rhs: Box::new(ExprAST::CallExpr(CallExprAST {
                     callee: "foo".to_string(),
                     args: vec![
                         ExprAST::VariableExpr(VariableExprAST {
                             name: "y".to_string(),
+                            position: Position {
+                                line: 2,
+                                column: 28,
+                            },
                         }),
                         ExprAST::NumberExpr(NumberExprAST { val: 4.0 }),
                     ],
@@ -441,14 +1010,24 @@ mod tests {
                     name: "foo".to_string(),
                     args: vec!["x".to_string(), "y".to_string()],
                     operator: None,
+                    ret_type: ReturnType::Double,
+                    var_args: false,
                 },
                 body: ExprAST::BinaryExpr(BinaryExprAST {
-                    op: '+',
+                    op: "+".to_string(),
                     lhs: Box::new(ExprAST::VariableExpr(VariableExprAST {
                         name: "x".to_string(),
+                        position: Position {
+                            line: 2,
+                            column: 22,
+                        },
                     })),
                     rhs: Box::new(ExprAST::VariableExpr(VariableExprAST {
                         name: "y".to_string(),
+                        position: Position {
+                            line: 2,
+                            column: 24,
+                        },
                     })),
                 }),
             }),
@@ -457,15 +1036,31 @@ mod tests {
                     name: ANONYM_FUNCTION.to_string(),
                     args: vec![],
                     operator: None,
+                    ret_type: ReturnType::Double,
+                    var_args: false,
                 },
                 body: ExprAST::VariableExpr(VariableExprAST {
                     name: "y".to_string(),
+                    position: Position {
+                        line: 2,
+                        column: 26,
+                    },
                 }),
             }),
         ]);
         assert_eq!(ast, result);
     }
 
+    #[test]
+    fn clone_function_ast() {
+        let input = "def foo(n) var a = 1 in for i = 0, i < n in if i then a = a * 2 else foo(i);";
+        let ast = GlobalParser::default().parse(input).unwrap();
+        let TopAST::Function(function) = &ast.0[0] else {
+            panic!("expected a function definition");
+        };
+        assert_eq!(&function.clone(), function);
+    }
+
     #[test]
     fn scan_bad_input_1() {
         let input = r#"
@@ -474,4 +1069,751 @@ mod tests {
         let ast = GlobalParser::default().parse(input);
         assert!(ast.is_err());
     }
+
+    #[test]
+    fn error_unexpected_token() {
+        let input = "def foo(x) if x 1 else 0;";
+        let ast = GlobalParser::default().parse(input);
+        assert_eq!(
+            ast,
+            Err(ParseError::UnexpectedToken {
+                expected: "'Then' token".to_string(),
+                found: Token::Int(1),
+            })
+        );
+    }
+
+    #[test]
+    fn error_unterminated_paren() {
+        let input = "(1 + 2";
+        let ast = GlobalParser::default().parse(input);
+        assert_eq!(ast, Err(ParseError::UnterminatedParen(Token::EoF)));
+    }
+
+    #[test]
+    fn error_empty_parens() {
+        let ast = GlobalParser::default().parse("1 + ()");
+        assert_eq!(
+            ast,
+            Err(ParseError::EmptyParens(Position { line: 1, column: 5 }))
+        );
+        let err = ast.unwrap_err();
+        assert_eq!(err.to_string(), "empty parenthesized expression at 1:5");
+        assert_eq!(err.position(), Some(Position { line: 1, column: 5 }));
+        let err = GlobalParser::default().parse("(1+)").unwrap_err();
+        assert!(!err.is_incomplete());
+        assert_eq!(
+            err.to_string(),
+            "unexpected ')' at 1:4: maybe a missing operand?"
+        );
+    }
+
+    #[test]
+    fn error_invalid_precedence() {
+        let input = "def binary| 200 (a b) a;";
+        let ast = GlobalParser::default().parse(input);
+        assert_eq!(ast, Err(ParseError::InvalidPrecedence(200.0)));
+    }
+
+    #[test]
+    fn binary_operator_precedence() {
+        let precedence_of = |input: &str| {
+            let ast = GlobalParser::default().parse(input)?;
+            let TopAST::Function(function) = &ast.0[0] else {
+                panic!("expected a function");
+            };
+            match &function.proto.operator {
+                Some(Operator::Binary { precedence, .. }) => Ok(*precedence),
+                other => panic!("expected a binary operator, got {other:?}"),
+            }
+        };
+        assert_eq!(precedence_of("def binary| (a b) a;"), Ok(30));
+        assert_eq!(precedence_of("def binary|(a b) a;"), Ok(30));
+        assert_eq!(precedence_of("def binary| 5 (a b) a;"), Ok(5));
+        assert_eq!(precedence_of("def binary| 1 (a b) a;"), Ok(1));
+        assert_eq!(precedence_of("def binary| 100 (a b) a;"), Ok(100));
+        assert_eq!(precedence_of("def binary| 7.0 (a b) a;"), Ok(7));
+        assert_eq!(
+            precedence_of("def binary| 0 (a b) a;"),
+            Err(ParseError::InvalidPrecedence(0.0))
+        );
+        assert_eq!(
+            precedence_of("def binary| 101 (a b) a;"),
+            Err(ParseError::InvalidPrecedence(101.0))
+        );
+        assert_eq!(
+            precedence_of("def binary| x (a b) a;"),
+            Err(ParseError::UnexpectedToken {
+                expected: String::from("a precedence between 1 and 100 or '('"),
+                found: Token::Identifier(String::from("x")),
+            })
+        );
+        assert_eq!(
+            precedence_of("def binary| ; (a b) a;"),
+            Err(ParseError::UnexpectedToken {
+                expected: String::from("a precedence between 1 and 100 or '('"),
+                found: Token::Semicolon,
+            })
+        );
+        assert_eq!(
+            precedence_of("def binary|() 1;"),
+            Err(ParseError::OperatorArity {
+                name: String::from("binary|"),
+                expected: 2,
+                found: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn error_operator_arity() {
+        let input = "def unary!(a b) a;";
+        let ast = GlobalParser::default().parse(input);
+        assert!(matches!(
+            ast,
+            Err(ParseError::OperatorArity {
+                expected: 1,
+                found: 2,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn error_display() {
+        let err = ParseError::ExpectedExpression {
+            found: Token::Then,
+            position: Position { line: 2, column: 3 },
+        };
+        assert_eq!(
+            err.to_string(),
+            "unexpected 'then' at 2:3, expecting an expression"
+        );
+    }
+
+    #[test]
+    fn error_malformed_expressions() {
+        let message = |input| {
+            GlobalParser::default()
+                .parse(input)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            message("1 + ;"),
+            "unexpected ';' at 1:5, expecting an expression"
+        );
+        assert_eq!(
+            message("def f(x) x *\n  then;"),
+            "unexpected 'then' at 2:3, expecting an expression"
+        );
+        assert_eq!(
+            message("foo(1, )"),
+            "unexpected ')' at 1:8: maybe a missing operand?"
+        );
+        assert_eq!(
+            message("foo(, 1)"),
+            "unexpected ',' at 1:5: maybe a missing operand?"
+        );
+        assert_eq!(
+            message("1 +"),
+            "unexpected end of input at 1:3, expecting an expression"
+        );
+    }
+
+    #[test]
+    fn unicode_operator_and_identifier() {
+        let mut parser = GlobalParser::default();
+        let ast = parser
+            .parse("def binary± 10 (a b) a - b; def λ(x) x ± 1 * 2;")
+            .unwrap();
+        let TopAST::Function(operator) = &ast.0[0] else {
+            panic!("expected a function");
+        };
+        assert_eq!(operator.proto.name, "binary±");
+        assert_eq!(parser.precedence("±"), Some(10));
+        let TopAST::Function(lambda) = &ast.0[1] else {
+            panic!("expected a function");
+        };
+        assert_eq!(lambda.proto.name, "λ");
+        assert!(matches!(&lambda.body, ExprAST::BinaryExpr(BinaryExprAST { op, .. }) if op == "±"));
+        // Without a definition, a Unicode operator is an unknown one
+        let err = GlobalParser::default()
+            .parse_strict("def f(x) x ± 1;")
+            .unwrap_err();
+        assert!(err.to_string().contains("'±'"), "{err}");
+    }
+
+    /// Expression with all its operations parenthesized
+    fn parenthesized(expr: &ExprAST) -> String {
+        match expr {
+            ExprAST::UnaryExpr(unary) => {
+                format!("({}{})", unary.opcode, parenthesized(&unary.operand))
+            }
+            ExprAST::BinaryExpr(bin) => format!(
+                "({} {} {})",
+                parenthesized(&bin.lhs),
+                bin.op,
+                parenthesized(&bin.rhs)
+            ),
+            ExprAST::VariableExpr(var) => var.name.clone(),
+            ExprAST::IntExpr(int) => int.val.to_string(),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn chained_assignment() {
+        let mut parser = GlobalParser::default();
+        let ast = parser
+            .parse("def f(a b c) a = b = c + 1 : a - b - c;")
+            .unwrap();
+        let [TopAST::Function(function)] = &ast.0[..] else {
+            panic!("expected a function");
+        };
+        assert_eq!(
+            parenthesized(&function.body),
+            "((a = (b = (c + 1))) : ((a - b) - c))"
+        );
+    }
+
+    #[test]
+    fn unary_and_binary_precedence() {
+        let parse = |parser: &mut GlobalParser, input: &str| {
+            let ast = parser.parse(&format!("def f(x y) {input};")).unwrap();
+            let [TopAST::Function(function)] = &ast.0[..] else {
+                panic!("expected a function");
+            };
+            parenthesized(&function.body)
+        };
+        // Without a power operator, `^` is not even a binary operator
+        let mut parser = GlobalParser::default();
+        assert_eq!(parse(&mut parser, "-x + 1"), "((-x) + 1)");
+        assert_eq!(parse(&mut parser, "-x * y"), "((-x) * y)");
+        parser
+            .parse("def binary ^ 50 (x n) x * x; def unary ~ (x) 0 - x;")
+            .unwrap();
+        let cases = [
+            ("-x ^ 2", "(-(x ^ 2))"),
+            ("-x + 1", "((-x) + 1)"),
+            ("~x ^ 2 * y", "((~(x ^ 2)) * y)"),
+            ("--x ^ 2", "(-(-(x ^ 2)))"),
+            ("x ^ -y", "(x ^ (-y))"),
+            ("(-x) ^ 2", "((-x) ^ 2)"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse(&mut parser, input), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn comment_at_end_of_input() {
+        let ast = GlobalParser::default()
+            .parse_strict("def f(x) x + 1 # no newline")
+            .unwrap();
+        let TopAST::Function(function) = &ast.0[0] else {
+            panic!("expected a function");
+        };
+        assert!(
+            matches!(&function.body, ExprAST::BinaryExpr(BinaryExprAST { op, .. }) if op == "+")
+        );
+    }
+
+    #[test]
+    fn semicolon_separates_items() {
+        let mut parser = GlobalParser::default();
+        let ast = parser
+            .parse_strict(";def f(x) x;; f(1); ;extern g();")
+            .unwrap();
+        assert_eq!(ast.0.len(), 3);
+        assert!(matches!(&ast.0[1], TopAST::Function(function) if function.is_top_function()));
+        assert!(matches!(&ast.0[2], TopAST::Prototype(proto) if proto.name == "g"));
+        // Not an operator, even between two operands
+        assert_eq!(parser.precedence(";"), None);
+        let err = parser.parse("def binary; 5 (a b) a;").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Was waiting for an operator, but received: Semicolon"
+        );
+    }
+
+    #[test]
+    fn else_if_chain() {
+        let ast = GlobalParser::default()
+            .parse("if a then x else if b then y else z;")
+            .unwrap();
+        let TopAST::Function(function) = &ast.0[0] else {
+            panic!("expected a function");
+        };
+        let ExprAST::IfExpr(outer) = &function.body else {
+            panic!("expected an if");
+        };
+        let ExprAST::IfExpr(inner) = outer.else_block.as_ref() else {
+            panic!("expected the else branch to be an if");
+        };
+        assert!(matches!(inner.condition.as_ref(), ExprAST::VariableExpr(var) if var.name == "b"));
+        assert!(matches!(inner.else_block.as_ref(), ExprAST::VariableExpr(var) if var.name == "z"));
+    }
+
+    #[test]
+    fn custom_operator_persists_across_parse_calls() {
+        let mut parser = GlobalParser::default();
+        parser.parse("def binary| 10 (a b) a+b;").unwrap();
+        assert_eq!(parser.token_precedence.get("|"), Some(&10));
+        let ast = parser.parse("1 + 2 | 3;").unwrap();
+        let result = KaleoGrammar(vec![TopAST::Function(FunctionAST {
+            proto: PrototypeAST {
+                name: ANONYM_FUNCTION.to_string(),
+                args: vec![],
+                operator: None,
+                ret_type: ReturnType::Double,
+                var_args: false,
+            },
+            body: ExprAST::BinaryExpr(BinaryExprAST {
+                op: "|".to_string(),
+                lhs: Box::new(ExprAST::BinaryExpr(BinaryExprAST {
+                    op: "+".to_string(),
+                    lhs: Box::new(ExprAST::IntExpr(IntExprAST { val: 1 })),
+                    rhs: Box::new(ExprAST::IntExpr(IntExprAST { val: 2 })),
+                })),
+                rhs: Box::new(ExprAST::IntExpr(IntExprAST { val: 3 })),
+            }),
+        })]);
+        assert_eq!(ast, result);
+    }
+
+    #[test]
+    fn custom_operator_unknown_to_fresh_parser() {
+        let ast = GlobalParser::default().parse("1 | 2;").unwrap();
+        assert_eq!(ast.0.len(), 2);
+    }
+
+    #[test]
+    fn scan_int_literal() {
+        let ast = GlobalParser::default().parse("1 + 2.5;").unwrap();
+        let result = KaleoGrammar(vec![TopAST::Function(FunctionAST {
+            proto: PrototypeAST {
+                name: ANONYM_FUNCTION.to_string(),
+                args: vec![],
+                operator: None,
+                ret_type: ReturnType::Double,
+                var_args: false,
+            },
+            body: ExprAST::BinaryExpr(BinaryExprAST {
+                op: "+".to_string(),
+                lhs: Box::new(ExprAST::IntExpr(IntExprAST { val: 1 })),
+                rhs: Box::new(ExprAST::NumberExpr(NumberExprAST { val: 2.5 })),
+            }),
+        })]);
+        assert_eq!(ast, result);
+    }
+
+    #[test]
+    fn scan_modulo_precedence() {
+        let ast = GlobalParser::default().parse("1 + 10 % 3;").unwrap();
+        let result = KaleoGrammar(vec![TopAST::Function(FunctionAST {
+            proto: PrototypeAST {
+                name: ANONYM_FUNCTION.to_string(),
+                args: vec![],
+                operator: None,
+                ret_type: ReturnType::Double,
+                var_args: false,
+            },
+            body: ExprAST::BinaryExpr(BinaryExprAST {
+                op: "+".to_string(),
+                lhs: Box::new(ExprAST::IntExpr(IntExprAST { val: 1 })),
+                rhs: Box::new(ExprAST::BinaryExpr(BinaryExprAST {
+                    op: "%".to_string(),
+                    lhs: Box::new(ExprAST::IntExpr(IntExprAST { val: 10 })),
+                    rhs: Box::new(ExprAST::IntExpr(IntExprAST { val: 3 })),
+                })),
+            }),
+        })]);
+        assert_eq!(ast, result);
+    }
+
+    #[test]
+    fn scan_logical_operators_precedence() {
+        let ast = GlobalParser::default().parse("1 || 2 && 3 < 4;").unwrap();
+        let result = KaleoGrammar(vec![TopAST::Function(FunctionAST {
+            proto: PrototypeAST {
+                name: ANONYM_FUNCTION.to_string(),
+                args: vec![],
+                operator: None,
+                ret_type: ReturnType::Double,
+                var_args: false,
+            },
+            body: ExprAST::BinaryExpr(BinaryExprAST {
+                op: "||".to_string(),
+                lhs: Box::new(ExprAST::IntExpr(IntExprAST { val: 1 })),
+                rhs: Box::new(ExprAST::BinaryExpr(BinaryExprAST {
+                    op: "&&".to_string(),
+                    lhs: Box::new(ExprAST::IntExpr(IntExprAST { val: 2 })),
+                    rhs: Box::new(ExprAST::BinaryExpr(BinaryExprAST {
+                        op: "<".to_string(),
+                        lhs: Box::new(ExprAST::IntExpr(IntExprAST { val: 3 })),
+                        rhs: Box::new(ExprAST::IntExpr(IntExprAST { val: 4 })),
+                    })),
+                })),
+            }),
+        })]);
+        assert_eq!(ast, result);
+    }
+
+    #[test]
+    fn builtin_comparison_precedence() {
+        for op in ["<=", ">=", "==", "!="] {
+            let ast = GlobalParser::default()
+                .parse_strict(&format!("def f(x) x{op}3 + 1;"))
+                .unwrap();
+            let TopAST::Function(function) = &ast.0[0] else {
+                panic!("expected a function");
+            };
+            let ExprAST::BinaryExpr(bin) = &function.body else {
+                panic!("expected a binary expression");
+            };
+            assert_eq!(bin.op, op);
+            assert!(matches!(*bin.rhs, ExprAST::BinaryExpr(_)));
+        }
+    }
+
+    #[test]
+    fn custom_multi_char_operator() {
+        let mut parser = GlobalParser::default();
+        parser.add_multi_char_op("<>");
+        parser
+            .parse("def binary<> 10 (a b) a < b || b < a;")
+            .unwrap();
+        let ast = parser.parse("1 <> 2 + 3;").unwrap();
+        let TopAST::Function(function) = &ast.0[0] else {
+            panic!("expected a function");
+        };
+        let ExprAST::BinaryExpr(bin) = &function.body else {
+            panic!("expected a binary expression");
+        };
+        assert_eq!(bin.op, "<>");
+        assert!(matches!(*bin.rhs, ExprAST::BinaryExpr(_)));
+    }
+
+    #[test]
+    fn precedence_table() {
+        let mut parser = GlobalParser::default();
+        let table = parser.precedence_table();
+        assert_eq!(table.first(), Some(&(String::from(":"), 1)));
+        assert_eq!(table.last(), Some(&(String::from("/"), 40)));
+        assert!(!table.iter().any(|(op, _)| op == "|"));
+        parser
+            .parse("def binary | 5 (a b) if a then 1 else b; def unary ! (v) 0;")
+            .unwrap();
+        let table = parser.precedence_table();
+        assert!(table.contains(&(String::from("|"), 5)));
+        assert!(!table.iter().any(|(op, _)| op == "!"));
+        let mut sorted = table.clone();
+        sorted.sort_by_key(|(_, prec)| *prec);
+        assert_eq!(table, sorted);
+    }
+
+    #[test]
+    fn reset_precedence() {
+        let mut parser = GlobalParser::default();
+        parser
+            .parse("def binary | 5 (a b) if a then 1 else b;")
+            .unwrap();
+        parser.set_precedence("+", 50);
+        assert_eq!(parser.precedence("|"), Some(5));
+        parser.reset_precedence();
+        assert_eq!(parser.precedence("|"), None);
+        assert_eq!(parser.precedence("+"), Some(20));
+        assert_eq!(
+            parser.precedence_table(),
+            GlobalParser::default().precedence_table()
+        );
+        assert!(parser.parse_strict("def f(a b) a | b;").is_err());
+    }
+
+    #[test]
+    fn configured_precedence() {
+        let mut parser = GlobalParser::default();
+        assert_eq!(parser.precedence("+"), Some(20));
+        assert_eq!(parser.precedence("|"), None);
+        parser.set_precedence("+", 50);
+        assert_eq!(parser.precedence("+"), Some(50));
+        let ast = parser.parse("1 * 2 + 3;").unwrap();
+        let TopAST::Function(function) = &ast.0[0] else {
+            panic!("expected a function");
+        };
+        let ExprAST::BinaryExpr(bin) = &function.body else {
+            panic!("expected a binary expression");
+        };
+        assert_eq!(bin.op, "*");
+        assert!(matches!(&*bin.rhs, ExprAST::BinaryExpr(rhs) if rhs.op == "+"));
+    }
+
+    #[test]
+    fn scan_string_literal() {
+        let ast = GlobalParser::default()
+            .parse(r#"extern printstr(s); printstr("hi\n");"#)
+            .unwrap();
+        let TopAST::Function(function) = &ast.0[1] else {
+            panic!("expected a function");
+        };
+        assert_eq!(
+            function.body,
+            ExprAST::CallExpr(CallExprAST {
+                callee: "printstr".to_string(),
+                args: vec![ExprAST::StringExpr(StringExprAST {
+                    val: "hi\n".to_string()
+                })],
+            })
+        );
+    }
+
+    #[test]
+    fn variadic_extern() {
+        let mut parser = GlobalParser::default();
+        let ast = parser
+            .parse("extern printf(fmt ...); printf(1, 2, 3); printf(1);")
+            .unwrap();
+        let TopAST::Prototype(proto) = &ast.0[0] else {
+            panic!("expected a prototype");
+        };
+        assert_eq!(proto.args, ["fmt"]);
+        assert!(proto.var_args);
+        assert_eq!(proto.to_string(), "printf(fmt ...)");
+        let ast = parser.parse("extern f(...);").unwrap();
+        let TopAST::Prototype(proto) = &ast.0[0] else {
+            panic!("expected a prototype");
+        };
+        assert!(proto.args.is_empty() && proto.var_args);
+
+        let err = parser.parse("def g(a ...) a;").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "function 'g' cannot take '...', only an extern can"
+        );
+        assert!(parser.parse("extern h(... a);").is_err());
+        assert!(parser.parse("extern h(a ... ...);").is_err());
+        assert!(parser.parse("def binary~ 10 (a ...) a;").is_err());
+    }
+
+    #[test]
+    fn return_type_annotation() {
+        let ast = GlobalParser::default()
+            .parse("extern putchard(c): void; extern sin(x) : double; def f(x) x;")
+            .unwrap();
+        let ret_types: Vec<_> = ast
+            .iter()
+            .map(|top| match top {
+                TopAST::Prototype(proto) => proto.ret_type,
+                TopAST::Function(function) => function.proto.ret_type,
+                TopAST::GlobalVar(_) => panic!("unexpected global"),
+            })
+            .collect();
+        assert_eq!(
+            ret_types,
+            [ReturnType::Void, ReturnType::Double, ReturnType::Double]
+        );
+        let err = GlobalParser::default()
+            .parse("extern f(x): int;")
+            .unwrap_err();
+        assert_eq!(
+            err,
+            ParseError::UnexpectedToken {
+                expected: "a return type, 'double' or 'void'".to_string(),
+                found: Token::Identifier("int".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn error_unterminated_string() {
+        let ast = GlobalParser::default().parse("1 +\n \"abc");
+        assert_eq!(
+            ast,
+            Err(ParseError::InvalidToken {
+                message: "unterminated string".to_string(),
+                position: Position { line: 2, column: 2 },
+            })
+        );
+    }
+
+    #[test]
+    fn error_lone_dot() {
+        let err = GlobalParser::default()
+            .parse("def f(x) x + .;")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "'.' is not a number at 1:14");
+        assert!(GlobalParser::default()
+            .parse("def f(x) x + .5 * 2.;")
+            .is_ok());
+    }
+
+    #[test]
+    fn scan_global() {
+        let ast = GlobalParser::default()
+            .parse("global counter; global limit = 10 * 2;")
+            .unwrap();
+        let result = KaleoGrammar(vec![
+            TopAST::GlobalVar(GlobalVarAST {
+                name: "counter".to_string(),
+                init: None,
+            }),
+            TopAST::GlobalVar(GlobalVarAST {
+                name: "limit".to_string(),
+                init: Some(ExprAST::BinaryExpr(BinaryExprAST {
+                    op: "*".to_string(),
+                    lhs: Box::new(ExprAST::IntExpr(IntExprAST { val: 10 })),
+                    rhs: Box::new(ExprAST::IntExpr(IntExprAST { val: 2 })),
+                })),
+            }),
+        ]);
+        assert_eq!(ast, result);
+    }
+
+    #[test]
+    fn error_call_arity_mismatch() {
+        let mut parser = GlobalParser::default();
+        parser.parse("extern foo(a b);").unwrap();
+        let ast = parser.parse("def bar(x)\n  1 + foo(x, 2, 3);");
+        assert_eq!(
+            ast,
+            Err(ParseError::ArityMismatch {
+                callee: "foo".to_string(),
+                expected: 2,
+                found: 3,
+                position: Position { line: 2, column: 7 },
+            })
+        );
+        assert_eq!(
+            ast.unwrap_err().to_string(),
+            "call to 'foo' expects 2 args, got 3 at 2:7"
+        );
+    }
+
+    #[test]
+    fn call_arity_of_recursive_function() {
+        let mut parser = GlobalParser::default();
+        assert!(parser.parse("def fib(x) fib(x-1) + fib(x-2);").is_ok());
+        assert!(parser.parse("def fact(x) x * fact(x-1, 0);").is_err());
+        assert!(parser.parse("unknown(1, 2, 3);").is_ok());
+    }
+
+    #[test]
+    fn iterate_top_level_items() {
+        let ast = GlobalParser::default()
+            .parse("extern sin(x); def f(x) sin(x); f(1);")
+            .unwrap();
+        let names: Vec<_> = ast
+            .iter()
+            .map(|top| match top {
+                TopAST::Function(function) => &function.proto.name,
+                TopAST::Prototype(proto) => &proto.name,
+                TopAST::GlobalVar(global) => &global.name,
+            })
+            .collect();
+        assert_eq!(names, ["sin", "f", ANONYM_FUNCTION]);
+        assert_eq!((&ast).into_iter().count(), 3);
+        let functions = ast
+            .into_iter()
+            .filter(|top| matches!(top, TopAST::Function(_)))
+            .count();
+        assert_eq!(functions, 2);
+    }
+
+    #[test]
+    fn custom_anonymous_name() {
+        let mut parser = GlobalParser::default();
+        parser.set_anonymous_name("__top");
+        let ast = parser.parse("1 + 2;").unwrap();
+        let TopAST::Function(function) = &ast.0[0] else {
+            panic!("expected a function, got {ast:?}");
+        };
+        assert_eq!(function.proto.name, "__top");
+        assert!(!function.is_top_function());
+    }
+
+    #[test]
+    fn collect_errors() {
+        let input = "def f(x) x +;\n1 + 2;\nextern g(x y;\ndef h() (1; def k() 2;";
+        let (ast, errors) = GlobalParser::default().parse_collecting(input);
+        assert_eq!(errors.len(), 3, "{errors:?}");
+        assert_eq!(
+            errors[0].to_string(),
+            "unexpected ';' at 1:13, expecting an expression"
+        );
+        assert!(matches!(errors[1], ParseError::UnexpectedToken { .. }));
+        assert!(matches!(errors[2], ParseError::UnterminatedParen(_)));
+        let names: Vec<_> = ast
+            .iter()
+            .map(|top| match top {
+                TopAST::Function(function) => function.proto.name.as_str(),
+                _ => panic!("unexpected item {top:?}"),
+            })
+            .collect();
+        assert_eq!(names, [ANONYM_FUNCTION, "k"]);
+        // The fail-fast parser stops at the first error
+        let first_error = GlobalParser::default().parse(input).unwrap_err();
+        assert_eq!(first_error, errors[0]);
+    }
+
+    #[test]
+    fn strict_trailing_input() {
+        let input = "1+2 garbage;";
+        let ast = GlobalParser::default().parse(input).unwrap();
+        assert_eq!(ast.0.len(), 2);
+        let err = GlobalParser::default().parse_strict(input).unwrap_err();
+        assert_eq!(
+            err,
+            ParseError::TrailingInput {
+                found: Token::Identifier("garbage".to_string()),
+                position: Position { line: 1, column: 5 },
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "unexpected identifier 'garbage' at 1:5 after a complete item, expecting ';'"
+        );
+        let ast = GlobalParser::default()
+            .parse_strict("def f(x) x def g(x) x; extern h(); 1+2")
+            .unwrap();
+        assert_eq!(ast.0.len(), 4);
+    }
+
+    #[test]
+    fn error_nesting_too_deep() {
+        let input = format!("{}1{}", "(".repeat(10_000), ")".repeat(10_000));
+        let err = GlobalParser::default().parse(&input).unwrap_err();
+        assert!(matches!(err, ParseError::NestingTooDeep(_)));
+        assert!(err
+            .to_string()
+            .starts_with("expression nesting too deep at 1:"));
+        let err = GlobalParser::default()
+            .parse(&format!("{}1", "-".repeat(10_000)))
+            .unwrap_err();
+        assert!(matches!(err, ParseError::NestingTooDeep(_)));
+        // Each '=' of a chain nests the following ones
+        let err = GlobalParser::default()
+            .parse(&format!("def f(a) {}1;", "a = ".repeat(100_000)))
+            .unwrap_err();
+        assert!(matches!(err, ParseError::NestingTooDeep(_)));
+        assert!(GlobalParser::default()
+            .parse(&format!("def f(a) {}1;", "a = ".repeat(100)))
+            .is_ok());
+
+        let mut parser = GlobalParser::default();
+        parser.set_max_nesting_depth(3);
+        assert!(parser.parse("((1))").is_ok());
+        assert!(parser.parse("(((1)))").is_err());
+        assert!(parser.parse("1+1+1; (1)+1; 1+(1)").is_ok());
+        assert!(parser.parse("1+1+1+1").is_err());
+        assert!(parser.parse("(1+1+1)").is_err());
+        // A chain of left-associative operators nests as deep as its length
+        let err = GlobalParser::default()
+            .parse(&format!("1{}", "+1".repeat(10_000)))
+            .unwrap_err();
+        assert!(matches!(err, ParseError::NestingTooDeep(_)));
+        assert!(GlobalParser::default()
+            .parse(&format!("1{}", "+1".repeat(200)))
+            .is_ok());
+    }
 }
@@ -0,0 +1,244 @@
+/*
+MIT License
+
+Copyright (c) 2023 Vincent Hiribarren
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use std::{env, path::PathBuf};
+
+use crate::ast::KaleoGrammar;
+use crate::parser::{GlobalParser, ParseError};
+
+pub const PROMPT: &str = "ready> ";
+pub const CONTINUATION_PROMPT: &str = "...> ";
+
+pub const HISTORY_FILE: &str = ".kaleido_history";
+pub const HISTORY_ENV_VAR: &str = "KALEIDO_HISTORY";
+
+/// Location of the REPL history: the file pointed by the KALEIDO_HISTORY
+/// environment variable if defined, else ~/.kaleido_history
+pub fn history_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os(HISTORY_ENV_VAR) {
+        return Some(PathBuf::from(path));
+    }
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+pub const HELP: &str = r#"Available commands:
+  .quit   Leave the REPL
+  .help   Display this help
+  .dump   Print the LLVM IR generated so far
+  .funcs  List the functions defined so far, with their number of arguments
+  .reset  Forget all the functions defined so far"#;
+
+#[derive(Debug, PartialEq)]
+pub enum ReplCommand {
+    Quit,
+    Help,
+    Dump,
+    Funcs,
+    Reset,
+    Unknown(String),
+}
+
+/// Return the REPL command of the line, or None if the line is not a command
+/// and must be parsed as kaleido code.
+pub fn parse_command(line: &str) -> Option<ReplCommand> {
+    let command = line.trim();
+    let mut chars = command.chars();
+    if chars.next() != Some('.') || !matches!(chars.next(), Some(c) if c.is_alphabetic()) {
+        return None;
+    }
+    let command = match command {
+        ".quit" => ReplCommand::Quit,
+        ".help" => ReplCommand::Help,
+        ".dump" => ReplCommand::Dump,
+        ".funcs" => ReplCommand::Funcs,
+        ".reset" => ReplCommand::Reset,
+        other => ReplCommand::Unknown(other.to_string()),
+    };
+    Some(command)
+}
+
+/// Line without its final `\`, if it ends with one that is not itself
+/// escaped by another `\`: the next line is then the continuation of this one.
+pub fn strip_continuation(line: &str) -> Option<&str> {
+    let trailing = line.len() - line.trim_end_matches('\\').len();
+    (trailing % 2 == 1).then(|| &line[..line.len() - 1])
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ReplInput {
+    Complete(KaleoGrammar),
+    Incomplete,
+    /// Parse error, with the input it was found in, which is no longer
+    /// buffered
+    Error(ParseError, String),
+}
+
+/// Accumulates REPL lines until they form a complete input, so that a
+/// definition can be typed across several lines.
+#[derive(Default)]
+pub struct ReplBuffer {
+    buffer: String,
+}
+
+impl ReplBuffer {
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    pub fn prompt(&self) -> &'static str {
+        if self.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        }
+    }
+
+    /// Add a line to the buffer and try to parse the accumulated input.
+    /// A blank line forces the parsing of what was accumulated so far, while
+    /// a line ending with `\` is joined to the next one before any parsing.
+    pub fn push_line(&mut self, parser: &mut GlobalParser, line: &str) -> ReplInput {
+        if let Some(line) = strip_continuation(line) {
+            self.buffer.push_str(line);
+            return ReplInput::Incomplete;
+        }
+        let force = line.trim().is_empty();
+        self.buffer.push_str(line);
+        self.buffer.push('\n');
+        match parser.parse(&self.buffer) {
+            Ok(ast) => {
+                self.buffer.clear();
+                ReplInput::Complete(ast)
+            }
+            Err(err) if err.is_incomplete() && !force => ReplInput::Incomplete,
+            Err(err) => ReplInput::Error(err, std::mem::take(&mut self.buffer)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::*;
+
+    #[test]
+    fn history_path_env_override() {
+        env::set_var(HISTORY_ENV_VAR, "/tmp/kaleido_test_history");
+        assert_eq!(
+            history_path(),
+            Some(PathBuf::from("/tmp/kaleido_test_history"))
+        );
+        env::remove_var(HISTORY_ENV_VAR);
+    }
+
+    #[test]
+    fn dispatch_commands() {
+        assert_eq!(parse_command(".quit"), Some(ReplCommand::Quit));
+        assert_eq!(parse_command("  .help "), Some(ReplCommand::Help));
+        assert_eq!(parse_command(".dump"), Some(ReplCommand::Dump));
+        assert_eq!(parse_command(".funcs"), Some(ReplCommand::Funcs));
+        assert_eq!(parse_command(".reset"), Some(ReplCommand::Reset));
+        assert_eq!(
+            parse_command(".foo"),
+            Some(ReplCommand::Unknown(".foo".to_string()))
+        );
+        assert_eq!(parse_command("def foo(x) x;"), None);
+        assert_eq!(parse_command(".5 + 1;"), None);
+    }
+
+    #[test]
+    fn multi_line_definition() {
+        let parser = &mut GlobalParser::default();
+        let mut buffer = ReplBuffer::default();
+        assert_eq!(buffer.prompt(), PROMPT);
+        assert_eq!(
+            buffer.push_line(parser, "def foo(x y)"),
+            ReplInput::Incomplete
+        );
+        assert_eq!(buffer.prompt(), CONTINUATION_PROMPT);
+        assert_eq!(buffer.push_line(parser, "  x +"), ReplInput::Incomplete);
+        let ReplInput::Complete(ast) = buffer.push_line(parser, "  y;") else {
+            panic!("Definition should be complete");
+        };
+        assert!(buffer.is_empty());
+        assert!(matches!(
+            &ast.0[..],
+            [TopAST::Function(FunctionAST { proto, .. })] if proto.name == "foo"
+        ));
+    }
+
+    #[test]
+    fn line_continuation() {
+        assert_eq!(strip_continuation("def foo(x) \\"), Some("def foo(x) "));
+        assert_eq!(strip_continuation("1 + 2;"), None);
+        assert_eq!(strip_continuation("a \\\\"), None);
+        assert_eq!(strip_continuation("a \\\\\\"), Some("a \\\\"));
+        assert_eq!(strip_continuation(""), None);
+
+        let parser = &mut GlobalParser::default();
+        let mut buffer = ReplBuffer::default();
+        // Without the continuation, `1 + 2` alone would be complete
+        assert_eq!(buffer.push_line(parser, "1 + 2 \\"), ReplInput::Incomplete);
+        assert_eq!(buffer.prompt(), CONTINUATION_PROMPT);
+        let ReplInput::Complete(ast) = buffer.push_line(parser, "* 3;") else {
+            panic!("Expression should be complete");
+        };
+        assert_eq!(ast, GlobalParser::default().parse("1 + 2 * 3;").unwrap());
+    }
+
+    #[test]
+    fn blank_line_aborts_incomplete_input() {
+        let parser = &mut GlobalParser::default();
+        let mut buffer = ReplBuffer::default();
+        assert_eq!(buffer.push_line(parser, "def foo(x"), ReplInput::Incomplete);
+        assert!(matches!(buffer.push_line(parser, ""), ReplInput::Error(..)));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn syntax_error_is_not_buffered() {
+        let parser = &mut GlobalParser::default();
+        let mut buffer = ReplBuffer::default();
+        assert!(matches!(
+            buffer.push_line(parser, "def foo(x) if x 1;"),
+            ReplInput::Error(..)
+        ));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn syntax_error_keeps_its_input() {
+        let parser = &mut GlobalParser::default();
+        let mut buffer = ReplBuffer::default();
+        assert_eq!(
+            buffer.push_line(parser, "def foo(x)"),
+            ReplInput::Incomplete
+        );
+        let ReplInput::Error(err, source) = buffer.push_line(parser, "  x + );") else {
+            panic!("expected a syntax error");
+        };
+        assert_eq!(source, "def foo(x)\n  x + );\n");
+        let position = err.position().unwrap();
+        assert_eq!((position.line, position.column), (2, 7));
+    }
+}
@@ -0,0 +1,287 @@
+/*
+MIT License
+
+Copyright (c) 2023 Vincent Hiribarren
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Checks performed on the AST once parsed, before code generation.
+
+use anyhow::{bail, Result};
+
+use crate::ast::*;
+use crate::lexer::Position;
+use crate::transform::subexprs;
+
+/// Operators compared by the chained comparison lint. All but `>` are built
+/// in, `>` is usually defined with `def binary`.
+const COMPARISON_OPS: [&str; 6] = ["<", ">", "<=", ">=", "==", "!="];
+
+/// Check that every variable used in a function body is bound, either as a
+/// function argument, a `for` loop variable, a `var` binding or a global.
+///
+/// All the undefined variables are reported at once, one per line.
+pub fn analyze(grammar: &KaleoGrammar) -> Result<()> {
+    analyze_with_globals(grammar, &[])
+}
+
+/// Same as [analyze], knowing the globals declared by previous inputs
+pub fn analyze_with_globals(grammar: &KaleoGrammar, globals: &[String]) -> Result<()> {
+    let mut analyzer = Analyzer {
+        globals: globals.to_vec(),
+        ..Default::default()
+    };
+    for top in grammar {
+        match top {
+            TopAST::Function(function) => analyzer.visit_function(function),
+            TopAST::GlobalVar(global) => analyzer.globals.push(global.name.clone()),
+            TopAST::Prototype(_) => (),
+        }
+    }
+    if analyzer.undefined.is_empty() {
+        return Ok(());
+    }
+    bail!(analyzer.undefined.join("\n"))
+}
+
+/// Suspicious constructs that are still valid code, one message each.
+///
+/// Reported are chained comparisons: `1 < 2 < 3` is read as `(1 < 2) < 3`,
+/// comparing 0 or 1 with 3, and divisions by a literal zero, evaluated to an
+/// infinity or NaN.
+pub fn lint(grammar: &KaleoGrammar) -> Vec<String> {
+    let mut warnings = vec![];
+    for top in grammar {
+        if let TopAST::Function(function) = top {
+            let context = if function.is_top_function() {
+                String::from("top-level expression")
+            } else {
+                format!("'{}'", function.proto.name)
+            };
+            lint_expr(&function.body, &context, &mut warnings);
+        }
+    }
+    warnings
+}
+
+fn lint_expr(expr: &ExprAST, context: &str, warnings: &mut Vec<String>) {
+    if let ExprAST::BinaryExpr(bin) = expr {
+        if is_comparison(expr) && (is_comparison(&bin.lhs) || is_comparison(&bin.rhs)) {
+            warnings.push(format!(
+                "chained comparison in {context}: '{}' compares the result of another \
+                 comparison, use '&&' to combine them",
+                bin.op
+            ));
+        }
+        if (bin.op == "/" || bin.op == "%") && is_zero_literal(&bin.rhs) {
+            warnings.push(format!(
+                "division by zero in {context}: '{}' by the literal 0 does not give a finite \
+                 number",
+                bin.op
+            ));
+        }
+    }
+    for subexpr in subexprs(expr) {
+        lint_expr(subexpr, context, warnings);
+    }
+}
+
+fn is_zero_literal(expr: &ExprAST) -> bool {
+    match expr {
+        ExprAST::NumberExpr(num) => num.val == 0.0,
+        ExprAST::IntExpr(int) => int.val == 0,
+        _ => false,
+    }
+}
+
+fn is_comparison(expr: &ExprAST) -> bool {
+    matches!(expr, ExprAST::BinaryExpr(bin) if COMPARISON_OPS.contains(&bin.op.as_str()))
+}
+
+#[derive(Default)]
+struct Analyzer {
+    scope: Vec<String>,
+    globals: Vec<String>,
+    /// Variables of the `for` loops already visited in the current function
+    finished_loops: Vec<String>,
+    /// One message per use of an unbound variable
+    undefined: Vec<String>,
+}
+
+impl Analyzer {
+    fn visit_function(&mut self, function: &FunctionAST) {
+        self.scope = function.proto.args.clone();
+        self.finished_loops.clear();
+        self.visit_expr(&function.body);
+    }
+
+    fn visit_expr(&mut self, expr: &ExprAST) {
+        match expr {
+            ExprAST::NumberExpr(_) | ExprAST::IntExpr(_) | ExprAST::StringExpr(_) => {}
+            ExprAST::VariableExpr(var) => {
+                if !self.scope.contains(&var.name) && !self.globals.contains(&var.name) {
+                    self.report_undefined(&var.name, var.position);
+                }
+            }
+            ExprAST::UnaryExpr(unary) => self.visit_expr(&unary.operand),
+            ExprAST::BinaryExpr(binary) => {
+                self.visit_expr(&binary.lhs);
+                self.visit_expr(&binary.rhs);
+            }
+            ExprAST::CallExpr(call) => call.args.iter().for_each(|arg| self.visit_expr(arg)),
+            ExprAST::IfExpr(if_expr) => {
+                self.visit_expr(&if_expr.condition);
+                self.visit_expr(&if_expr.then_block);
+                self.visit_expr(&if_expr.else_block);
+            }
+            ExprAST::ForExpr(for_expr) => {
+                self.visit_expr(&for_expr.var_start);
+                self.scope.push(for_expr.var_name.clone());
+                self.visit_expr(&for_expr.var_end);
+                if let Some(step) = &for_expr.step {
+                    self.visit_expr(step);
+                }
+                self.visit_expr(&for_expr.body);
+                self.scope.pop();
+                self.finished_loops.push(for_expr.var_name.clone());
+            }
+            ExprAST::WhileExpr(while_expr) => {
+                self.visit_expr(&while_expr.condition);
+                self.visit_expr(&while_expr.body);
+            }
+            ExprAST::VarExpr(var_expr) => {
+                let scope_len = self.scope.len();
+                for (name, init) in &var_expr.var_names {
+                    if let Some(init) = init {
+                        self.visit_expr(init);
+                    }
+                    self.scope.push(name.clone());
+                }
+                self.visit_expr(&var_expr.body);
+                self.scope.truncate(scope_len);
+            }
+        }
+    }
+
+    fn report_undefined(&mut self, name: &str, position: Position) {
+        let message = if self.finished_loops.iter().any(|loop_var| loop_var == name) {
+            format!("loop variable '{name}' is not in scope after the loop, at {position}")
+        } else {
+            format!("undefined variable '{name}' at {position}")
+        };
+        self.undefined.push(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::GlobalParser;
+
+    fn analyze_str(input: &str) -> Result<()> {
+        analyze(&GlobalParser::default().parse(input).unwrap())
+    }
+
+    #[test]
+    fn bound_variables() {
+        assert!(analyze_str("def foo(x) x + 1;").is_ok());
+        assert!(analyze_str("def foo(n) for i = 0, i < n in i;").is_ok());
+        assert!(analyze_str("def foo(x) var a = x, b = a in a + b;").is_ok());
+    }
+
+    #[test]
+    fn undefined_variable() {
+        let err = analyze_str("def foo(x) x + y;").unwrap_err();
+        assert_eq!(err.to_string(), "undefined variable 'y' at 1:16");
+    }
+
+    #[test]
+    fn all_undefined_variables_reported() {
+        let err = analyze_str("def foo(x) a;\ndef bar(y) x + b;").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "undefined variable 'a' at 1:12\n\
+             undefined variable 'x' at 2:12\n\
+             undefined variable 'b' at 2:16"
+        );
+    }
+
+    #[test]
+    fn globals() {
+        assert!(analyze_str("global g; def foo(x) g = x + g;").is_ok());
+        let err = analyze_str("def foo(x) g; global g;").unwrap_err();
+        assert_eq!(err.to_string(), "undefined variable 'g' at 1:12");
+        let ast = GlobalParser::default().parse("def foo(x) g;").unwrap();
+        assert!(analyze_with_globals(&ast, &["g".to_string()]).is_ok());
+    }
+
+    #[test]
+    fn scoped_bindings_do_not_leak() {
+        let err = analyze_str("def foo(n) (for i = 0, i < n in 1) + j;").unwrap_err();
+        assert_eq!(err.to_string(), "undefined variable 'j' at 1:38");
+        let err = analyze_str("def foo() var a = a in a;").unwrap_err();
+        assert_eq!(err.to_string(), "undefined variable 'a' at 1:19");
+    }
+
+    #[test]
+    fn chained_comparison() {
+        let lint_str = |input| lint(&GlobalParser::default().parse(input).unwrap());
+        assert_eq!(
+            lint_str("def f() 1 < 2 < 3;"),
+            [
+                "chained comparison in 'f': '<' compares the result of another comparison, \
+              use '&&' to combine them"
+            ]
+        );
+        assert!(lint_str("1 < 2 < 3;")[0].starts_with("chained comparison in top-level expression"));
+        assert_eq!(lint_str("def f(x) if x < 1 < 2 then 1 else 0;").len(), 1);
+        assert!(lint_str("def f(x) 0 < x && x < 3;").is_empty());
+        assert!(lint_str("def f(x) (x < 1) + 1 < 3;").is_empty());
+    }
+
+    #[test]
+    fn division_by_zero() {
+        let lint_str = |input| lint(&GlobalParser::default().parse(input).unwrap());
+        assert_eq!(
+            lint_str("5/0;"),
+            [
+                "division by zero in top-level expression: '/' by the literal 0 does not give a \
+              finite number"
+            ]
+        );
+        assert!(lint_str("def f(x) x % 0.0;")[0].starts_with("division by zero in 'f': '%'"));
+        assert_eq!(lint_str("def f(x) (x / 0) / 0;").len(), 2);
+        assert!(lint_str("def f(x) 0 / x + x / 0.5;").is_empty());
+        assert!(lint_str("def f(x) x / (1 - 1);").is_empty());
+    }
+
+    #[test]
+    fn loop_variable_after_loop() {
+        let err = analyze_str("def foo() (for i = 0, i < 3 in i) + i;").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "loop variable 'i' is not in scope after the loop, at 1:37"
+        );
+        // Loop variables of other functions are simply undefined
+        let err = analyze_str("def foo() for i = 0, i < 3 in i;\ndef bar() i;").unwrap_err();
+        assert_eq!(err.to_string(), "undefined variable 'i' at 2:11");
+        assert!(analyze_str("def foo() (for i = 0, i < 3 in i) + (var i = 1 in i);").is_ok());
+    }
+}
@@ -0,0 +1,117 @@
+/*
+MIT License
+
+Copyright (c) 2023 Vincent Hiribarren
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Time spent in each compilation phase, for `--report-timing`
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    Parsing,
+    Codegen,
+    ObjectEmission,
+}
+
+/// Accumulated durations of the compilation phases.
+///
+/// Parsing pulls its tokens from the lexer, so lexing is not a phase run on
+/// its own: its time is measured by the parser while parsing, then moved
+/// out of the parsing time by split_lexing.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Timings {
+    pub lexing: Duration,
+    pub parsing: Duration,
+    pub codegen: Duration,
+    pub object_emission: Duration,
+}
+
+impl Timings {
+    /// Run `f`, adding its duration to the total of `phase`
+    pub fn record<T>(&mut self, phase: Phase, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(phase, start.elapsed());
+        result
+    }
+
+    /// Add a duration measured by the caller to the total of `phase`
+    pub fn add(&mut self, phase: Phase, duration: Duration) {
+        *self.total_mut(phase) += duration;
+    }
+
+    /// Count lexing, measured during the parsing phase, as its own phase
+    pub fn split_lexing(&mut self, lexing: Duration) {
+        self.lexing += lexing;
+        self.parsing = self.parsing.saturating_sub(lexing);
+    }
+
+    pub fn total(&self) -> Duration {
+        self.lexing + self.parsing + self.codegen + self.object_emission
+    }
+
+    fn total_mut(&mut self, phase: Phase) -> &mut Duration {
+        match phase {
+            Phase::Parsing => &mut self.parsing,
+            Phase::Codegen => &mut self.codegen,
+            Phase::ObjectEmission => &mut self.object_emission,
+        }
+    }
+}
+
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "lexing:          {:?}", self.lexing)?;
+        writeln!(f, "parsing:         {:?}", self.parsing)?;
+        writeln!(f, "codegen+opt:     {:?}", self.codegen)?;
+        writeln!(f, "object emission: {:?}", self.object_emission)?;
+        write!(f, "total:           {:?}", self.total())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::GlobalParser;
+
+    #[test]
+    fn phases_are_recorded() {
+        let input = "def fib(x) if x < 3 then 1 else fib(x-1) + fib(x-2);\n".repeat(200);
+        let mut parser = GlobalParser::default();
+        parser.set_measure_lexing(true);
+        let mut timings = Timings::default();
+        let ast = timings
+            .record(Phase::Parsing, || parser.parse(&input))
+            .unwrap();
+        let parsing_and_lexing = timings.parsing;
+        let lexing = parser.take_lexing_time();
+        timings.split_lexing(lexing);
+        assert_eq!(ast.0.len(), 200);
+        assert!(timings.lexing > Duration::ZERO);
+        assert!(timings.parsing > Duration::ZERO);
+        assert_eq!(timings.codegen, Duration::ZERO);
+        assert_eq!(timings.total(), parsing_and_lexing);
+        assert_eq!(parser.take_lexing_time(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,637 @@
+/*
+MIT License
+
+Copyright (c) 2023 Vincent Hiribarren
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+//! Rewriting passes applied on the AST before code generation.
+
+use std::cmp::Ordering;
+
+use crate::ast::*;
+use crate::lexer::Position;
+
+/// Replace the built-in operations whose operands are all literals with the
+/// literal they evaluate to. User-defined operators are left untouched, but
+/// their operands are still folded.
+pub fn fold_constants(expr: ExprAST) -> ExprAST {
+    match expr {
+        ExprAST::BinaryExpr(BinaryExprAST { op, lhs, rhs }) => {
+            let lhs = fold_constants(*lhs);
+            let rhs = fold_constants(*rhs);
+            fold_binary_op(&op, &lhs, &rhs).unwrap_or_else(|| {
+                ExprAST::BinaryExpr(BinaryExprAST {
+                    op,
+                    lhs: Box::new(lhs),
+                    rhs: Box::new(rhs),
+                })
+            })
+        }
+        ExprAST::UnaryExpr(UnaryExprAST { opcode, operand }) => {
+            let operand = fold_constants(*operand);
+            fold_unary_op(opcode, &operand).unwrap_or_else(|| {
+                ExprAST::UnaryExpr(UnaryExprAST {
+                    opcode,
+                    operand: Box::new(operand),
+                })
+            })
+        }
+        other => map_subexprs(other, fold_constants),
+    }
+}
+
+/// Rewrite `for` loops as a `while` loop on variables declared with `var`,
+/// so that the loop semantics only live in one place. The hidden variables
+/// start with `_`, which the lexer never produces, so they cannot clash with
+/// the variables of the program.
+///
+/// `for i = start, end, step in body` becomes:
+/// ```text
+/// var i = start in
+///     while end do
+///         body : var __for_step = step in i = i + __for_step
+/// ```
+pub fn desugar(expr: ExprAST) -> ExprAST {
+    match expr {
+        ExprAST::ForExpr(ForExprAST {
+            var_name,
+            var_start,
+            var_end,
+            step,
+            body,
+        }) => {
+            let step = step.map_or(ExprAST::NumberExpr(NumberExprAST { val: 1.0 }), |step| {
+                desugar(*step)
+            });
+            let update = binary(
+                "=",
+                variable(&var_name),
+                binary("+", variable(&var_name), variable(FOR_STEP)),
+            );
+            let step_then_update = ExprAST::VarExpr(VarExprAST {
+                var_names: vec![(FOR_STEP.to_string(), Some(step))],
+                body: Box::new(update),
+            });
+            ExprAST::VarExpr(VarExprAST {
+                var_names: vec![(var_name, Some(desugar(*var_start)))],
+                body: Box::new(ExprAST::WhileExpr(WhileExprAST {
+                    condition: Box::new(desugar(*var_end)),
+                    body: Box::new(binary(":", desugar(*body), step_then_update)),
+                })),
+            })
+        }
+        other => map_subexprs(other, desugar),
+    }
+}
+
+/// Replace the uses of a `var` initialized with a literal by the literal,
+/// when the variable is never assigned nor shadowed in its scope, then fold
+/// the resulting constants. The propagated variables are removed from the
+/// `var`, and the `var` itself if none is left.
+///
+/// As variables are always floating point, integer initializers are
+/// propagated as floating point literals.
+pub fn propagate_constants(expr: ExprAST) -> ExprAST {
+    let ExprAST::VarExpr(VarExprAST { var_names, body }) = expr else {
+        return map_subexprs(expr, propagate_constants);
+    };
+    let mut pending: Vec<_> = var_names
+        .into_iter()
+        .map(|(name, init)| (name, init.map(propagate_constants)))
+        .collect();
+    let mut body = propagate_constants(*body);
+    let mut kept = vec![];
+    while !pending.is_empty() {
+        let (name, init) = pending.remove(0);
+        let value = match init.clone().map(fold_constants) {
+            None => Some(0.0),
+            Some(ExprAST::NumberExpr(NumberExprAST { val })) => Some(val),
+            Some(ExprAST::IntExpr(IntExprAST { val })) => Some(val as f64),
+            Some(_) => None,
+        };
+        let rebound = pending.iter().any(|(other, other_init)| {
+            other == &name || other_init.as_ref().is_some_and(|e| rebinds(e, &name))
+        }) || rebinds(&body, &name);
+        let Some(val) = value.filter(|_| !rebound) else {
+            kept.push((name, init));
+            continue;
+        };
+        let literal = ExprAST::NumberExpr(NumberExprAST { val });
+        for (_, other_init) in &mut pending {
+            *other_init = other_init
+                .take()
+                .map(|e| fold_constants(substitute(e, &name, &literal)));
+        }
+        body = fold_constants(substitute(body, &name, &literal));
+    }
+    if kept.is_empty() {
+        return body;
+    }
+    ExprAST::VarExpr(VarExprAST {
+        var_names: kept,
+        body: Box::new(body),
+    })
+}
+
+/// True if `name` is assigned, or declared again by a `var` or a `for`,
+/// somewhere in `expr`
+fn rebinds(expr: &ExprAST, name: &str) -> bool {
+    let found = match expr {
+        ExprAST::BinaryExpr(BinaryExprAST { op, lhs, .. }) if op == "=" => {
+            matches!(lhs.as_ref(), ExprAST::VariableExpr(var) if var.name == name)
+        }
+        ExprAST::ForExpr(for_expr) => for_expr.var_name == name,
+        ExprAST::VarExpr(var_expr) => var_expr.var_names.iter().any(|(n, _)| n == name),
+        _ => false,
+    };
+    found || subexprs(expr).into_iter().any(|e| rebinds(e, name))
+}
+
+/// Replace the variable `name` by `value`, which is only correct if the
+/// variable is not declared again in `expr`
+fn substitute(expr: ExprAST, name: &str, value: &ExprAST) -> ExprAST {
+    match expr {
+        ExprAST::VariableExpr(var) if var.name == name => value.clone(),
+        other => map_subexprs(other, |e| substitute(e, name, value)),
+    }
+}
+
+const FOR_STEP: &str = "__for_step";
+
+fn variable(name: &str) -> ExprAST {
+    ExprAST::VariableExpr(VariableExprAST {
+        name: name.to_string(),
+        position: Position::default(),
+    })
+}
+
+fn binary(op: &str, lhs: ExprAST, rhs: ExprAST) -> ExprAST {
+    ExprAST::BinaryExpr(BinaryExprAST {
+        op: op.to_string(),
+        lhs: Box::new(lhs),
+        rhs: Box::new(rhs),
+    })
+}
+
+/// Direct sub-expressions of `expr`
+pub(crate) fn subexprs(expr: &ExprAST) -> Vec<&ExprAST> {
+    match expr {
+        ExprAST::BinaryExpr(bin) => vec![&bin.lhs, &bin.rhs],
+        ExprAST::UnaryExpr(unary) => vec![&unary.operand],
+        ExprAST::CallExpr(call) => call.args.iter().collect(),
+        ExprAST::IfExpr(if_expr) => {
+            vec![&if_expr.condition, &if_expr.then_block, &if_expr.else_block]
+        }
+        ExprAST::ForExpr(for_expr) => {
+            let mut exprs: Vec<&ExprAST> = vec![&for_expr.var_start, &for_expr.var_end];
+            exprs.extend(for_expr.step.as_deref());
+            exprs.push(&for_expr.body);
+            exprs
+        }
+        ExprAST::VarExpr(var_expr) => {
+            let mut exprs: Vec<&ExprAST> = var_expr
+                .var_names
+                .iter()
+                .filter_map(|(_, init)| init.as_ref())
+                .collect();
+            exprs.push(&var_expr.body);
+            exprs
+        }
+        ExprAST::WhileExpr(while_expr) => vec![&while_expr.condition, &while_expr.body],
+        ExprAST::NumberExpr(_)
+        | ExprAST::IntExpr(_)
+        | ExprAST::StringExpr(_)
+        | ExprAST::VariableExpr(_) => vec![],
+    }
+}
+
+/// Apply `f` on the direct sub-expressions of `expr`
+fn map_subexprs(expr: ExprAST, f: impl Fn(ExprAST) -> ExprAST) -> ExprAST {
+    match expr {
+        ExprAST::BinaryExpr(BinaryExprAST { op, lhs, rhs }) => ExprAST::BinaryExpr(BinaryExprAST {
+            op,
+            lhs: Box::new(f(*lhs)),
+            rhs: Box::new(f(*rhs)),
+        }),
+        ExprAST::UnaryExpr(UnaryExprAST { opcode, operand }) => ExprAST::UnaryExpr(UnaryExprAST {
+            opcode,
+            operand: Box::new(f(*operand)),
+        }),
+        ExprAST::CallExpr(CallExprAST { callee, args }) => ExprAST::CallExpr(CallExprAST {
+            callee,
+            args: args.into_iter().map(&f).collect(),
+        }),
+        ExprAST::IfExpr(IfExprAST {
+            condition,
+            then_block,
+            else_block,
+        }) => ExprAST::IfExpr(IfExprAST {
+            condition: Box::new(f(*condition)),
+            then_block: Box::new(f(*then_block)),
+            else_block: Box::new(f(*else_block)),
+        }),
+        ExprAST::ForExpr(ForExprAST {
+            var_name,
+            var_start,
+            var_end,
+            step,
+            body,
+        }) => ExprAST::ForExpr(ForExprAST {
+            var_name,
+            var_start: Box::new(f(*var_start)),
+            var_end: Box::new(f(*var_end)),
+            step: step.map(|step| Box::new(f(*step))),
+            body: Box::new(f(*body)),
+        }),
+        ExprAST::VarExpr(VarExprAST { var_names, body }) => ExprAST::VarExpr(VarExprAST {
+            var_names: var_names
+                .into_iter()
+                .map(|(name, init)| (name, init.map(&f)))
+                .collect(),
+            body: Box::new(f(*body)),
+        }),
+        ExprAST::WhileExpr(WhileExprAST { condition, body }) => ExprAST::WhileExpr(WhileExprAST {
+            condition: Box::new(f(*condition)),
+            body: Box::new(f(*body)),
+        }),
+        other @ (ExprAST::NumberExpr(_)
+        | ExprAST::IntExpr(_)
+        | ExprAST::StringExpr(_)
+        | ExprAST::VariableExpr(_)) => other,
+    }
+}
+
+fn fold_unary_op(opcode: char, operand: &ExprAST) -> Option<ExprAST> {
+    match (opcode, operand) {
+        // -i64::MIN overflows, it is then negated as a float
+        ('-', ExprAST::IntExpr(int)) if int.val != i64::MIN => {
+            Some(ExprAST::IntExpr(IntExprAST { val: -int.val }))
+        }
+        ('-', other) => Some(ExprAST::NumberExpr(NumberExprAST {
+            val: -as_float(other)?,
+        })),
+        ('!', other) => Some(ExprAST::NumberExpr(NumberExprAST {
+            val: bool_as_float(as_float(other)? == 0.0),
+        })),
+        _ => None,
+    }
+}
+
+/// Evaluate a built-in operator the same way the code generator would:
+/// integer arithmetic when both sides are integers, floating point otherwise.
+fn fold_binary_op(op: &str, lhs: &ExprAST, rhs: &ExprAST) -> Option<ExprAST> {
+    if op == ":" {
+        // A literal has no side effect, only the right side is kept
+        return as_float(lhs).map(|_| rhs.clone());
+    }
+    if let (ExprAST::IntExpr(l), ExprAST::IntExpr(r)) = (lhs, rhs) {
+        if let Some(result) = compare(op, l.val.partial_cmp(&r.val)) {
+            return Some(ExprAST::NumberExpr(NumberExprAST {
+                val: bool_as_float(result),
+            }));
+        }
+        if let Some(val) = int_binary_op(op, l.val, r.val) {
+            return Some(ExprAST::IntExpr(IntExprAST { val }));
+        }
+        // Left to the code generator, which yields NaN
+        if op == "%" && r.val == 0 {
+            return None;
+        }
+        // Otherwise, the float result is used, as for any other number
+    }
+    let (l, r) = (as_float(lhs)?, as_float(rhs)?);
+    if let Some(result) = compare(op, l.partial_cmp(&r)) {
+        return Some(ExprAST::NumberExpr(NumberExprAST {
+            val: bool_as_float(result),
+        }));
+    }
+    let val = match op {
+        "+" => l + r,
+        "-" => l - r,
+        "*" => l * r,
+        "/" => l / r,
+        "%" => l % r,
+        _ => return None,
+    };
+    Some(ExprAST::NumberExpr(NumberExprAST { val }))
+}
+
+/// Result of a built-in comparison given the ordering of its operands, None
+/// for an unordered one, or None if op is not a comparison. As in the
+/// generated code, NaN operands compare as true, except for `==`.
+fn compare(op: &str, ordering: Option<Ordering>) -> Option<bool> {
+    match op {
+        "<" => Some(!matches!(
+            ordering,
+            Some(Ordering::Greater | Ordering::Equal)
+        )),
+        "<=" => Some(ordering != Some(Ordering::Greater)),
+        ">=" => Some(ordering != Some(Ordering::Less)),
+        "==" => Some(ordering == Some(Ordering::Equal)),
+        "!=" => Some(ordering != Some(Ordering::Equal)),
+        _ => None,
+    }
+}
+
+/// Integer arithmetic, the only one there is: integers only exist as
+/// literals, so the code generator computes it at compile time as well.
+/// None if op has no integer implementation, like `/` which is always done
+/// on floats, or if the result is not an i64.
+pub(crate) fn int_binary_op(op: &str, l: i64, r: i64) -> Option<i64> {
+    match op {
+        "+" => l.checked_add(r),
+        "-" => l.checked_sub(r),
+        "*" => l.checked_mul(r),
+        // i64::MIN % -1 overflows in LLVM, but its remainder is 0
+        "%" if r != 0 => Some(l.wrapping_rem(r)),
+        _ => None,
+    }
+}
+
+fn as_float(expr: &ExprAST) -> Option<f64> {
+    match expr {
+        ExprAST::NumberExpr(number) => Some(number.val),
+        ExprAST::IntExpr(int) => Some(int.val as f64),
+        _ => None,
+    }
+}
+
+fn bool_as_float(value: bool) -> f64 {
+    if value {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::GlobalParser;
+
+    fn fold_str(input: &str) -> ExprAST {
+        let mut ast = GlobalParser::default().parse(input).unwrap();
+        let TopAST::Function(function) = ast.0.remove(0) else {
+            panic!("expected a function");
+        };
+        fold_constants(function.body)
+    }
+
+    #[test]
+    fn fold_float_arithmetic() {
+        assert_eq!(
+            fold_str("2.0+3.0*4.0"),
+            ExprAST::NumberExpr(NumberExprAST { val: 14.0 })
+        );
+    }
+
+    #[test]
+    fn fold_int_arithmetic() {
+        assert_eq!(fold_str("2+3*4"), ExprAST::IntExpr(IntExprAST { val: 14 }));
+        assert_eq!(fold_str("7 % 4"), ExprAST::IntExpr(IntExprAST { val: 3 }));
+        assert_eq!(
+            fold_str("7 / 2"),
+            ExprAST::NumberExpr(NumberExprAST { val: 3.5 })
+        );
+        assert_eq!(
+            fold_str("1 + 0.5"),
+            ExprAST::NumberExpr(NumberExprAST { val: 1.5 })
+        );
+        assert_eq!(
+            fold_str("2 < 3"),
+            ExprAST::NumberExpr(NumberExprAST { val: 1.0 })
+        );
+    }
+
+    #[test]
+    fn fold_comparisons() {
+        for (input, val) in [
+            ("2 <= 2", 1.0),
+            ("3 >= 4", 0.0),
+            ("2 == 2.0", 1.0),
+            ("1 != 1", 0.0),
+            ("(0/0) <= 1", 1.0),
+            ("(0/0) == (0/0)", 0.0),
+            ("(0/0) != (0/0)", 1.0),
+        ] {
+            assert_eq!(
+                fold_str(input),
+                ExprAST::NumberExpr(NumberExprAST { val }),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn fold_int_overflow() {
+        let big = 3_037_000_500_f64;
+        assert_eq!(
+            fold_str("3037000500 * 3037000500"),
+            ExprAST::NumberExpr(NumberExprAST { val: big * big })
+        );
+        assert_eq!(
+            fold_str("9223372036854775807 + 1"),
+            ExprAST::NumberExpr(NumberExprAST {
+                val: i64::MAX as f64 + 1.0
+            })
+        );
+        assert_eq!(
+            fold_str("-(0 - 9223372036854775807 - 1)"),
+            ExprAST::NumberExpr(NumberExprAST {
+                val: -(i64::MIN as f64)
+            })
+        );
+        assert_eq!(
+            fold_str("(0 - 9223372036854775807 - 1) % -1"),
+            ExprAST::IntExpr(IntExprAST { val: 0 })
+        );
+    }
+
+    #[test]
+    fn fold_builtin_unary_operators() {
+        assert_eq!(fold_str("-(2+3)"), ExprAST::IntExpr(IntExprAST { val: -5 }));
+        assert_eq!(
+            fold_str("!0"),
+            ExprAST::NumberExpr(NumberExprAST { val: 1.0 })
+        );
+        assert_eq!(
+            fold_str("1 - -0.5"),
+            ExprAST::NumberExpr(NumberExprAST { val: 1.5 })
+        );
+    }
+
+    #[test]
+    fn fold_sequence() {
+        let ast = fold_str("def foo(x) 1 : x;");
+        assert!(matches!(ast, ExprAST::VariableExpr(_)));
+        let ast = fold_str("def foo(x) x : 1;");
+        assert!(matches!(ast, ExprAST::BinaryExpr(BinaryExprAST { ref op, .. }) if op == ":"));
+    }
+
+    #[test]
+    fn variables_are_not_folded() {
+        let ast = fold_str("def foo(x) x+1;");
+        assert!(matches!(
+            ast,
+            ExprAST::BinaryExpr(BinaryExprAST { ref op, .. }) if op == "+"
+        ));
+        let ast = fold_str("def foo(x) x+(1+2);");
+        let ExprAST::BinaryExpr(bin) = ast else {
+            panic!("expected a binary expression");
+        };
+        assert_eq!(*bin.rhs, ExprAST::IntExpr(IntExprAST { val: 3 }));
+    }
+
+    #[test]
+    fn user_defined_operators_are_not_folded() {
+        let mut parser = GlobalParser::default();
+        parser.parse("def binary| 5 (a b) a;").unwrap();
+        let mut ast = parser.parse("1 | 2;").unwrap();
+        let TopAST::Function(function) = ast.0.remove(0) else {
+            panic!("expected a function");
+        };
+        assert!(matches!(
+            fold_constants(function.body),
+            ExprAST::BinaryExpr(BinaryExprAST { ref op, .. }) if op == "|"
+        ));
+    }
+
+    #[test]
+    fn int_modulo_by_zero_is_not_folded() {
+        assert!(matches!(
+            fold_str("1 % 0"),
+            ExprAST::BinaryExpr(BinaryExprAST { ref op, .. }) if op == "%"
+        ));
+    }
+
+    fn desugar_str(input: &str) -> ExprAST {
+        let mut ast = GlobalParser::default().parse(input).unwrap();
+        let TopAST::Function(function) = ast.0.remove(0) else {
+            panic!("expected a function");
+        };
+        desugar(function.body)
+    }
+
+    /// Variable as read by the parser, on the first line
+    fn variable_at(name: &str, column: usize) -> ExprAST {
+        ExprAST::VariableExpr(VariableExprAST {
+            name: name.to_string(),
+            position: Position { line: 1, column },
+        })
+    }
+
+    #[test]
+    fn desugar_for_into_while() {
+        let body = binary(
+            ":",
+            ExprAST::CallExpr(CallExprAST {
+                callee: "g".to_string(),
+                args: vec![variable_at("i", 31)],
+            }),
+            ExprAST::VarExpr(VarExprAST {
+                var_names: vec![(
+                    "__for_step".to_string(),
+                    Some(ExprAST::NumberExpr(NumberExprAST { val: 1.0 })),
+                )],
+                body: Box::new(binary(
+                    "=",
+                    variable("i"),
+                    binary("+", variable("i"), variable("__for_step")),
+                )),
+            }),
+        );
+        let expected = ExprAST::VarExpr(VarExprAST {
+            var_names: vec![(
+                "i".to_string(),
+                Some(ExprAST::IntExpr(IntExprAST { val: 0 })),
+            )],
+            body: Box::new(ExprAST::WhileExpr(WhileExprAST {
+                condition: Box::new(binary(
+                    "<",
+                    variable_at("i", 20),
+                    ExprAST::IntExpr(IntExprAST { val: 3 }),
+                )),
+                body: Box::new(body),
+            })),
+        });
+        assert_eq!(desugar_str("def f() for i = 0, i < 3 in g(i);"), expected);
+    }
+
+    #[test]
+    fn desugar_nested_for() {
+        let ExprAST::CallExpr(call) =
+            desugar_str("def f() g(for i = 0, i < 3 in for j = 0, j < 3 in 0);")
+        else {
+            panic!("expected a call");
+        };
+        let ExprAST::VarExpr(outer) = &call.args[0] else {
+            panic!("expected the outer loop to be desugared");
+        };
+        let ExprAST::WhileExpr(outer_loop) = outer.body.as_ref() else {
+            panic!("expected a while loop");
+        };
+        assert!(matches!(
+            outer_loop.body.as_ref(),
+            ExprAST::BinaryExpr(BinaryExprAST { lhs, .. })
+                if matches!(lhs.as_ref(), ExprAST::VarExpr(inner)
+                    if matches!(inner.body.as_ref(), ExprAST::WhileExpr(_)))
+        ));
+    }
+
+    fn propagate_str(input: &str) -> ExprAST {
+        let mut ast = GlobalParser::default().parse(input).unwrap();
+        let TopAST::Function(function) = ast.0.remove(0) else {
+            panic!("expected a function");
+        };
+        propagate_constants(function.body)
+    }
+
+    #[test]
+    fn propagate_immutable_var() {
+        assert_eq!(
+            propagate_str("var k = 3 in k * k"),
+            ExprAST::NumberExpr(NumberExprAST { val: 9.0 })
+        );
+        assert_eq!(
+            propagate_str("var a = 1, b = a + 1 in b * 10"),
+            ExprAST::NumberExpr(NumberExprAST { val: 20.0 })
+        );
+    }
+
+    #[test]
+    fn reassigned_var_is_not_propagated() {
+        let ast = propagate_str("var k = 3 in (k = k + 1) : k * k");
+        let ExprAST::VarExpr(var_expr) = ast else {
+            panic!("expected the var to be kept");
+        };
+        assert_eq!(var_expr.var_names[0].0, "k");
+    }
+
+    #[test]
+    fn shadowed_var_is_not_propagated() {
+        let ast = propagate_str("def f(x) var k = 3, j = x in (for k = 0, k < 2 in k) + k * j");
+        let ExprAST::VarExpr(var_expr) = ast else {
+            panic!("expected the var to be kept");
+        };
+        let names: Vec<_> = var_expr.var_names.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["k", "j"]);
+    }
+}
@@ -0,0 +1,519 @@
+/*
+MIT License
+
+Copyright (c) 2023 Vincent Hiribarren
+
+Permission is hereby granted, free of charge, to any person obtaining a copy
+of this software and associated documentation files (the "Software"), to deal
+in the Software without restriction, including without limitation the rights
+to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+copies of the Software, and to permit persons to whom the Software is
+furnished to do so, subject to the following conditions:
+
+The above copyright notice and this permission notice shall be included in all
+copies or substantial portions of the Software.
+
+THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+SOFTWARE.
+*/
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Output, Stdio};
+
+fn run_kaleido(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_llvm-tuto-kaleidoscope-rust"))
+        .current_dir(Path::new(env!("CARGO_MANIFEST_DIR")))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn run_kaleido_with_stdin(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_llvm-tuto-kaleidoscope-rust"))
+        .current_dir(Path::new(env!("CARGO_MANIFEST_DIR")))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn dump_ast() {
+    let output = run_kaleido(&["--dump-ast", "-f", "tests/scripts/average.kaleido"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("FunctionAST"));
+    assert!(stdout.contains("BinaryExprAST"));
+    assert!(stdout.contains("name: \"average\""));
+    assert!(!stdout.contains("define double"));
+}
+
+#[test]
+fn recursive_and_iterative_fib() {
+    let output = run_kaleido(&["-s", "-f", "tests/scripts/fib.kaleido"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.matches("Evaluated to: 55").count(), 2, "{stdout}");
+}
+
+#[test]
+fn global_kept_across_top_level_expressions() {
+    let output = run_kaleido(&["-s", "-f", "tests/scripts/global_counter.kaleido"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let results: Vec<_> = stdout
+        .lines()
+        .filter(|line| line.contains("Evaluated to"))
+        .collect();
+    assert_eq!(
+        results,
+        ["Evaluated to: 1", "Evaluated to: 2", "Evaluated to: 6"],
+        "{stdout}"
+    );
+}
+
+#[test]
+fn desugared_fib() {
+    let output = run_kaleido(&["-s", "--desugar", "-f", "tests/scripts/fib.kaleido"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.matches("Evaluated to: 55").count(), 2, "{stdout}");
+}
+
+#[test]
+fn forward_reference() {
+    let script = "tests/scripts/forward_reference.kaleido";
+    let output = run_kaleido(&["-s", "-f", script]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("Evaluated to: 42"), "{stdout}");
+
+    let output = run_kaleido(&["-s", "-f", script, "--run", "main"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().ends_with("42\n"));
+}
+
+#[test]
+fn run_function_with_args() {
+    let script = "tests/scripts/square.kaleido";
+    let output = run_kaleido(&["-s", "-f", script, "--run", "square", "--arg", "7"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "49\n");
+
+    let output = run_kaleido(&["-s", "-f", script, "--run", "square"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("function 'square' expects 1 args, got 0"));
+}
+
+#[test]
+fn nan_result() {
+    let script = std::env::temp_dir().join(format!("kaleido-nan-{}.kaleido", std::process::id()));
+    std::fs::write(&script, "0/0; 1/0; 1/2;").unwrap();
+    let script = script.to_str().unwrap();
+
+    let output = run_kaleido(&["-s", "-f", script]);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stdout.contains("Evaluated to: NaN"));
+    assert!(stderr.contains("Warning: evaluation produced NaN"));
+    assert!(stderr.contains("Warning: evaluation produced inf"));
+
+    let output = run_kaleido(&["-s", "--trap-on-nan", "-f", script]);
+    std::fs::remove_file(script).unwrap();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stdout.contains("NaN"));
+    assert!(stderr.contains("Error: evaluation produced NaN"));
+    assert!(stderr.contains("Error: evaluation produced inf"));
+    assert!(stdout.contains("Evaluated to: 0.5"));
+}
+
+#[test]
+fn with_libm() {
+    let script = "tests/scripts/libm.kaleido";
+    let output = run_kaleido(&[
+        "-s",
+        "--with-libm",
+        "-f",
+        script,
+        "--run",
+        "hypot",
+        "--arg",
+        "3",
+        "--arg",
+        "4",
+    ]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8(output.stdout).unwrap(), "5\n");
+}
+
+#[test]
+fn script_from_stdin() {
+    let output = run_kaleido_with_stdin(&["-s", "--stdin"], "def double(x) x*2;\ndouble(21);\n");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stdout.contains("Evaluated to: 42"), "{stdout}");
+    assert!(!stderr.contains("Ctrl+D"), "{stderr}");
+}
+
+#[test]
+fn failing_script_exit_code() {
+    let output = run_kaleido_with_stdin(&["-s", "--stdin"], "1 + 2;\ndef f(x) x +;\n3;\n");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("1 error(s) while executing the script"),
+        "{stderr}"
+    );
+    assert!(!stdout.contains("Evaluated to"), "{stdout}");
+}
+
+#[test]
+fn max_jit_iterations() {
+    let output = run_kaleido_with_stdin(
+        &["-s", "--stdin", "--max-jit-iterations", "100"],
+        "for i = 0, 1 in i;",
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("aborted after 100 loop iterations"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn max_jit_iterations_stops_the_whole_evaluation() {
+    let output = run_kaleido_with_stdin(
+        &["-s", "--stdin", "--max-jit-iterations", "100"],
+        "extern putchard(c); def spin() while 1 do 0; spin() : putchard(88);",
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("aborted after 100 loop iterations"),
+        "{stderr}"
+    );
+    assert!(!stderr.contains('X'), "{stderr}");
+}
+
+#[test]
+fn ast_json() {
+    let path = std::env::temp_dir().join(format!("kaleido-ast-{}.json", std::process::id()));
+    let output = run_kaleido_with_stdin(
+        &["-s", "--stdin", "--ast-json", path.to_str().unwrap()],
+        "def double(x) x * 2; double(4);",
+    );
+    assert!(output.status.success());
+    let json = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    for tag in [
+        "\"Function\"",
+        "\"BinaryExpr\"",
+        "\"CallExpr\"",
+        "\"VariableExpr\"",
+    ] {
+        assert!(json.contains(tag), "{tag} missing in {json}");
+    }
+    assert!(json.contains("\"name\": \"double\""), "{json}");
+}
+
+#[test]
+fn parse_only() {
+    let output = run_kaleido(&["--parse-only", "-f", "tests/scripts/fib.kaleido"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("fib.kaleido: syntax OK"), "{stderr}");
+    // Calling an unknown function is only an error for the code generator
+    let output = run_kaleido_with_stdin(&["--parse-only", "--stdin"], "unknown(1);");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.is_empty(), "{stdout}");
+    let output = run_kaleido_with_stdin(
+        &["--parse-only", "--stdin"],
+        "def f(x) x +;
+f(1);",
+    );
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("<stdin>: unexpected"), "{stderr}");
+    assert!(!stdout.contains("define double"), "{stdout}");
+}
+
+#[test]
+fn dump_precedence() {
+    let input = "def binary | 5 (a b) if a then 1 else b; 1 | 0;";
+    let output = run_kaleido_with_stdin(&["-s", "--stdin", "--dump-precedence"], input);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("  5 |\n"), "{stdout}");
+    assert!(stdout.contains(" 40 *\n"), "{stdout}");
+    let output = run_kaleido_with_stdin(&["--parse-only", "--stdin", "--dump-precedence"], input);
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.contains("  5 |\n"), "{stdout}");
+}
+
+#[test]
+fn no_jit() {
+    let ir_path = std::env::temp_dir().join(format!("kaleido-no-jit-{}.ll", std::process::id()));
+    let output = run_kaleido_with_stdin(
+        &[
+            "--stdin",
+            "--no-jit",
+            "--output-ir",
+            ir_path.to_str().unwrap(),
+        ],
+        "extern printd(x); printd(1);",
+    );
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stdout.contains("Evaluated to"), "{stdout}");
+    assert!(stdout.contains("call double @printd"), "{stdout}");
+    assert!(!stderr.lines().any(|line| line == "1"), "{stderr}");
+    let ir = std::fs::read_to_string(&ir_path).unwrap();
+    std::fs::remove_file(&ir_path).unwrap();
+    assert!(ir.contains("call double @printd"), "{ir}");
+}
+
+#[test]
+fn output_ir_on_early_exit() {
+    let ir_path = std::env::temp_dir().join(format!("kaleido-early-{}.ll", std::process::id()));
+    let output = run_kaleido_with_stdin(
+        &[
+            "-s",
+            "--stdin",
+            "--output-ir",
+            ir_path.to_str().unwrap(),
+            "--run",
+            "missing",
+        ],
+        "def f(x) x * 2;",
+    );
+    assert!(!output.status.success());
+    let ir = std::fs::read_to_string(&ir_path).unwrap();
+    std::fs::remove_file(&ir_path).unwrap();
+    assert!(ir.contains("define double @f(double"), "{ir}");
+}
+
+#[test]
+#[cfg(not(windows))]
+fn keep_anon() {
+    let dir = std::env::temp_dir();
+    let ir_path = dir.join(format!("kaleido-anon-{}.ll", std::process::id()));
+    let obj_path = dir.join(format!("kaleido-anon-{}.o", std::process::id()));
+    let emitted = |keep_anon: Option<&str>| {
+        let mut args = vec![
+            "-s",
+            "--stdin",
+            "--output-ir",
+            ir_path.to_str().unwrap(),
+            "--output-object",
+            obj_path.to_str().unwrap(),
+        ];
+        if let Some(keep_anon) = keep_anon {
+            args.extend(["--keep-anon", keep_anon]);
+        }
+        let output = run_kaleido_with_stdin(&args, "def f(x) x * 2; f(21);");
+        assert!(output.status.success());
+        let ir = std::fs::read_to_string(&ir_path).unwrap();
+        let object = std::fs::read(&obj_path).unwrap();
+        std::fs::remove_file(&ir_path).unwrap();
+        std::fs::remove_file(&obj_path).unwrap();
+        let has_anon = |bytes: &[u8]| bytes.windows(11).any(|window| window == b"__anon_expr");
+        assert!(ir.contains("define double @f("), "{ir}");
+        (has_anon(ir.as_bytes()), has_anon(&object))
+    };
+    assert_eq!(emitted(None), (true, false));
+    assert_eq!(emitted(Some("true")), (true, true));
+    assert_eq!(emitted(Some("false")), (false, false));
+}
+
+#[test]
+fn llvm_version() {
+    // Without a script, the REPL would be launched if the flag was ignored
+    let output = run_kaleido_with_stdin(&["--llvm-version"], "");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let version = stdout.trim_end().strip_prefix("LLVM ").unwrap();
+    assert!(
+        version
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit())),
+        "{stdout}"
+    );
+    assert!(output.stderr.is_empty());
+}
+
+#[test]
+fn dump_symbols() {
+    let output = run_kaleido_with_stdin(
+        &["-s", "--stdin", "--dump-symbols"],
+        "extern printd(x); extern kaleido_nowhere(); def f(x) printd(x);",
+    );
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let printd = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("printd: 0x"))
+        .unwrap_or_else(|| panic!("printd not resolved: {stdout}"));
+    assert_ne!(usize::from_str_radix(printd, 16).unwrap(), 0);
+    assert!(stdout.contains("kaleido_nowhere: not found\n"), "{stdout}");
+    assert!(!stdout.contains("f: "), "{stdout}");
+}
+
+#[test]
+fn emit_dot() {
+    let path = std::env::temp_dir().join(format!("kaleido-ast-{}.dot", std::process::id()));
+    let output = run_kaleido_with_stdin(
+        &["-s", "--stdin", "--emit", "dot", path.to_str().unwrap()],
+        "def double(x) x * 2; double(4);",
+    );
+    assert!(output.status.success());
+    let dot = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(dot.starts_with("digraph ast {"), "{dot}");
+    assert!(dot.contains("[label=\"Function double(x)\"];"), "{dot}");
+    assert!(dot.contains("[label=\"CallExpr double\"];"), "{dot}");
+}
+
+#[test]
+fn emit_unknown_kind() {
+    let output = run_kaleido_with_stdin(&["--stdin", "--emit", "svg", "out.svg"], "1;");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("unknown --emit kind 'svg', expected dot"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn emit_deps() {
+    let path = std::env::temp_dir().join(format!("kaleido-deps-{}.txt", std::process::id()));
+    let output = run_kaleido_with_stdin(
+        &["-s", "--stdin", "--emit-deps", path.to_str().unwrap()],
+        "extern sin(x); extern printd(x); def f(x) printd(sin(x)); f(1);",
+    );
+    assert!(output.status.success());
+    let deps = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(deps, "printd\nsin\n");
+
+    // The loop guard calls a trap only provided by the JIT
+    let output = run_kaleido_with_stdin(
+        &[
+            "-s",
+            "--stdin",
+            "--max-jit-iterations",
+            "10",
+            "--emit-deps",
+            path.to_str().unwrap(),
+        ],
+        "def f(x) for i = 0, i < x in 0;",
+    );
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("cannot be used with"), "{stderr}");
+    assert!(!path.exists());
+}
+
+#[test]
+fn repl_functions_persist_across_inputs() {
+    let history = std::env::temp_dir().join(format!("kaleido-history-{}", std::process::id()));
+    let mut child = Command::new(env!("CARGO_BIN_EXE_llvm-tuto-kaleidoscope-rust"))
+        .args(["-s"])
+        .env("KALEIDO_HISTORY", &history)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"def sq(x) x*x;\nsq(5);\nsq(2) + 1;\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    let _ = std::fs::remove_file(&history);
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    // The anonymous function of the first evaluation is replaced, not sq
+    assert!(stdout.contains("Evaluated to: 25"), "{stdout}");
+    assert!(stdout.contains("Evaluated to: 5"), "{stdout}");
+}
+
+#[test]
+fn colored_errors() {
+    let input = "1 + ();";
+    let output = run_kaleido_with_stdin(&["-s", "--stdin", "--color", "never"], input);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(!stderr.contains('\x1b'), "{stderr}");
+    assert!(stderr.contains("1 + ();\n    ^"), "{stderr}");
+    let output = run_kaleido_with_stdin(&["-s", "--stdin", "--color", "always"], input);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("\x1b[1;31mempty parenthesized expression at 1:5\x1b[0m"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn chained_comparison_lint() {
+    let output = run_kaleido_with_stdin(&["-s", "--stdin"], "1 < 2 < 3;");
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Warning: chained comparison"), "{stderr}");
+    let output = run_kaleido_with_stdin(&["-s", "--stdin", "--strict"], "1 < 2 < 3;");
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Error: chained comparison"), "{stderr}");
+    assert!(!stdout.contains("Evaluated to"), "{stdout}");
+}
+
+#[test]
+fn annotate_ir() {
+    let output = run_kaleido_with_stdin(
+        &["--stdin", "--annotate-ir"],
+        "extern sin(x);\ndef binary| 5 (a b) a + b;\ndef fib(x) x;\nfib(1);",
+    );
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    for (header, ir) in [
+        ("; --- extern sin(x) ---", "declare double @sin"),
+        (
+            "; --- def binary| 5 (a b) ---",
+            "define double @\"binary|\"",
+        ),
+        ("; --- def fib(x) ---", "define double @fib"),
+        (
+            "; --- top-level expression ---",
+            "define double @__anon_expr",
+        ),
+    ] {
+        let (_, after_header) = stdout.split_once(header).expect(header);
+        assert!(after_header.trim_start().starts_with(ir), "{stdout}");
+    }
+}